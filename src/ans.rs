@@ -0,0 +1,368 @@
+#![allow(dead_code)]
+
+use crate::bit_reader::BitStream;
+use crate::decode_error::{DecodeError, DecodeResultExt};
+
+// Distributions are scaled so that their counts add up to 1 << 12
+pub const ANS_LOG_TAB_SIZE: u8 = 12;
+pub const ANS_TAB_SIZE: u32 = 1 << ANS_LOG_TAB_SIZE;
+// The state the encoder starts from, which the decoder must end on
+const ANS_FINAL_STATE: u32 = 0x130000;
+
+// Log counts are read with a fixed prefix code, given as (code, length) for each value. 13 starts a run.
+const LOG_COUNT_CODES: [(u8, u8); 14] = [
+    (17, 5), (11, 4), (15, 4), (3, 4), (9, 4), (7, 4), (4, 3), (2, 3), (5, 3), (6, 3), (0, 3), (33, 6), (1, 7), (65, 7)
+];
+const LOG_COUNT_RLE: u8 = 13;
+
+// A distribution, along with its alias table. Symbol s takes up counts[s] of the 4096 slots, and the alias table
+// splits the slots into one bucket per possible symbol, each holding at most two symbols.
+#[derive(Debug,Clone,PartialEq)]
+pub struct AnsDistribution {
+    counts: Vec<u16>,
+    log_bucket_size: u8,
+    cutoffs: Vec<u16>,      // Positions in the bucket below this are the bucket's own symbol
+    symbols: Vec<u8>,       // The other symbol in the bucket
+    offsets: Vec<u16>       // Added to the position to get the offset within that symbol's slots
+}
+
+impl AnsDistribution {
+    // Reads a distribution over at most 1 << log_alpha_size symbols (log_alpha_size is 5 to 8)
+    pub fn read(bitstream: &mut BitStream, log_alpha_size: u8) -> Result<Self,DecodeError> {
+        let counts = if bitstream.read_bool().field("AnsDistribution.simple")? {
+            Self::read_simple(bitstream).field("AnsDistribution.symbols")?
+        } else if bitstream.read_bool().field("AnsDistribution.flat")? {
+            let alphabet_size = bitstream.read_var_u8().field("AnsDistribution.alphabet_size")? as u32 + 1;
+            (0..alphabet_size).map(|symbol| (ANS_TAB_SIZE / alphabet_size + (symbol < ANS_TAB_SIZE % alphabet_size) as u32) as u16).collect()
+        } else {
+            Self::read_log_counts(bitstream)?
+        };
+        if counts.len() > 1 << log_alpha_size {
+            return Err(bitstream.invalid_value("AnsDistribution.alphabet_size",Some(counts.len() as u64)));
+        }
+        Ok(Self::from_counts(counts, log_alpha_size))
+    }
+    // One or two symbols. With two, the count of the first is stored and the second gets the rest.
+    fn read_simple(bitstream: &mut BitStream) -> Result<Vec<u16>,DecodeError> {
+        let symbol_count = bitstream.read(1)? + 1;
+        let first = bitstream.read_var_u8()? as usize;
+        let mut counts = vec![0; first + 1];
+        if symbol_count == 1 {
+            counts[first] = ANS_TAB_SIZE as u16;
+            return Ok(counts);
+        }
+        let second = bitstream.read_var_u8()? as usize;
+        if second == first {
+            return Err(bitstream.invalid_value("",Some(second as u64)));
+        }
+        counts.resize(first.max(second) + 1, 0);
+        counts[first] = bitstream.read_u16(ANS_LOG_TAB_SIZE)?;
+        counts[second] = ANS_TAB_SIZE as u16 - counts[first];
+        Ok(counts)
+    }
+    // Each count is given by its log (plus one, 0 means a count of 0) and as many of the bits below the top one
+    // as `shift` asks for. Runs repeat the previous count, and the largest count is left out and takes what's left.
+    fn read_log_counts(bitstream: &mut BitStream) -> Result<Vec<u16>,DecodeError> {
+        let mut shift_bits = 0;
+        while shift_bits < 3 && bitstream.read_bool().field("AnsDistribution.shift")? {
+            shift_bits += 1;
+        }
+        let shift = (bitstream.read_u32(shift_bits).field("AnsDistribution.shift")? + (1 << shift_bits) - 1) as i32;
+        if shift > ANS_LOG_TAB_SIZE as i32 + 1 {
+            return Err(bitstream.invalid_value("AnsDistribution.shift",Some(shift as u64)));
+        }
+        let length = bitstream.read_var_u8().field("AnsDistribution.alphabet_size")? as usize + 3;
+
+        let mut log_counts = vec![0u8; length];
+        let mut run_lengths = vec![0usize; length];
+        let mut omit_pos = None;
+        let mut index = 0;
+        while index < length {
+            let log_count = Self::read_log_count(bitstream).field("AnsDistribution.log_counts")?;
+            if log_count == LOG_COUNT_RLE {
+                run_lengths[index] = bitstream.read_var_u8().field("AnsDistribution.run_length")? as usize + 4;
+                index += run_lengths[index];
+                continue;
+            }
+            log_counts[index] = log_count;
+            match omit_pos {
+                Some(pos) if log_counts[pos] >= log_count => (),
+                _ => omit_pos = Some(index)
+            }
+            index += 1;
+        }
+        // A run can't follow the left out count, as its count isn't known yet
+        let omit_pos = omit_pos.filter(|&pos| pos + 1 >= length || run_lengths[pos + 1] == 0)
+            .ok_or_else(|| bitstream.invalid_value("AnsDistribution.log_counts",None))?;
+
+        let mut counts = vec![0u16; length];
+        let mut total = 0u32;
+        let mut index = 0;
+        while index < length {
+            if run_lengths[index] != 0 {
+                let previous = if index == 0 { 0 } else { counts[index - 1] };
+                let end = (index + run_lengths[index]).min(length);
+                counts[index..end].fill(previous);
+                total += previous as u32 * (end - index) as u32;
+                index = end;
+                continue;
+            }
+            let log_count = log_counts[index];
+            if index != omit_pos && log_count != 0 {
+                let log = log_count as i32 - 1;
+                let bit_count = log.min(shift - ((ANS_LOG_TAB_SIZE as i32 - log) >> 1)).max(0);
+                let extra = bitstream.read_u32(bit_count as u8).field("AnsDistribution.counts")?;
+                let count = (1 << log) + (extra << (log - bit_count));
+                counts[index] = count as u16;
+                total += count;
+            }
+            index += 1;
+        }
+        if total >= ANS_TAB_SIZE {
+            return Err(bitstream.invalid_value("AnsDistribution.counts",Some(total as u64)));
+        }
+        counts[omit_pos] = (ANS_TAB_SIZE - total) as u16;
+        Ok(counts)
+    }
+    fn read_log_count(bitstream: &mut BitStream) -> Result<u8,DecodeError> {
+        let bits = bitstream.peek(7) as u8;
+        // The code is complete, so one of these always matches
+        let (log_count, &(_, length)) = LOG_COUNT_CODES.iter().enumerate()
+            .find(|(_, &(code, length))| bits & ((1 << length) - 1) == code).unwrap();
+        bitstream.consume(length)?;
+        Ok(log_count as u8)
+    }
+
+    // The counts must add up to 4096, with at most 1 << log_alpha_size of them
+    pub fn from_counts(mut counts: Vec<u16>, log_alpha_size: u8) -> Self {
+        let table_size = 1usize << log_alpha_size;
+        let log_bucket_size = ANS_LOG_TAB_SIZE - log_alpha_size;
+        let bucket_size = 1u16 << log_bucket_size;
+        counts.resize(table_size, 0);
+
+        if let Some(symbol) = counts.iter().position(|&count| count as u32 == ANS_TAB_SIZE) {
+            return Self {
+                counts,
+                log_bucket_size,
+                cutoffs: vec![0; table_size],
+                symbols: vec![symbol as u8; table_size],
+                offsets: (0..table_size).map(|bucket| (bucket << log_bucket_size) as u16).collect()
+            };
+        }
+
+        // Fills the buckets that are short with slots from the ones that have too many
+        let mut cutoffs = counts.clone();
+        let mut symbols = vec![0u8; table_size];
+        let mut offsets = vec![0u16; table_size];
+        let mut underfull: Vec<usize> = (0..table_size).filter(|&i| cutoffs[i] < bucket_size).collect();
+        let mut overfull: Vec<usize> = (0..table_size).filter(|&i| cutoffs[i] > bucket_size).collect();
+        while let Some(over) = overfull.pop() {
+            let under = underfull.pop().expect("counts add up to the table size");
+            cutoffs[over] -= bucket_size - cutoffs[under];
+            symbols[under] = over as u8;
+            offsets[under] = cutoffs[over];
+            if cutoffs[over] < bucket_size {
+                underfull.push(over);
+            } else if cutoffs[over] > bucket_size {
+                overfull.push(over);
+            }
+        }
+        for i in 0..table_size {
+            if cutoffs[i] == bucket_size {
+                symbols[i] = i as u8;
+                offsets[i] = 0;
+                cutoffs[i] = 0;
+            } else {
+                offsets[i] = offsets[i].wrapping_sub(cutoffs[i]);
+            }
+        }
+        Self { counts, log_bucket_size, cutoffs, symbols, offsets }
+    }
+    pub fn count(&self, symbol: usize) -> u32 {
+        self.counts.get(symbol).copied().unwrap_or(0) as u32
+    }
+    // The symbol in slot `index` (below 4096), and its offset within that symbol's slots
+    pub fn lookup(&self, index: u32) -> (u8, u32) {
+        let bucket = (index >> self.log_bucket_size) as usize;
+        let position = index as u16 & ((1 << self.log_bucket_size) - 1);
+        if position < self.cutoffs[bucket] {
+            (bucket as u8, position as u32)
+        } else {
+            (self.symbols[bucket], (self.offsets[bucket].wrapping_add(position)) as u32)
+        }
+    }
+}
+
+// The rANS state, shared by all the distributions of an entropy coded stream
+#[derive(Debug)]
+pub struct AnsDecoder {
+    state: u32
+}
+
+impl AnsDecoder {
+    pub fn new(bitstream: &mut BitStream) -> Result<Self,DecodeError> {
+        Ok(Self { state: bitstream.read_u32(32).field("AnsDecoder.state")? })
+    }
+    pub fn read_symbol(&mut self, bitstream: &mut BitStream, distribution: &AnsDistribution) -> Result<u32,DecodeError> {
+        let (symbol, offset) = distribution.lookup(self.state & (ANS_TAB_SIZE - 1));
+        self.state = distribution.count(symbol as usize) * (self.state >> ANS_LOG_TAB_SIZE) + offset;
+        if self.state < 1 << 16 {
+            self.state = (self.state << 16) | bitstream.read_u32(16).field("AnsDecoder.state")?;
+        }
+        Ok(symbol as u32)
+    }
+    // The stream must end with the state the encoder started from
+    pub fn check_final_state(&self, bitstream: &BitStream) -> Result<(),DecodeError> {
+        if self.state != ANS_FINAL_STATE {
+            return Err(bitstream.invalid_value("AnsDecoder.state",Some(self.state as u64)));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod ans_tests {
+    use crate::ans::*;
+    use crate::bit_reader::BitStream;
+    use crate::bit_writer::BitWriter;
+
+    // rANS works backwards: the symbols are encoded last to first, starting from the final state
+    fn encode(distribution: &AnsDistribution, symbols: &[u8]) -> Vec<u8> {
+        let mut slots = vec![Vec::new(); 256];
+        for index in 0..ANS_TAB_SIZE {
+            let (symbol, offset) = distribution.lookup(index);
+            let slots = &mut slots[symbol as usize];
+            if slots.len() <= offset as usize { slots.resize(offset as usize + 1, 0); }
+            slots[offset as usize] = index;
+        }
+        let mut state = ANS_FINAL_STATE;
+        let mut chunks = Vec::new();
+        for &symbol in symbols.iter().rev() {
+            let count = distribution.count(symbol as usize);
+            if state >= count << 20 {
+                chunks.push(state & 0xffff);
+                state >>= 16;
+            }
+            state = ((state / count) << ANS_LOG_TAB_SIZE) | slots[symbol as usize][(state % count) as usize];
+        }
+        let mut writer = BitWriter::new();
+        writer.write(32, state as u64);
+        for chunk in chunks.iter().rev() {
+            writer.write(16, *chunk as u64);
+        }
+        writer.finish()
+    }
+
+    #[test]
+    fn alias_table_covers_every_slot_once() {
+        let counts = vec![2000, 1, 0, 1000, 95, 1000];
+        let distribution = AnsDistribution::from_counts(counts.clone(), 5);
+        let mut seen = vec![vec![false; 4096]; counts.len()];
+        for index in 0..ANS_TAB_SIZE {
+            let (symbol, offset) = distribution.lookup(index);
+            assert!(offset < counts[symbol as usize] as u32);
+            assert!(!seen[symbol as usize][offset as usize]);
+            seen[symbol as usize][offset as usize] = true;
+        }
+    }
+
+    #[test]
+    fn read_distributions() {
+        let mut writer = BitWriter::new();
+        // Simple, one symbol
+        writer.write_bool(true);
+        writer.write(1, 0);
+        writer.write_var_u8(7);
+        // Simple, two symbols
+        writer.write_bool(true);
+        writer.write(1, 1);
+        writer.write_var_u8(3);
+        writer.write_var_u8(1);
+        writer.write(12, 1000);
+        // Flat over 3 symbols
+        writer.write_bool(false);
+        writer.write_bool(true);
+        writer.write_var_u8(2);
+        // Log counts with a shift of 0, so every count is a power of two: 2560 (left out), 1024, 512, 0
+        writer.write_bool(false);
+        writer.write_bool(false);
+        writer.write(1, 0);
+        writer.write_var_u8(1);
+        for log_count in [12, 11, 10, 0] {
+            let (code, length) = LOG_COUNT_CODES[log_count];
+            writer.write(length, code as u64);
+        }
+        // Log counts with a shift of 13 and a run: 16 plus 4 more bits, then a run of 5 more of it, then the rest
+        writer.write_bool(false);
+        writer.write_bool(false);
+        writer.write(3, 0b111);
+        writer.write(3, 6);
+        writer.write_var_u8(4);
+        for log_count in [5, 13, 12] {
+            let (code, length) = LOG_COUNT_CODES[log_count];
+            writer.write(length, code as u64);
+            if log_count == 13 { writer.write_var_u8(1); }
+        }
+        writer.write(4, 0b1010);
+        let data = writer.finish();
+
+        let mut bitstream = BitStream::new(&data);
+        let read = |bitstream: &mut BitStream| AnsDistribution::read(bitstream, 8).map(|distribution| distribution.counts);
+        let mut expected = vec![0; 256];
+        expected[7] = 4096;
+        assert_eq!(read(&mut bitstream), Ok(expected.clone()));
+        expected.fill(0);
+        expected[1] = 3096;
+        expected[3] = 1000;
+        assert_eq!(read(&mut bitstream), Ok(expected.clone()));
+        expected.fill(0);
+        expected[..3].copy_from_slice(&[1366, 1365, 1365]);
+        assert_eq!(read(&mut bitstream), Ok(expected.clone()));
+        expected.fill(0);
+        expected[..3].copy_from_slice(&[2560, 1024, 512]);
+        assert_eq!(read(&mut bitstream), Ok(expected.clone()));
+        expected.fill(0);
+        expected[..7].copy_from_slice(&[26, 26, 26, 26, 26, 26, 4096 - 6 * 26]);
+        assert_eq!(read(&mut bitstream), Ok(expected.clone()));
+
+        // Too many symbols for the alphabet
+        let mut writer = BitWriter::new();
+        writer.write_bool(true);
+        writer.write(1, 0);
+        writer.write_var_u8(40);
+        let data = writer.finish();
+        assert!(AnsDistribution::read(&mut BitStream::new(&data), 5).is_err());
+    }
+
+    #[test]
+    fn decode_symbols() {
+        let distributions = [
+            AnsDistribution::from_counts(vec![2000, 1, 0, 1000, 95, 1000], 5),
+            AnsDistribution::from_counts(vec![0, 0, 4096], 6),
+            AnsDistribution::from_counts(vec![16; 256], 8)
+        ];
+        for distribution in &distributions {
+            let symbols: Vec<u8> = (0..1000u32)
+                .map(|i| i.wrapping_mul(2654435761) >> 7)
+                .filter_map(|i| {
+                    let symbol = (i % 256) as u8;
+                    (distribution.count(symbol as usize) != 0).then_some(symbol)
+                }).take(300).collect();
+            let data = encode(distribution, &symbols);
+            let mut bitstream = BitStream::new(&data);
+            let mut decoder = AnsDecoder::new(&mut bitstream).unwrap();
+            for &symbol in &symbols {
+                assert_eq!(decoder.read_symbol(&mut bitstream, distribution), Ok(symbol as u32));
+            }
+            assert_eq!(decoder.check_final_state(&bitstream), Ok(()));
+            assert!(bitstream.is_empty());
+        }
+
+        // A stream cut short ends on the wrong state
+        let data = encode(&distributions[0], &[0, 3, 3, 5]);
+        let mut bitstream = BitStream::new(&data);
+        let mut decoder = AnsDecoder::new(&mut bitstream).unwrap();
+        decoder.read_symbol(&mut bitstream, &distributions[0]).unwrap();
+        assert!(decoder.check_final_state(&bitstream).is_err());
+    }
+}
@@ -1,11 +1,19 @@
-// TODO: Write a more optimised BitStream class that doesn't just read each bit one-by-one as that is stupid
 #![allow(dead_code)]
 
-pub struct BitStream {
-    data: Vec<u8>,
+use std::fmt::Debug;
+use std::ops::Range;
+
+use crate::bit_trace::{BitTrace, TraceNode};
+use crate::decode_error::DecodeError;
+
+// Bits are read least significant first. Up to 64 bits are kept in `buffer`, which is refilled
+// from `data` a whole word at a time where possible.
+pub struct BitStream<'a> {
+    data: &'a [u8],
     ptr: usize,
-    tail: u8,
-    tail_len: u8
+    buffer: u64,
+    buffer_len: u8,
+    trace: Option<BitTrace>
 }
 
 #[derive(Copy, Clone, Debug)]
@@ -15,77 +23,155 @@ pub enum QuadDistributions {
     BitCountWithOffset(u8, u32)
 }
 
-impl BitStream {
-    pub fn new<'a>(data: &[u8]) -> Self { Self { data: data.to_owned(), ptr: 0, tail: data[0], tail_len: 8 } }
+impl<'a> BitStream<'a> {
+    pub fn new(data: &'a [u8]) -> Self { Self { data, ptr: 0, buffer: 0, buffer_len: 0, trace: None } }
+    // A separate stream over a byte range of the data this stream was made from, counted from its start.
+    // This stream's position doesn't move.
+    pub fn sub_stream(&self, range: Range<usize>) -> Result<BitStream<'a>,DecodeError> {
+        let end = range.end as u64 * 8;
+        match self.data.get(range) {
+            Some(data) => Ok(BitStream::new(data)),
+            None => Err(DecodeError::Truncated { bit_offset: end.min(self.data.len() as u64 * 8), field: "" })
+        }
+    }
 }
 
-impl BitStream {
-    pub fn is_empty(&self) -> bool {
-        self.tail_len == 0 && self.ptr == self.data.len()
-    } 
-    pub fn pad_zero(&mut self) -> Option<()> {
-        if self.is_empty() { None }
-        else if self.tail != 0 { None }
-        else { Some(()) }
-    }
-    fn read_bit(&mut self) -> Option<u8> {
-        if self.tail_len == 0 {
-            if self.ptr == (self.data.len() - 1) {
-                return None;
-            } else {
+impl BitStream<'_> {
+    // Tops the buffer up to at least 56 bits, or as many as are left
+    fn refill(&mut self) {
+        if self.ptr + 8 <= self.data.len() {
+            let word = u64::from_le_bytes(self.data[self.ptr..self.ptr+8].try_into().unwrap());
+            let bytes = (63 - self.buffer_len) / 8;
+            // bytes can't be 8 as buffer_len is never negative, so the shift stays in range
+            self.buffer |= word << self.buffer_len;
+            self.ptr += bytes as usize;
+            self.buffer_len += bytes * 8;
+        } else {
+            while self.buffer_len <= 56 && self.ptr < self.data.len() {
+                self.buffer |= (self.data[self.ptr] as u64) << self.buffer_len;
                 self.ptr += 1;
-                self.tail = self.data[self.ptr];
-                self.tail_len = 8;
+                self.buffer_len += 8;
             }
         }
-        let out = self.tail & 0x1;
-        self.tail >>= 1;
-        self.tail_len -= 1;
-        return Some(out);
-    }
-    pub fn read_bool(&mut self) -> Option<bool> {
-        let out = self.read_bit()?;
-        #[cfg(feature = "bit_read_debug_prints")]
-        println!("Read bool: {}",out);
-        Some(out != 0)
-    }
-    pub fn read_u8(&mut self, bits: u8) -> Option<u8> {
-        let mut out: u8 = 0;
-        for i in 0..bits {
-            out |= self.read_bit()? << i;
+    }
+    // Returns the next `bits` bits (at most 56) without consuming them. Past the end of the data, zeros are returned.
+    pub fn peek(&mut self, bits: u8) -> u64 {
+        debug_assert!(bits <= 56);
+        if self.buffer_len < bits { self.refill(); }
+        self.buffer & ((1u64 << bits) - 1)
+    }
+    pub fn consume(&mut self, bits: u8) -> Result<(),DecodeError> {
+        debug_assert!(bits <= 56);
+        if self.buffer_len < bits {
+            self.refill();
+            if self.buffer_len < bits { return Err(self.truncated()); }
+        }
+        self.buffer >>= bits;
+        self.buffer_len -= bits;
+        Ok(())
+    }
+    pub fn read(&mut self, bits: u8) -> Result<u64,DecodeError> {
+        if bits > 56 {
+            let low = self.read(32)?;
+            return Ok(low | (self.read(bits - 32)? << 32));
         }
-        #[cfg(feature = "bit_read_debug_prints")]
-        println!("Read u8({}): {}",bits,out);
-        Some(out)
-    }
-    pub fn read_u16(&mut self, bits: u8) -> Option<u16> {
-        let mut out: u16 = 0;
-        for i in 0..bits {
-            out |= (self.read_bit()? as u16) << i;
+        let out = self.peek(bits);
+        self.consume(bits)?;
+        Ok(out)
+    }
+    pub fn is_empty(&self) -> bool {
+        self.buffer_len == 0 && self.ptr == self.data.len()
+    }
+    pub fn bit_position(&self) -> u64 {
+        self.ptr as u64 * 8 - self.buffer_len as u64
+    }
+    // Number of bytes that have been at least partially read
+    pub fn bytes_consumed(&self) -> usize {
+        self.ptr - (self.buffer_len / 8) as usize
+    }
+    // Moves forward by `bits`. If that goes past the end of the data, the stream is left where it was.
+    pub fn skip_bits(&mut self, bits: u64) -> Result<(),DecodeError> {
+        if bits <= self.buffer_len.min(56) as u64 {
+            return self.consume(bits as u8);
         }
-        #[cfg(feature = "bit_read_debug_prints")]
-        println!("Read u16({}): {}",bits,out);
-        Some(out)
-    }
-    pub fn read_u32(&mut self, bits: u8) -> Option<u32> {
-        let mut out: u32 = 0;
-        for i in 0..bits {
-            out |= (self.read_bit()? as u32) << i;
+        let target = self.bit_position().checked_add(bits).filter(|&target| target <= self.data.len() as u64 * 8);
+        let Some(target) = target else { return Err(self.truncated()); };
+        self.ptr = (target / 8) as usize;
+        self.buffer = 0;
+        self.buffer_len = 0;
+        self.consume((target % 8) as u8)
+    }
+    pub fn skip_bytes(&mut self, bytes: u64) -> Result<(),DecodeError> {
+        self.skip_bits(bytes.checked_mul(8).ok_or_else(|| self.truncated())?)
+    }
+    // ZeroPadToByte: moves to the next byte boundary, checking that the bits skipped are all zero
+    pub fn align_to_byte(&mut self) -> Result<(),DecodeError> {
+        let padding = ((8 - self.bit_position() % 8) % 8) as u8;
+        if self.read(padding)? != 0 {
+            return Err(self.invalid_value("",None));
         }
-        #[cfg(feature = "bit_read_debug_prints")]
-        println!("Read u32({}): {}",bits,out);
-        Some(out)
-    }
-    pub fn read_u64(&mut self, bits: u8) -> Option<u64> {
-        let mut out: u64 = 0;
-        for i in 0..bits {
-            out |= (self.read_bit()? as u64) << i;
+        Ok(())
+    }
+
+    // Errors at the current position, for the header readers to return
+    pub fn truncated(&self) -> DecodeError {
+        DecodeError::Truncated { bit_offset: self.bit_position(), field: "" }
+    }
+    pub fn invalid_value(&self, field: &'static str, value: Option<u64>) -> DecodeError {
+        DecodeError::InvalidValue { bit_offset: self.bit_position(), field, value }
+    }
+    pub fn unsupported(&self, field: &'static str, feature: &'static str) -> DecodeError {
+        DecodeError::Unsupported { bit_offset: self.bit_position(), field, feature }
+    }
+
+    // Starts recording the fields read from here on, under a root node with the given name
+    pub fn enable_trace(&mut self, name: &'static str) {
+        self.trace = Some(BitTrace::new(name, self.bit_position()));
+    }
+    pub fn take_trace(&mut self) -> Option<TraceNode> {
+        let end = self.bit_position();
+        Some(self.trace.take()?.finish(end))
+    }
+    // Reads a single header field. Errors are tagged with `name`, and the value goes in the trace if there is one.
+    // Names are written as "Bundle.field"; the trace only shows the part after the dot.
+    pub fn field<T: Debug>(&mut self, name: &'static str, read: impl FnOnce(&mut Self) -> Result<T,DecodeError>) -> Result<T,DecodeError> {
+        let start = self.bit_position();
+        let out = read(self).map_err(|error| error.with_field(name))?;
+        let end = self.bit_position();
+        if let Some(trace) = &mut self.trace {
+            trace.field(name.rsplit('.').next().unwrap(), start, end, format!("{:?}",out));
         }
-        #[cfg(feature = "bit_read_debug_prints")]
-        println!("Read u64({}): {}",bits,out);
-        Some(out)
+        Ok(out)
+    }
+    // Reads a bundle of fields, which becomes a node of the trace
+    pub fn bundle<T>(&mut self, name: &'static str, read: impl FnOnce(&mut Self) -> Result<T,DecodeError>) -> Result<T,DecodeError> {
+        let start = self.bit_position();
+        if let Some(trace) = &mut self.trace { trace.begin(name, start); }
+        let out = read(self);
+        let end = self.bit_position();
+        if let Some(trace) = &mut self.trace { trace.end(end); }
+        out
+    }
+
+    fn read_bit(&mut self) -> Result<u8,DecodeError> {
+        Ok(self.read(1)? as u8)
+    }
+    pub fn read_bool(&mut self) -> Result<bool,DecodeError> {
+        Ok(self.read_bit()? != 0)
+    }
+    pub fn read_u8(&mut self, bits: u8) -> Result<u8,DecodeError> {
+        Ok(self.read(bits)? as u8)
+    }
+    pub fn read_u16(&mut self, bits: u8) -> Result<u16,DecodeError> {
+        Ok(self.read(bits)? as u16)
+    }
+    pub fn read_u32(&mut self, bits: u8) -> Result<u32,DecodeError> {
+        Ok(self.read(bits)? as u32)
+    }
+    pub fn read_u64(&mut self, bits: u8) -> Result<u64,DecodeError> {
+        self.read(bits)
     }
-    pub fn read_quad_u32(&mut self, d0: QuadDistributions, d1: QuadDistributions, d2: QuadDistributions, d3: QuadDistributions) -> Option<u32> {
+    pub fn read_quad_u32(&mut self, d0: QuadDistributions, d1: QuadDistributions, d2: QuadDistributions, d3: QuadDistributions) -> Result<u32,DecodeError> {
         let distribution = self.read_u8(2)?;
         let selected_distribution = [d0,d1,d2,d3][distribution as usize];
         
@@ -94,19 +180,49 @@ impl BitStream {
             QuadDistributions::BitCount(n) => self.read_u32(n)?,
             QuadDistributions::BitCountWithOffset(n, o) => self.read_u32(n)?.wrapping_add(o)
         };
-
-        #[cfg(feature = "bit_read_debug_prints")]
-        println!("Read U32({:?},{:?},{:?},{:?}): {}:{}",d0,d1,d2,d3,distribution,out);
-        
-        Some(out)
+        Ok(out)
+    }
+    // Half precision float. NaN and infinity aren't allowed in the codestream.
+    pub fn read_f16(&mut self) -> Result<f32,DecodeError> {
+        let bits = self.read_u16(16)? as u32;
+        let sign = bits >> 15;
+        let exponent = (bits >> 10) & 0x1f;
+        let mantissa = bits & 0x3ff;
+        if exponent == 0x1f {
+            return Err(self.invalid_value("",Some(bits as u64)));
+        }
+        let out = if exponent == 0 {
+            // Subnormal, exact in f32
+            mantissa as f32 * (1.0 / (1 << 24) as f32)
+        } else {
+            f32::from_bits(((exponent + 112) << 23) | (mantissa << 13))
+        };
+        Ok(if sign == 1 { -out } else { out })
+    }
+    // U8: a flag, then a 3-bit count n and n bits on top of 1 << n
+    pub fn read_var_u8(&mut self) -> Result<u8,DecodeError> {
+        if !self.read_bool()? { return Ok(0); }
+        let bits = self.read_u8(3)?;
+        Ok(self.read_u8(bits)? + (1 << bits))
     }
-    pub fn read_var_u64(&mut self) -> Option<u64> {
+    pub fn read_var_u64(&mut self) -> Result<u64,DecodeError> {
         match self.read_u8(2)? {
-            0 => Some(0),
-            1 => Some(self.read_u8(4)? as u64 + 1),
-            2 => Some(self.read_u8(8)? as u64 + 17),
+            0 => Ok(0),
+            1 => Ok(self.read_u8(4)? as u64 + 1),
+            2 => Ok(self.read_u8(8)? as u64 + 17),
             3 => {
-                todo!("Not implemented: U64.3");
+                // 12 bits, then 8-bit chunks each preceded by a continuation flag, ending with a 4-bit chunk at shift 60
+                let mut out = self.read_u64(12)?;
+                let mut shift = 12;
+                while self.read_bool()? {
+                    if shift == 60 {
+                        out |= self.read_u64(4)? << 60;
+                        break;
+                    }
+                    out |= self.read_u64(8)? << shift;
+                    shift += 8;
+                }
+                Ok(out)
             }
             _ => unreachable!()
         }
@@ -116,42 +232,155 @@ impl BitStream {
 #[cfg(test)]
 mod bit_stream_tests {
     use crate::bit_reader::BitStream;
+    use crate::decode_error::DecodeError;
     
     #[test]
     fn read_bits() {
-        let mut stream = BitStream::new(Vec::from([0b0011_0111,0b1001_0110,0b1111_0010]).as_slice());
+        let mut stream = BitStream::new(&[0b0011_0111,0b1001_0110,0b1111_0010]);
         let mut str: String = String::new();
         loop {
-            str.push_str(&format!("{}",match stream.read_bit() {Some(x)=>x, None=>break}));
+            str.push_str(&format!("{}",match stream.read_bit() {Ok(x)=>x, Err(_)=>break}));
         }
         assert_eq!("111011000110100101001111",str);
     }
 
     #[test]
     fn read_bytes_aligned() {
-        let mut stream = BitStream::new(Vec::from([0b0011_0111,0b1001_0110,0b1111_0010]).as_slice());
-        assert_eq!(stream.read_u8(8),Some(0b0011_0111));
-        assert_eq!(stream.read_u8(8),Some(0b1001_0110));
-        assert_eq!(stream.read_u8(8),Some(0b1111_0010));        
+        let mut stream = BitStream::new(&[0b0011_0111,0b1001_0110,0b1111_0010]);
+        assert_eq!(stream.read_u8(8),Ok(0b0011_0111));
+        assert_eq!(stream.read_u8(8),Ok(0b1001_0110));
+        assert_eq!(stream.read_u8(8),Ok(0b1111_0010));        
     }
 
     #[test]
+    #[allow(clippy::unusual_byte_groupings)]
     fn read_bytes_misaligned() {
-        let mut stream = BitStream::new(Vec::from([0b0011_0111,0b1001_0110,0b1111_0010]).as_slice());
-        assert_eq!(stream.read_u8(3),Some(0b111));
-        assert_eq!(stream.read_u8(8),Some(0b110_0011_0));
-        assert_eq!(stream.read_u8(8),Some(0b010_1001_0));       
+        let mut stream = BitStream::new(&[0b0011_0111,0b1001_0110,0b1111_0010]);
+        assert_eq!(stream.read_u8(3),Ok(0b111));
+        assert_eq!(stream.read_u8(8),Ok(0b110_0011_0));
+        assert_eq!(stream.read_u8(8),Ok(0b010_1001_0));       
     }
 
     #[test]
     fn read_a_lot() {
-        let mut stream = BitStream::new(Vec::from([
+        let mut stream = BitStream::new(&[
             0b0011_0111,0b1001_0110,0b1111_0010,0b1101_1011,
             0b0101_0101,0b0000_1111,0b1010_1010,0b1111_0000,0b0101_0101,0b0000_1111,0b1010_1010,0b1111_0000,
             0b1011_0111,0b1111_1100
-        ]).as_slice());
-        assert_eq!(stream.read_u32(32),Some(0b1101_1011_1111_0010_1001_0110_0011_0111));
-        assert_eq!(stream.read_u64(64),Some(0b1111_0000_1010_1010_0000_1111_0101_0101_1111_0000_1010_1010_0000_1111_0101_0101));   
-        assert_eq!(stream.read_u16(16),Some(0b1111_1100_1011_0111));
+        ]);
+        assert_eq!(stream.read_u32(32),Ok(0b1101_1011_1111_0010_1001_0110_0011_0111));
+        assert_eq!(stream.read_u64(64),Ok(0b1111_0000_1010_1010_0000_1111_0101_0101_1111_0000_1010_1010_0000_1111_0101_0101));   
+        assert_eq!(stream.read_u16(16),Ok(0b1111_1100_1011_0111));
+    }
+
+    #[test]
+    fn peek_and_consume() {
+        let data: Vec<u8> = (0..20).collect();
+        let mut stream = BitStream::new(&data);
+        assert_eq!(stream.peek(16),0x0100);
+        assert_eq!(stream.peek(16),0x0100);
+        stream.consume(4).unwrap();
+        let low = u128::from_le_bytes(data[0..16].try_into().unwrap());
+        let high = u128::from_le_bytes(data[4..20].try_into().unwrap());
+        assert_eq!(stream.read(40),Ok((low >> 4) as u64 & ((1 << 40) - 1)));
+        assert_eq!(stream.read(64),Ok((low >> 44) as u64));
+        assert_eq!(stream.read(52),Ok((high >> 76) as u64));
+        assert!(stream.is_empty());
+        assert_eq!(stream.read(1),Err(DecodeError::Truncated { bit_offset: 160, field: "" }));
+    }
+
+    // Packs (value, bit count) pairs least significant bit first
+    fn pack_bits(fields: &[(u64, u8)]) -> Vec<u8> {
+        let mut out: Vec<u8> = Vec::new();
+        let mut bit_len = 0;
+        for &(value, bits) in fields {
+            for i in 0..bits {
+                if bit_len % 8 == 0 { out.push(0); }
+                *out.last_mut().unwrap() |= (((value >> i) & 1) as u8) << (bit_len % 8);
+                bit_len += 1;
+            }
+        }
+        out
+    }
+
+    #[test]
+    fn read_var_u64() {
+        let data = pack_bits(&[(0,2), (1,2),(15,4), (2,2),(255,8)]);
+        let mut stream = BitStream::new(&data);
+        assert_eq!(stream.read_var_u64(),Ok(0));
+        assert_eq!(stream.read_var_u64(),Ok(16));
+        assert_eq!(stream.read_var_u64(),Ok(272));
+
+        let data = pack_bits(&[(3,2),(0x123,12),(0,1), (3,2),(0xabc,12),(1,1),(0xde,8),(0,1)]);
+        let mut stream = BitStream::new(&data);
+        assert_eq!(stream.read_var_u64(),Ok(0x123));
+        assert_eq!(stream.read_var_u64(),Ok(0xdeabc));
+
+        let mut fields = vec![(3,2),(0xfff,12)];
+        for _ in 0..6 { fields.extend([(1,1),(0xff,8)]); }
+        fields.extend([(1,1),(0xf,4)]);
+        let data = pack_bits(&fields);
+        let mut stream = BitStream::new(&data);
+        assert_eq!(stream.read_var_u64(),Ok(u64::MAX));
+
+        // Cut off in the middle of a continuation chunk
+        let data = pack_bits(&[(3,2),(0,12),(1,1)]);
+        assert_eq!(BitStream::new(&data).read_var_u64(),Err(DecodeError::Truncated { bit_offset: 15, field: "" }));
+    }
+
+    #[test]
+    fn skip_and_align() {
+        let data: Vec<u8> = (0..32).collect();
+        let mut stream = BitStream::new(&data);
+        stream.skip_bits(3).unwrap();
+        assert_eq!(stream.bit_position(),3);
+        assert_eq!(stream.align_to_byte(),Ok(()));
+        assert_eq!(stream.bit_position(),8);
+        assert_eq!(stream.align_to_byte(),Ok(()));
+        assert_eq!(stream.bit_position(),8);
+        stream.skip_bits(100).unwrap();
+        assert_eq!(stream.bit_position(),108);
+        assert_eq!(stream.read_u8(4),Ok(13 >> 4));
+        stream.skip_bytes(2).unwrap();
+        assert_eq!(stream.read_u8(8),Ok(16));
+        assert_eq!(stream.skip_bits(1000),Err(DecodeError::Truncated { bit_offset: 136, field: "" }));
+        assert_eq!(stream.bit_position(),136);
+
+        // Byte 17 is 0b0001_0001, so skipping one bit leaves non-zero padding
+        stream.skip_bits(1).unwrap();
+        assert_eq!(stream.align_to_byte(),Err(DecodeError::InvalidValue { bit_offset: 144, field: "", value: None }));
+    }
+
+    #[test]
+    fn sub_stream() {
+        let data: Vec<u8> = (0..32).collect();
+        let mut stream = BitStream::new(&data);
+        stream.skip_bytes(4).unwrap();
+        let mut group = stream.sub_stream(10..12).unwrap();
+        assert_eq!(group.read_u16(16),Ok(0x0b0a));
+        assert!(group.is_empty());
+        assert_eq!(stream.read_u8(8),Ok(4));
+        assert_eq!(stream.sub_stream(30..33).err(),Some(DecodeError::Truncated { bit_offset: 256, field: "" }));
+    }
+
+    #[test]
+    fn read_f16() {
+        let values: [u16; 9] = [0x0000, 0x8000, 0x3c00, 0xc000, 0x3555, 0x7bff, 0x0001, 0x03ff, 0x0400];
+        let data: Vec<u8> = values.iter().flat_map(|value| value.to_le_bytes()).collect();
+        let mut stream = BitStream::new(&data);
+        assert_eq!(stream.read_f16().map(f32::to_bits),Ok(0.0f32.to_bits()));
+        assert_eq!(stream.read_f16().map(f32::to_bits),Ok((-0.0f32).to_bits()));
+        assert_eq!(stream.read_f16(),Ok(1.0));
+        assert_eq!(stream.read_f16(),Ok(-2.0));
+        assert_eq!(stream.read_f16(),Ok(0.33325195));
+        assert_eq!(stream.read_f16(),Ok(65504.0));
+        assert_eq!(stream.read_f16(),Ok(2.0f32.powi(-24)));
+        assert_eq!(stream.read_f16(),Ok(1023.0 * 2.0f32.powi(-24)));
+        assert_eq!(stream.read_f16(),Ok(2.0f32.powi(-14)));
+
+        for invalid in [0x7c00u16, 0xfc00, 0x7e00, 0x7fff] {
+            let data = invalid.to_le_bytes();
+            assert_eq!(BitStream::new(&data).read_f16(),Err(DecodeError::InvalidValue { bit_offset: 16, field: "", value: Some(invalid as u64) }));
+        }
     }
-}
\ No newline at end of file
+}
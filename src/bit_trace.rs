@@ -0,0 +1,124 @@
+#![allow(dead_code)]
+
+use std::fmt;
+
+// A field read from the bitstream. Bundles have children and no value, single fields have a value and no children.
+#[derive(Debug,Clone,PartialEq)]
+pub struct TraceNode {
+    pub name: &'static str,
+    pub start: u64,     // Bit position of the first bit
+    pub end: u64,       // Bit position after the last bit
+    pub value: Option<String>,
+    pub children: Vec<TraceNode>
+}
+
+impl TraceNode {
+    pub fn to_json(&self) -> String {
+        let mut out = String::new();
+        self.write_json(&mut out);
+        out
+    }
+    fn write_json(&self, out: &mut String) {
+        out.push_str(&format!("{{\"name\":{},\"start\":{},\"end\":{}",json_string(self.name),self.start,self.end));
+        if let Some(value) = &self.value {
+            out.push_str(&format!(",\"value\":{}",json_string(value)));
+        }
+        if !self.children.is_empty() {
+            out.push_str(",\"children\":[");
+            for (index, child) in self.children.iter().enumerate() {
+                if index != 0 { out.push(','); }
+                child.write_json(out);
+            }
+            out.push(']');
+        }
+        out.push('}');
+    }
+    fn write_listing(&self, f: &mut fmt::Formatter, depth: usize) -> fmt::Result {
+        write!(f,"{:indent$}{} [{}..{}]","",self.name,self.start,self.end,indent = depth * 2)?;
+        match &self.value {
+            Some(value) => writeln!(f," = {}",value)?,
+            None => writeln!(f)?
+        }
+        for child in &self.children {
+            child.write_listing(f, depth + 1)?;
+        }
+        Ok(())
+    }
+}
+
+// The indented listing, one field per line
+impl fmt::Display for TraceNode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.write_listing(f, 0)
+    }
+}
+
+fn json_string(value: &str) -> String {
+    let mut out = String::from("\"");
+    for c in value.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}",c as u32)),
+            c => out.push(c)
+        }
+    }
+    out.push('"');
+    out
+}
+
+// Builds the tree while the bitstream is read. The last node of `open` is the bundle being read.
+#[derive(Debug)]
+pub struct BitTrace {
+    open: Vec<TraceNode>
+}
+
+impl BitTrace {
+    pub fn new(name: &'static str, start: u64) -> Self {
+        Self { open: vec![TraceNode { name, start, end: start, value: None, children: Vec::new() }] }
+    }
+    pub fn begin(&mut self, name: &'static str, start: u64) {
+        self.open.push(TraceNode { name, start, end: start, value: None, children: Vec::new() });
+    }
+    pub fn end(&mut self, end: u64) {
+        if self.open.len() < 2 { return; }
+        let mut node = self.open.pop().unwrap();
+        node.end = end;
+        self.open.last_mut().unwrap().children.push(node);
+    }
+    pub fn field(&mut self, name: &'static str, start: u64, end: u64, value: String) {
+        self.open.last_mut().unwrap().children.push(TraceNode { name, start, end, value: Some(value), children: Vec::new() });
+    }
+    // Closes any bundles left open by an error and returns the root
+    pub fn finish(mut self, end: u64) -> TraceNode {
+        while self.open.len() > 1 {
+            self.end(end);
+        }
+        let mut root = self.open.pop().unwrap();
+        root.end = end;
+        root
+    }
+}
+
+#[cfg(test)]
+mod bit_trace_tests {
+    use crate::bit_reader::BitStream;
+    use crate::common::ImageSize;
+
+    #[test]
+    fn trace_image_size() {
+        // div8, height (0+1)*8, ratio 1
+        let mut bitstream = BitStream::new(&[0b0100_0001,0b0000_0000]);
+        bitstream.enable_trace("test");
+        assert_eq!(ImageSize::read(&mut bitstream), Ok(ImageSize { width: 8, height: 8 }));
+        let trace = bitstream.take_trace().unwrap();
+        assert_eq!(trace.to_string(), concat!(
+            "test [0..9]\n",
+            "  ImageSize [0..9]\n",
+            "    div8 [0..1] = true\n",
+            "    height_div8 [1..6] = 1\n",
+            "    ratio [6..9] = 1\n"
+        ));
+        assert_eq!(trace.children[0].children[0].to_json(), r#"{"name":"div8","start":0,"end":1,"value":"true"}"#);
+    }
+}
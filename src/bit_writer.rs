@@ -0,0 +1,192 @@
+#![allow(dead_code)]
+
+use std::io::Error as IoError;
+use std::io::ErrorKind;
+
+use crate::bit_reader::QuadDistributions;
+
+// Writes bits least significant first, so that BitStream reads them back in the same order
+pub struct BitWriter {
+    data: Vec<u8>,
+    buffer: u64,
+    buffer_len: u8
+}
+
+impl Default for BitWriter {
+    fn default() -> Self { Self::new() }
+}
+
+impl BitWriter {
+    pub fn new() -> Self { Self { data: Vec::new(), buffer: 0, buffer_len: 0 } }
+
+    pub fn bit_position(&self) -> u64 {
+        self.data.len() as u64 * 8 + self.buffer_len as u64
+    }
+    pub fn write(&mut self, bits: u8, value: u64) {
+        debug_assert!(bits == 64 || value >> bits == 0);
+        if bits > 56 {
+            self.write(32, value & 0xffff_ffff);
+            self.write(bits - 32, value >> 32);
+            return;
+        }
+        self.buffer |= value << self.buffer_len;
+        self.buffer_len += bits;
+        while self.buffer_len >= 8 {
+            self.data.push(self.buffer as u8);
+            self.buffer >>= 8;
+            self.buffer_len -= 8;
+        }
+    }
+    pub fn write_bool(&mut self, value: bool) {
+        self.write(1, value as u64);
+    }
+    // Pads with zeros up to the next byte boundary
+    pub fn align_to_byte(&mut self) {
+        let padding = (8 - self.buffer_len % 8) % 8;
+        self.write(padding, 0);
+    }
+    // Pads the last byte with zeros and returns the data
+    pub fn finish(mut self) -> Vec<u8> {
+        self.align_to_byte();
+        self.data
+    }
+
+    // Uses the first distribution that can hold the value
+    pub fn write_quad_u32(&mut self, value: u32, d0: QuadDistributions, d1: QuadDistributions, d2: QuadDistributions, d3: QuadDistributions) -> Result<(),IoError> {
+        for (selector, distribution) in [d0,d1,d2,d3].into_iter().enumerate() {
+            let bits = match distribution {
+                QuadDistributions::RawValue(n) if n == value => Some((0, 0)),
+                QuadDistributions::BitCount(n) if n == 32 || value >> n == 0 => Some((n, value)),
+                QuadDistributions::BitCountWithOffset(n, o) if value >= o && (n == 32 || (value - o) >> n == 0) => Some((n, value - o)),
+                _ => None
+            };
+            if let Some((n, raw)) = bits {
+                self.write(2, selector as u64);
+                self.write(n, raw as u64);
+                return Ok(());
+            }
+        }
+        Err(unrepresentable(&format!("{} doesn't fit U32({:?},{:?},{:?},{:?})",value,d0,d1,d2,d3)))
+    }
+    pub fn write_var_u8(&mut self, value: u8) {
+        self.write_bool(value != 0);
+        if value != 0 {
+            let bits = value.ilog2() as u8;
+            self.write(3, bits as u64);
+            self.write(bits, (value - (1 << bits)) as u64);
+        }
+    }
+    pub fn write_var_u64(&mut self, value: u64) {
+        match value {
+            0 => self.write(2, 0),
+            1..=16 => {
+                self.write(2, 1);
+                self.write(4, value - 1);
+            },
+            17..=272 => {
+                self.write(2, 2);
+                self.write(8, value - 17);
+            },
+            _ => {
+                self.write(2, 3);
+                self.write(12, value & 0xfff);
+                let mut rest = value >> 12;
+                let mut shift = 12;
+                while rest != 0 {
+                    self.write_bool(true);
+                    if shift == 60 {
+                        self.write(4, rest);
+                        return;
+                    }
+                    self.write(8, rest & 0xff);
+                    rest >>= 8;
+                    shift += 8;
+                }
+                self.write_bool(false);
+            }
+        }
+    }
+    // Rounds to the nearest half precision value. Values that would round to infinity, and NaN, can't be written.
+    pub fn write_f16(&mut self, value: f32) -> Result<(),IoError> {
+        let bits = value.to_bits();
+        let sign = ((bits >> 16) & 0x8000) as u16;
+        let abs = value.abs();
+        let half = if !value.is_finite() {
+            None
+        } else if abs < 1.0 / (1 << 14) as f32 {
+            // Subnormal: multiples of 2^-24, which round exactly
+            Some(sign | (abs * (1 << 24) as f32).round_ties_even() as u16)
+        } else {
+            let exponent = ((bits >> 23) & 0xff) as i32 - 127 + 15;
+            let mantissa = bits & 0x7f_ffff;
+            let remainder = mantissa & 0x1fff;
+            let mut out = ((exponent as u32) << 10) | (mantissa >> 13);
+            if remainder > 0x1000 || (remainder == 0x1000 && out & 1 == 1) {
+                out += 1;
+            }
+            if out >= 0x7c00 { None } else { Some(sign | out as u16) }
+        };
+        match half {
+            Some(half) => {
+                self.write(16, half as u64);
+                Ok(())
+            },
+            None => Err(unrepresentable(&format!("{} is out of range for F16",value)))
+        }
+    }
+}
+
+pub fn unrepresentable(message: &str) -> IoError {
+    IoError::new(ErrorKind::InvalidInput, message)
+}
+
+#[cfg(test)]
+mod bit_writer_tests {
+    use crate::bit_reader::BitStream;
+    use crate::bit_reader::QuadDistributions::*;
+    use crate::bit_writer::BitWriter;
+
+    #[test]
+    fn write_and_read_back() {
+        let mut writer = BitWriter::new();
+        writer.write(3, 0b101);
+        writer.write_bool(true);
+        writer.write(64, u64::MAX - 1);
+        for value in [0, 1, 16, 17, 272, 273, 0xfff, 0x1000, 1 << 60, u64::MAX] {
+            writer.write_var_u64(value);
+        }
+        for value in [0, 1, 2, 3, 128, 255] {
+            writer.write_var_u8(value);
+        }
+        for value in [0, 1, 2, 9, 10, 1 << 20] {
+            writer.write_quad_u32(value, RawValue(0), BitCountWithOffset(3, 1), BitCount(8), BitCount(32)).unwrap();
+        }
+        assert!(writer.write_quad_u32(9, RawValue(0), RawValue(1), BitCount(3), BitCountWithOffset(2, 10)).is_err());
+        for value in [0.0, -0.0, 1.0, -2.5, 65504.0, 1.0 / 16777216.0, 0.1] {
+            writer.write_f16(value).unwrap();
+        }
+        assert!(writer.write_f16(65520.0).is_err());
+        assert!(writer.write_f16(f32::NAN).is_err());
+        let data = writer.finish();
+
+        let mut stream = BitStream::new(&data);
+        assert_eq!(stream.read(3), Ok(0b101));
+        assert_eq!(stream.read_bool(), Ok(true));
+        assert_eq!(stream.read(64), Ok(u64::MAX - 1));
+        for value in [0, 1, 16, 17, 272, 273, 0xfff, 0x1000, 1 << 60, u64::MAX] {
+            assert_eq!(stream.read_var_u64(), Ok(value));
+        }
+        for value in [0, 1, 2, 3, 128, 255] {
+            assert_eq!(stream.read_var_u8(), Ok(value));
+        }
+        for value in [0, 1, 2, 9, 10, 1 << 20] {
+            assert_eq!(stream.read_quad_u32(RawValue(0), BitCountWithOffset(3, 1), BitCount(8), BitCount(32)), Ok(value));
+        }
+        for value in [0.0, -0.0, 1.0, -2.5, 65504.0, 1.0 / 16777216.0] {
+            assert_eq!(stream.read_f16().map(f32::to_bits), Ok(f32::to_bits(value)));
+        }
+        assert_eq!(stream.read_f16(), Ok(0.099975586));
+        assert_eq!(stream.align_to_byte(), Ok(()));
+        assert!(stream.is_empty());
+    }
+}
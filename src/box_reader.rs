@@ -0,0 +1,172 @@
+#![allow(dead_code)]
+
+use std::io::Error as IoError;
+use std::io::ErrorKind;
+use std::io::{Read, Seek, SeekFrom, Take};
+
+use crate::jxl_file::{JxlBox, JxlBoxType};
+
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub struct JxlBoxHeader {
+    pub box_type: JxlBoxType,
+    pub offset: u64,          // Position of the box header in the source
+    pub header_size: u64,     // 8, or 16 if the box uses a 64-bit size (0 for a raw codestream)
+    pub size: u64,            // Size of the whole box including the header
+    pub to_end_of_file: bool  // The size field was 0, so the box runs until the end of the source
+}
+impl JxlBoxHeader {
+    pub fn payload_offset(&self) -> u64 {
+        self.offset + self.header_size
+    }
+    pub fn payload_size(&self) -> u64 {
+        self.size - self.header_size
+    }
+}
+
+// Walks the boxes of a file one header at a time, only reading payloads when asked to
+pub struct JxlBoxReader<R: Read + Seek> {
+    src: R,
+    len: u64,
+    next_offset: u64,
+    finished: bool
+}
+
+impl<R: Read + Seek> JxlBoxReader<R> {
+    pub fn new(mut src: R) -> Result<Self,IoError> {
+        let len = src.seek(SeekFrom::End(0))?;
+        Ok(Self { src, len, next_offset: 0, finished: false })
+    }
+
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn into_inner(self) -> R {
+        self.src
+    }
+
+    pub fn payload_reader(&mut self, header: &JxlBoxHeader) -> Result<Take<&mut R>,IoError> {
+        self.src.seek(SeekFrom::Start(header.payload_offset()))?;
+        Ok((&mut self.src).take(header.payload_size()))
+    }
+
+    pub fn read_payload(&mut self, header: &JxlBoxHeader) -> Result<Vec<u8>,IoError> {
+        let mut data: Vec<u8> = Vec::with_capacity(header.payload_size() as usize);
+        self.payload_reader(header)?.read_to_end(&mut data)?;
+        if data.len() as u64 != header.payload_size() {
+            return Err(IoError::new(ErrorKind::UnexpectedEof,format!("{:?} box at offset {} is truncated",header.box_type,header.offset)));
+        }
+        Ok(data)
+    }
+
+    // Reads the payload and unwraps it if the box is Brotli-compressed
+    pub fn read_box(&mut self, header: &JxlBoxHeader) -> Result<JxlBox,IoError> {
+        let jxl_box = JxlBox {
+            box_type: header.box_type,
+            length: header.size,
+            data: self.read_payload(header)?
+        };
+        match jxl_box.box_type {
+            JxlBoxType::JXL_BROTLI => jxl_box.decompress_brotli(),
+            _ => Ok(jxl_box)
+        }
+    }
+
+    fn read_header(&mut self) -> Result<Option<JxlBoxHeader>,IoError> {
+        let offset = self.next_offset;
+        if offset >= self.len { return Ok(None); }
+        self.src.seek(SeekFrom::Start(offset))?;
+
+        let mut signature = [0u8; 2];
+        if offset == 0 && self.len >= 2 {
+            self.src.read_exact(&mut signature)?;
+            if signature == [0xff, 0x0a] {
+                self.next_offset = self.len;
+                return Ok(Some(JxlBoxHeader {
+                    box_type: JxlBoxType::JXL_RAW,
+                    offset,
+                    header_size: 0,
+                    size: self.len,
+                    to_end_of_file: true
+                }));
+            }
+            self.src.seek(SeekFrom::Start(offset))?;
+        }
+
+        if self.len - offset < 8 {
+            return Err(IoError::new(ErrorKind::UnexpectedEof,format!("Box header at offset {} is truncated",offset)));
+        }
+        let mut header = [0u8; 8];
+        self.src.read_exact(&mut header)?;
+        let box_type = JxlBoxType::from_fourcc(header[4..8].try_into().unwrap());
+        let (header_size, size, to_end_of_file) = match u32::from_be_bytes(header[0..4].try_into().unwrap()) {
+            0 => (8, self.len - offset, true),
+            1 => {
+                if self.len - offset < 16 {
+                    return Err(IoError::new(ErrorKind::UnexpectedEof,format!("Box header at offset {} is truncated",offset)));
+                }
+                let mut extended_size = [0u8; 8];
+                self.src.read_exact(&mut extended_size)?;
+                (16, u64::from_be_bytes(extended_size), false)
+            },
+            n => (8, n as u64, false)
+        };
+        if size < header_size {
+            return Err(IoError::new(ErrorKind::InvalidData,format!("{:?} box at offset {} has invalid size {}",box_type,offset,size)));
+        }
+        if size > self.len - offset {
+            return Err(IoError::new(ErrorKind::UnexpectedEof,format!("{:?} box at offset {} extends past the end of the file",box_type,offset)));
+        }
+        self.next_offset = offset + size;
+        Ok(Some(JxlBoxHeader { box_type, offset, header_size, size, to_end_of_file }))
+    }
+}
+
+impl<R: Read + Seek> Iterator for JxlBoxReader<R> {
+    type Item = Result<JxlBoxHeader,IoError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.finished { return None; }
+        let header = self.read_header().transpose();
+        if !matches!(header, Some(Ok(_))) {
+            self.finished = true;
+        }
+        header
+    }
+}
+
+#[cfg(test)]
+mod box_reader_tests {
+    use std::io::Cursor;
+    use crate::box_reader::JxlBoxReader;
+    use crate::jxl_file::JxlBoxType;
+
+    #[test]
+    fn read_box_headers() {
+        let mut data: Vec<u8> = Vec::new();
+        data.extend_from_slice(b"\0\0\0\x0cJXL \x0d\x0a\x87\x0a");
+        data.extend_from_slice(b"\0\0\0\x01free\0\0\0\0\0\0\0\x12ab");
+        data.extend_from_slice(b"\0\0\0\0jxlc\xff\x0a\x00");
+        let mut reader = JxlBoxReader::new(Cursor::new(data)).unwrap();
+        let headers: Vec<_> = reader.by_ref().collect::<Result<_,_>>().unwrap();
+        assert_eq!(headers.len(),3);
+        assert_eq!(headers[0].box_type,JxlBoxType::JXL_SIGNATURE);
+        assert_eq!(headers[1].box_type,JxlBoxType::Unknown(*b"free"));
+        assert_eq!((headers[1].offset,headers[1].header_size,headers[1].size),(12,16,18));
+        assert_eq!(headers[2].box_type,JxlBoxType::JXL_CODESTREAM);
+        assert!(headers[2].to_end_of_file);
+        assert_eq!(reader.read_payload(&headers[1]).unwrap(),b"ab");
+        assert_eq!(reader.read_payload(&headers[2]).unwrap(),b"\xff\x0a\x00");
+    }
+
+    #[test]
+    fn read_truncated_box() {
+        let mut reader = JxlBoxReader::new(Cursor::new(b"\0\0\0\x10jxlc\xff\x0a".to_vec())).unwrap();
+        assert!(reader.next().unwrap().is_err());
+        assert!(reader.next().is_none());
+    }
+}
@@ -0,0 +1,66 @@
+#![allow(dead_code)]
+
+use std::io::Error as IoError;
+use std::io::Write;
+
+use crate::bit_writer::BitWriter;
+
+pub const JXL_SIGNATURE_BOX: &[u8] = b"\0\0\0\x0cJXL \x0d\x0a\x87\x0a";
+pub const JXL_FILE_TYPE_BOX: &[u8] = b"\0\0\0\x14ftypjxl \0\0\0\0jxl ";
+
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub enum JxlCodestreamLayout {
+    Single,                         // One "jxlc" box
+    Partial { part_size: usize }    // A sequence of "jxlp" boxes holding at most part_size bytes of codestream each
+}
+
+// Writes a box header and payload, switching to a 64-bit size when the box doesn't fit in 32 bits
+pub fn write_box<W: Write>(dst: &mut W, box_type: &[u8;4], payload: &[&[u8]]) -> Result<(),IoError> {
+    let payload_size: u64 = payload.iter().map(|part| part.len() as u64).sum();
+    if payload_size + 8 <= u32::MAX as u64 {
+        dst.write_all(&((payload_size + 8) as u32).to_be_bytes())?;
+        dst.write_all(box_type)?;
+    } else {
+        dst.write_all(&1u32.to_be_bytes())?;
+        dst.write_all(box_type)?;
+        dst.write_all(&(payload_size + 16).to_be_bytes())?;
+    }
+    for part in payload {
+        dst.write_all(part)?;
+    }
+    Ok(())
+}
+
+pub fn write_codestream<W: Write>(dst: &mut W, codestream: &[u8], layout: JxlCodestreamLayout) -> Result<(),IoError> {
+    match layout {
+        JxlCodestreamLayout::Single => write_box(dst, b"jxlc", &[codestream]),
+        JxlCodestreamLayout::Partial { part_size } => {
+            let parts: Vec<&[u8]> = if codestream.is_empty() { vec![codestream] } else { codestream.chunks(part_size.max(1)).collect() };
+            for (index, part) in parts.iter().enumerate() {
+                let is_last = index == parts.len() - 1;
+                let index = index as u32 | if is_last { 1 << 31 } else { 0 };
+                write_box(dst, b"jxlp", &[&index.to_be_bytes(), part])?;
+            }
+            Ok(())
+        }
+    }
+}
+
+// Wraps data in a Brotli stream without compressing it, as uncompressed meta-blocks of up to 64 KiB each
+pub fn brotli_stored(data: &[u8]) -> Vec<u8> {
+    let mut writer = BitWriter::new();
+    writer.write(1, 0);                         // 16-bit window
+    for block in data.chunks(1 << 16) {
+        writer.write_bool(false);               // Not the last meta-block
+        writer.write(2, 0);                     // 4 nibbles of length
+        writer.write(16, block.len() as u64 - 1);
+        writer.write_bool(true);                // Uncompressed
+        writer.align_to_byte();
+        for &byte in block {
+            writer.write(8, byte as u64);
+        }
+    }
+    writer.write_bool(true);                    // An empty last meta-block
+    writer.write_bool(true);
+    writer.finish()
+}
@@ -0,0 +1,299 @@
+#![allow(dead_code)]
+
+use std::fmt::Debug;
+use std::io::Error as IoError;
+
+use crate::bit_reader::{BitStream, QuadDistributions};
+use crate::bit_writer::{BitWriter, unrepresentable};
+use crate::common::{pack_signed, unpack_signed};
+use crate::decode_error::DecodeError;
+
+// How a single field is stored in the bitstream. Values are passed around as u64, F16 values as the bits of an f32.
+#[derive(Debug,Clone,Copy)]
+pub enum Coding {
+    Bool,
+    Bits(u8),
+    BitsWithOffset(u8, u32),
+    U32(QuadDistributions, QuadDistributions, QuadDistributions, QuadDistributions),
+    U64,
+    F16
+}
+impl Coding {
+    pub fn read(self, bitstream: &mut BitStream) -> Result<u64,DecodeError> {
+        match self {
+            Coding::Bool => Ok(bitstream.read_bool()? as u64),
+            Coding::Bits(n) => bitstream.read(n),
+            Coding::BitsWithOffset(n, offset) => Ok(bitstream.read(n)? + offset as u64),
+            Coding::U32(d0, d1, d2, d3) => Ok(bitstream.read_quad_u32(d0, d1, d2, d3)? as u64),
+            Coding::U64 => bitstream.read_var_u64(),
+            Coding::F16 => Ok(bitstream.read_f16()?.to_bits() as u64)
+        }
+    }
+    pub fn write(self, writer: &mut BitWriter, raw: u64) -> Result<(),IoError> {
+        match self {
+            Coding::Bool if raw <= 1 => writer.write_bool(raw == 1),
+            Coding::Bits(n) if n == 64 || raw >> n == 0 => writer.write(n, raw),
+            Coding::BitsWithOffset(n, offset) if raw.checked_sub(offset as u64).is_some_and(|value| value >> n == 0) => writer.write(n, raw - offset as u64),
+            Coding::U32(d0, d1, d2, d3) if raw <= u32::MAX as u64 => return writer.write_quad_u32(raw as u32, d0, d1, d2, d3),
+            Coding::U64 => writer.write_var_u64(raw),
+            Coding::F16 if raw <= u32::MAX as u64 => return writer.write_f16(f32::from_bits(raw as u32)),
+            _ => return Err(unrepresentable(&format!("{} doesn't fit {:?}",raw,self)))
+        }
+        Ok(())
+    }
+}
+
+// Converts between a field's type and the value stored in the bitstream
+pub trait FieldValue: Sized + Debug {
+    fn from_raw(raw: u64) -> Option<Self>;
+    fn to_raw(&self) -> u64;
+}
+impl FieldValue for bool {
+    fn from_raw(raw: u64) -> Option<Self> { (raw <= 1).then_some(raw == 1) }
+    fn to_raw(&self) -> u64 { *self as u64 }
+}
+macro_rules! unsigned_field_value {
+    ($($ty:ty),*) => {$(
+        impl FieldValue for $ty {
+            fn from_raw(raw: u64) -> Option<Self> { Self::try_from(raw).ok() }
+            fn to_raw(&self) -> u64 { *self as u64 }
+        }
+    )*}
+}
+unsigned_field_value!(u8, u16, u32, u64);
+// Signed values are stored as U32s with the sign in the lowest bit
+impl FieldValue for i32 {
+    fn from_raw(raw: u64) -> Option<Self> { Some(unpack_signed(u32::try_from(raw).ok()?)) }
+    fn to_raw(&self) -> u64 { pack_signed(*self) as u64 }
+}
+impl FieldValue for f32 {
+    fn from_raw(raw: u64) -> Option<Self> { Some(f32::from_bits(u32::try_from(raw).ok()?)) }
+    fn to_raw(&self) -> u64 { self.to_bits() as u64 }
+}
+
+// Fields that hold a number of values of the same coding. An Option is present or not.
+pub trait Repeated: Sized {
+    type Item;
+    fn from_items(items: Vec<Self::Item>) -> Option<Self>;
+    fn items(&self) -> &[Self::Item];
+}
+impl<T> Repeated for Vec<T> {
+    type Item = T;
+    fn from_items(items: Vec<T>) -> Option<Self> { Some(items) }
+    fn items(&self) -> &[T] { self }
+}
+impl<T, const N: usize> Repeated for [T; N] {
+    type Item = T;
+    fn from_items(items: Vec<T>) -> Option<Self> { items.try_into().ok() }
+    fn items(&self) -> &[T] { self }
+}
+impl<T> Repeated for Option<T> {
+    type Item = T;
+    fn from_items(mut items: Vec<T>) -> Option<Self> { if items.len() > 1 { None } else { Some(items.pop()) } }
+    fn items(&self) -> &[T] { self.as_slice() }
+}
+
+// Declares a header bundle. The struct, its defaults, `read` (with trace output) and `write` all come from one list of fields:
+//
+//     name: Type = [count] Coding if condition, default value, valid check, supported check => "Feature";
+//
+// Everything but the coding and the default is optional. The coding is a `Coding` variant, `Bundle(args)` for a nested
+// bundle, or `Optional(args)` for a nested bundle behind a presence flag. `[count]` reads that many values into a Vec or
+// an array. Conditions, defaults and checks can use the context arguments and the fields before them.
+// A field whose condition is false isn't stored, and takes its default. `with all_default` starts the bundle with a
+// flag that gives every field its default.
+macro_rules! bundle {
+    ($(#[$meta:meta])* $vis:vis struct $name:ident as $bundle_name:literal ($($context:ident: $context_ty:ty),*) $(with $all_default:ident)? { $($fields:tt)* }) => {
+        $crate::bundle::bundle!(@impl $bundle_name [$($context: $context_ty),*] [$($all_default)?] $(#[$meta])* $vis struct $name { $($fields)* });
+    };
+    ($(#[$meta:meta])* $vis:vis struct $name:ident as $bundle_name:literal $(with $all_default:ident)? { $($fields:tt)* }) => {
+        $crate::bundle::bundle!(@impl $bundle_name [] [$($all_default)?] $(#[$meta])* $vis struct $name { $($fields)* });
+        impl Default for $name {
+            fn default() -> Self { Self::defaults() }
+        }
+    };
+
+    (@impl $bundle_name:literal [$($context:ident: $context_ty:ty),*] [$($all_default:ident)?] $(#[$meta:meta])* $vis:vis struct $name:ident {
+        $(
+            $field_vis:vis $field:ident: $ty:ty = $([$count:expr])? $kind:ident $(($($args:tt)*))? $(if $condition:expr)?,
+                default $default:expr $(, valid $valid:expr)? $(, supported $supported:expr => $feature:literal)?;
+        )*
+    }) => {
+        $(#[$meta])*
+        $vis struct $name {
+            $($field_vis $field: $ty),*
+        }
+        impl $name {
+            #[allow(unused_variables)]
+            pub fn defaults($($context: $context_ty),*) -> Self {
+                $(let $field: $ty = $default;)*
+                Self { $($field),* }
+            }
+            #[allow(unused_variables)]
+            pub fn read(bitstream: &mut $crate::bit_reader::BitStream, $($context: $context_ty),*) -> Result<Self,$crate::decode_error::DecodeError> {
+                bitstream.bundle($bundle_name, |bitstream| {
+                    let all_default = false $(|| bitstream.field(concat!($bundle_name, ".", stringify!($all_default)), |b| b.read_bool())?)?;
+                    $(
+                        let $field: $ty = if !all_default && $crate::bundle::bundle!(@condition $($condition)?) {
+                            $crate::bundle::bundle!(@read bitstream, concat!($bundle_name, ".", stringify!($field)), $ty, [$($count)?] $kind $(($($args)*))?)
+                        } else {
+                            $default
+                        };
+                        $(if !{ let valid: bool = $valid; valid } {
+                            return Err(bitstream.invalid_value(concat!($bundle_name, ".", stringify!($field)), None));
+                        })?
+                        $(if !($supported) {
+                            return Err(bitstream.unsupported(concat!($bundle_name, ".", stringify!($field)), $feature));
+                        })?
+                    )*
+                    Ok(Self { $($field),* })
+                })
+            }
+            // Fields whose condition is false must hold their default, as they can't be written
+            #[allow(unused_variables)]
+            pub fn write(&self, writer: &mut $crate::bit_writer::BitWriter, $($context: $context_ty),*) -> Result<(),std::io::Error> {
+                let defaults = || Self::defaults($($context),*);
+                $(
+                    let $all_default = *self == defaults();
+                    writer.write_bool($all_default);
+                    if $all_default { return Ok(()); }
+                )?
+                $(
+                    let $field: $ty = self.$field.clone();
+                    $(if !{ let valid: bool = $valid; valid } {
+                        return Err($crate::bit_writer::unrepresentable(concat!($bundle_name, ".", stringify!($field), " is out of range")));
+                    })?
+                    if $crate::bundle::bundle!(@condition $($condition)?) {
+                        $crate::bundle::bundle!(@write writer, concat!($bundle_name, ".", stringify!($field)), $ty, $field, [$($count)?] $kind $(($($args)*))?);
+                    } else if $field != { let default: $ty = $default; default } {
+                        return Err($crate::bit_writer::unrepresentable(concat!($bundle_name, ".", stringify!($field), " isn't stored here, so it must be the default")));
+                    }
+                )*
+                Ok(())
+            }
+        }
+    };
+
+    (@condition) => { true };
+    (@condition $condition:expr) => { $condition };
+
+    (@read $bitstream:ident, $field_name:expr, $ty:ty, [$count:expr] $($coding:tt)*) => {{
+        let mut items = Vec::new();
+        for _ in 0..$count {
+            items.push($crate::bundle::bundle!(@read $bitstream, $field_name, <$ty as $crate::bundle::Repeated>::Item, [] $($coding)*));
+        }
+        <$ty as $crate::bundle::Repeated>::from_items(items).ok_or_else(|| $bitstream.invalid_value($field_name, None))?
+    }};
+    (@read $bitstream:ident, $field_name:expr, $ty:ty, [] Bundle($($arg:expr),*)) => {
+        <$ty>::read($bitstream, $($arg),*)?
+    };
+    (@read $bitstream:ident, $field_name:expr, $ty:ty, [] Optional($($arg:expr),*)) => {{
+        let present = $bitstream.field($field_name, |b| b.read_bool())?;
+        let count = present as usize;
+        $crate::bundle::bundle!(@read $bitstream, $field_name, $ty, [count] Bundle($($arg),*))
+    }};
+    (@read $bitstream:ident, $field_name:expr, $ty:ty, [] $kind:ident $($args:tt)?) => {
+        $bitstream.field($field_name, |b| {
+            let raw = $crate::bundle::Coding::$kind $($args)?.read(b)?;
+            <$ty as $crate::bundle::FieldValue>::from_raw(raw).ok_or_else(|| b.invalid_value("", Some(raw)))
+        })?
+    };
+
+    (@write $writer:ident, $field_name:expr, $ty:ty, $value:expr, [$count:expr] $($coding:tt)*) => {{
+        let items = <$ty as $crate::bundle::Repeated>::items(&$value);
+        if items.len() != $count as usize {
+            return Err($crate::bit_writer::unrepresentable(concat!($field_name, " has the wrong number of values")));
+        }
+        for item in items {
+            $crate::bundle::bundle!(@write $writer, $field_name, <$ty as $crate::bundle::Repeated>::Item, *item, [] $($coding)*);
+        }
+    }};
+    (@write $writer:ident, $field_name:expr, $ty:ty, $value:expr, [] Bundle($($arg:expr),*)) => {
+        $value.write($writer, $($arg),*)?
+    };
+    (@write $writer:ident, $field_name:expr, $ty:ty, $value:expr, [] Optional($($arg:expr),*)) => {{
+        $writer.write_bool($value.is_some());
+        let count = $value.is_some() as usize;
+        $crate::bundle::bundle!(@write $writer, $field_name, $ty, $value, [count] Bundle($($arg),*))
+    }};
+    (@write $writer:ident, $field_name:expr, $ty:ty, $value:expr, [] $kind:ident $($args:tt)?) => {
+        $crate::bundle::Coding::$kind $($args)?.write($writer, <$ty as $crate::bundle::FieldValue>::to_raw(&$value))?
+    };
+}
+pub(crate) use bundle;
+
+#[cfg(test)]
+mod bundle_tests {
+    use crate::bit_reader::BitStream;
+    use crate::bit_reader::QuadDistributions::*;
+    use crate::bit_writer::BitWriter;
+    use crate::decode_error::DecodeError;
+
+    bundle! {
+        #[derive(Debug,Clone,PartialEq)]
+        struct Inner as "Inner" (scale: u32) {
+            value: u32 = U32(RawValue(0), BitCount(4), BitCount(8), BitCount(16)), default scale;
+        }
+    }
+    bundle! {
+        #[derive(Debug,Clone,PartialEq)]
+        struct Outer as "Outer" with all_default {
+            count: u8 = Bits(2), default 1;
+            values: Vec<u16> = [count] U32(RawValue(0), RawValue(1), BitCount(8), BitCount(16)), default vec![0];
+            signed: i32 = U32(BitCount(4), BitCount(8), BitCount(16), BitCount(32)) if count > 1, default 0;
+            limit: f32 = F16, default 1.0, valid limit > 0.0;
+            inner: Option<Inner> = Optional(count as u32), default None;
+            pair: [Inner; 2] = [2] Bundle(7), default [Inner { value: 7 }, Inner { value: 7 }];
+        }
+    }
+
+    fn round_trip(outer: &Outer) -> (usize, Outer) {
+        let mut writer = BitWriter::new();
+        outer.write(&mut writer).unwrap();
+        let bits = writer.bit_position() as usize;
+        let data = writer.finish();
+        (bits, Outer::read(&mut BitStream::new(&data)).unwrap())
+    }
+
+    #[test]
+    fn schema_round_trip() {
+        assert_eq!(round_trip(&Outer::default()), (1, Outer::default()));
+
+        let outer = Outer { count: 3, values: vec![1, 200, 40000], signed: -5, limit: 0.5, inner: Some(Inner { value: 300 }), pair: [Inner { value: 0 }, Inner { value: 7 }] };
+        assert_eq!(round_trip(&outer), (82, outer.clone()));
+
+        // Fields that aren't stored must hold their defaults, and checks apply to writing too
+        let mut writer = BitWriter::new();
+        assert!(Outer { count: 1, values: vec![0], signed: 2, ..outer.clone() }.write(&mut writer).is_err());
+        assert!(Outer { values: vec![1], ..outer.clone() }.write(&mut writer).is_err());
+        assert!(Outer { limit: -1.0, ..outer.clone() }.write(&mut writer).is_err());
+
+        // A limit of 0.0
+        let mut bitstream = BitStream::new(&[0b0000_0000,0,0b0000_0000]);
+        assert_eq!(Outer::read(&mut bitstream), Err(DecodeError::InvalidValue { bit_offset: 19, field: "Outer.limit", value: None }));
+    }
+
+    #[test]
+    fn schema_trace() {
+        let mut writer = BitWriter::new();
+        Outer { count: 0, values: vec![], signed: 0, limit: 1.0, inner: Some(Inner { value: 0 }), pair: Outer::default().pair }.write(&mut writer).unwrap();
+        let data = writer.finish();
+        let mut bitstream = BitStream::new(&data);
+        bitstream.enable_trace("test");
+        Outer::read(&mut bitstream).unwrap();
+        assert_eq!(bitstream.take_trace().unwrap().to_string(), concat!(
+            "test [0..34]\n",
+            "  Outer [0..34]\n",
+            "    all_default [0..1] = false\n",
+            "    count [1..3] = 0\n",
+            "    limit [3..19] = 1.0\n",
+            "    inner [19..20] = true\n",
+            "    Inner [20..22]\n",
+            "      value [20..22] = 0\n",
+            "    Inner [22..28]\n",
+            "      value [22..28] = 7\n",
+            "    Inner [28..34]\n",
+            "      value [28..34] = 7\n"
+        ));
+    }
+}
@@ -4,6 +4,24 @@ pub struct ImageSize {
     pub height: u32
 }
 
+// Odd values are negative: 0, -1, 1, -2, 2, ...
 pub fn unpack_signed(val: u32) -> i32 {
-    if val & 1 == 0 { (-(val as i64 + 1)/2) as i32 } else { (val / 2) as i32 }
-}
\ No newline at end of file
+    if val & 1 == 1 { (-(val as i64 + 1)/2) as i32 } else { (val / 2) as i32 }
+}
+
+pub fn pack_signed(val: i32) -> u32 {
+    if val < 0 { (-2 * val as i64 - 1) as u32 } else { 2 * val as u32 }
+}
+#[cfg(test)]
+mod common_tests {
+    use crate::common::{pack_signed, unpack_signed};
+
+    #[test]
+    fn signed_packing() {
+        let pairs: [(u32, i32); 6] = [(0, 0), (1, -1), (2, 1), (u32::MAX, i32::MIN), (u32::MAX - 1, i32::MAX), (5, -3)];
+        for (packed, value) in pairs {
+            assert_eq!(unpack_signed(packed), value);
+            assert_eq!(pack_signed(value), packed);
+        }
+    }
+}
@@ -0,0 +1,199 @@
+#![allow(dead_code)]
+
+use std::fmt;
+use std::io::{Read, Seek};
+
+use crate::box_reader::{JxlBoxHeader, JxlBoxReader};
+use crate::jxl_file::JxlBoxType;
+
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub enum FindingSeverity {
+    Error,      // The file is not a valid JPEG XL container
+    Warning     // The file is valid but goes against a recommendation of the spec
+}
+
+#[derive(Debug,Clone,PartialEq,Eq)]
+pub struct ContainerFinding {
+    pub severity: FindingSeverity,
+    pub offset: Option<u64>,    // Offset of the box the finding is about
+    pub message: String
+}
+impl fmt::Display for ContainerFinding {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.offset {
+            Some(offset) => write!(f,"{:?} at offset {}: {}",self.severity,offset,self.message),
+            None => write!(f,"{:?}: {}",self.severity,self.message)
+        }
+    }
+}
+
+struct Findings(Vec<ContainerFinding>);
+impl Findings {
+    fn error(&mut self, header: Option<&JxlBoxHeader>, message: String) {
+        self.0.push(ContainerFinding { severity: FindingSeverity::Error, offset: header.map(|h| h.offset), message });
+    }
+    fn warning(&mut self, header: Option<&JxlBoxHeader>, message: String) {
+        self.0.push(ContainerFinding { severity: FindingSeverity::Warning, offset: header.map(|h| h.offset), message });
+    }
+}
+
+// Checks the box structure of a file without decoding the codestream. An empty list means the container is valid.
+pub fn validate_container<R: Read + Seek>(src: R) -> Vec<ContainerFinding> {
+    let mut findings = Findings(Vec::new());
+    let mut box_reader = match JxlBoxReader::new(src) {
+        Ok(box_reader) => box_reader,
+        Err(error) => {
+            findings.error(None,format!("Could not read file: {}",error));
+            return findings.0;
+        }
+    };
+
+    let mut headers: Vec<JxlBoxHeader> = Vec::new();
+    for header in box_reader.by_ref() {
+        match header {
+            Ok(header) => headers.push(header),
+            Err(error) => findings.error(None,error.to_string())
+        }
+    }
+    if headers.is_empty() {
+        findings.error(None,"File is empty".to_owned());
+        return findings.0;
+    }
+    if headers[0].box_type == JxlBoxType::JXL_RAW {
+        return findings.0;
+    }
+
+    use JxlBoxType as E;
+    // Reads at most max_len bytes of a payload, so huge boxes are never loaded just to look at their start
+    let mut payload = |header: &JxlBoxHeader, max_len: u64| -> Option<Vec<u8>> {
+        let mut data: Vec<u8> = Vec::new();
+        box_reader.payload_reader(header).ok()?.take(max_len).read_to_end(&mut data).ok()?;
+        Some(data)
+    };
+
+    // Signature and file type
+    match headers.first() {
+        Some(h) if h.box_type == E::JXL_SIGNATURE => {
+            if payload(h,5).as_deref() != Some(b"\x0d\x0a\x87\x0a") {
+                findings.error(Some(h),"Invalid JXL signature".to_owned());
+            }
+        },
+        h => findings.error(h,"File does not start with a JXL signature box".to_owned())
+    }
+    match headers.get(1) {
+        Some(h) if h.box_type == E::JXL_FILE_TYPE => {
+            match payload(h,4096) {
+                Some(data) if data.len() >= 8 && data.len() % 4 == 0 && (data.len() as u64) == h.payload_size() => {
+                    if &data[0..4] != b"jxl " {
+                        findings.error(Some(h),format!("ftyp major brand is {:?}, expected \"jxl \"",String::from_utf8_lossy(&data[0..4])));
+                    }
+                    if !data[8..].chunks(4).any(|brand| brand == b"jxl ") {
+                        findings.error(Some(h),"ftyp compatible brands don't include \"jxl \"".to_owned());
+                    }
+                },
+                _ => findings.error(Some(h),"Invalid ftyp box".to_owned())
+            }
+        },
+        h => findings.error(h,"The signature box must be followed by an ftyp box".to_owned())
+    }
+
+    let codestream_position = headers.iter().position(|h| matches!(h.box_type, E::JXL_CODESTREAM | E::JXL_PARTIAL));
+    if codestream_position.is_none() {
+        findings.error(None,"File has no jxlc or jxlp box".to_owned());
+    }
+    let before_codestream = |index: usize| match codestream_position { Some(position) => index < position, None => true };
+
+    let mut has_codestream = false;
+    let mut has_partial = false;
+    let mut has_level = false;
+    let mut next_partial_index: u32 = 0;
+    let mut seen_last_partial = false;
+    for (index, h) in headers.iter().enumerate() {
+        match h.box_type {
+            E::JXL_SIGNATURE if index != 0 => findings.error(Some(h),"Duplicate signature box".to_owned()),
+            E::JXL_FILE_TYPE if index != 1 => findings.error(Some(h),"Duplicate ftyp box".to_owned()),
+            E::JXL_LEVEL => {
+                if has_level {
+                    findings.error(Some(h),"Duplicate jxll box".to_owned());
+                }
+                has_level = true;
+                if !before_codestream(index) {
+                    findings.error(Some(h),"jxll box must come before the codestream".to_owned());
+                } else if index != 2 {
+                    findings.warning(Some(h),"jxll box should come right after the ftyp box".to_owned());
+                }
+                match payload(h,2).as_deref() {
+                    Some([5]) | Some([10]) => (),
+                    _ => findings.error(Some(h),"jxll box must hold a level of 5 or 10".to_owned())
+                }
+            },
+            E::JXL_RECONSTRUCTION if !before_codestream(index) => {
+                findings.error(Some(h),"jbrd box must come before the codestream".to_owned());
+            },
+            E::JXL_CODESTREAM => {
+                if has_codestream {
+                    findings.error(Some(h),"Duplicate jxlc box".to_owned());
+                }
+                if has_partial {
+                    findings.error(Some(h),"jxlc and jxlp boxes cannot be mixed".to_owned());
+                }
+                has_codestream = true;
+            },
+            E::JXL_PARTIAL => {
+                if has_codestream {
+                    findings.error(Some(h),"jxlc and jxlp boxes cannot be mixed".to_owned());
+                }
+                has_partial = true;
+                match payload(h,4).as_deref().and_then(|data| data.get(0..4)).map(|index| u32::from_be_bytes(index.try_into().unwrap())) {
+                    Some(partial_index) => {
+                        let is_last = partial_index & (1<<31) != 0;
+                        let partial_index = partial_index & !(1<<31);
+                        if seen_last_partial {
+                            findings.error(Some(h),format!("jxlp box {} comes after the box marked as last",partial_index));
+                        } else if partial_index != next_partial_index {
+                            findings.error(Some(h),format!("jxlp index is {}, expected {}",partial_index,next_partial_index));
+                        }
+                        next_partial_index = partial_index.wrapping_add(1);
+                        seen_last_partial |= is_last;
+                    },
+                    None => findings.error(Some(h),"jxlp box is too short to hold an index".to_owned())
+                }
+            },
+            E::JXL_BROTLI => {
+                match payload(h,4).as_deref().and_then(|data| data.get(0..4)).map(|t| JxlBoxType::from_fourcc(t.try_into().unwrap())) {
+                    Some(E::JXL_SIGNATURE | E::JXL_FILE_TYPE | E::JXL_LEVEL | E::JXL_BROTLI | E::JXL_INDEX | E::JXL_CODESTREAM | E::JXL_PARTIAL | E::JXL_RECONSTRUCTION) => {
+                        findings.error(Some(h),"brob box wraps a box type that can't be compressed".to_owned());
+                    },
+                    Some(_) => (),
+                    None => findings.error(Some(h),"brob box is too short to hold a box type".to_owned())
+                }
+            },
+            E::JXL_EXIF if h.payload_size() < 4 => findings.error(Some(h),"Exif box is too short to hold the TIFF header offset".to_owned()),
+            _ => ()
+        }
+    }
+    if has_partial && !seen_last_partial {
+        findings.error(None,"No jxlp box is marked as last".to_owned());
+    }
+    findings.0
+}
+
+#[cfg(test)]
+mod container_validation_tests {
+    use std::io::Cursor;
+    use crate::box_writer;
+    use crate::container_validation::validate_container;
+
+    #[test]
+    fn report_misplaced_boxes() {
+        let mut file = box_writer::JXL_SIGNATURE_BOX.to_vec();
+        file.extend_from_slice(box_writer::JXL_FILE_TYPE_BOX);
+        assert_eq!(validate_container(Cursor::new(file.clone())).len(), 1);
+
+        box_writer::write_box(&mut file,b"jxlp",&[b"\0\0\0\0\xff\x0a"]).unwrap();
+        box_writer::write_box(&mut file,b"jbrd",&[b""]).unwrap();
+        box_writer::write_box(&mut file,b"jxlp",&[b"\x80\0\0\x02"]).unwrap();
+        let messages: Vec<String> = validate_container(Cursor::new(file)).iter().map(|f| f.message.clone()).collect();
+        assert_eq!(messages, vec!["jbrd box must come before the codestream", "jxlp index is 2, expected 1"]);
+    }
+}
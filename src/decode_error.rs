@@ -0,0 +1,95 @@
+#![allow(dead_code)]
+
+use std::fmt;
+use std::io::Error as IoError;
+
+use crate::jxl_level::JxlLevelViolation;
+
+// Every error records the bit offset in the codestream where it was found, and the header field being read.
+// Errors made by BitStream have an empty field name, which the header readers fill in with `field`.
+// Container errors come before there is a codestream, so they are at bit 0 and only carry a message.
+#[derive(Debug,Clone,PartialEq)]
+pub enum DecodeError {
+    Truncated { bit_offset: u64, field: &'static str },                                 // The data ended in the middle of the field
+    InvalidValue { bit_offset: u64, field: &'static str, value: Option<u64> },          // The field holds something the spec doesn't allow
+    Unsupported { bit_offset: u64, field: &'static str, feature: &'static str },        // The field selects a feature this decoder can't handle yet
+    LimitViolation { bit_offset: u64, violations: Vec<JxlLevelViolation> },             // The header goes over the limits of the file's level
+    Container { message: String }                                                       // The boxes around the codestream are invalid
+}
+impl DecodeError {
+    pub fn bit_offset(&self) -> u64 {
+        match self {
+            DecodeError::Truncated { bit_offset, .. } => *bit_offset,
+            DecodeError::InvalidValue { bit_offset, .. } => *bit_offset,
+            DecodeError::Unsupported { bit_offset, .. } => *bit_offset,
+            DecodeError::LimitViolation { bit_offset, .. } => *bit_offset,
+            DecodeError::Container { .. } => 0
+        }
+    }
+    pub fn field(&self) -> &'static str {
+        match self {
+            DecodeError::Truncated { field, .. } => field,
+            DecodeError::InvalidValue { field, .. } => field,
+            DecodeError::Unsupported { field, .. } => field,
+            DecodeError::LimitViolation { violations, .. } => violations.first().map_or("", |violation| violation.field),
+            DecodeError::Container { .. } => "container"
+        }
+    }
+    // Names the field the error happened in, unless a more specific name was already given
+    pub fn with_field(mut self, name: &'static str) -> Self {
+        match &mut self {
+            DecodeError::Truncated { field, .. } | DecodeError::InvalidValue { field, .. } | DecodeError::Unsupported { field, .. } => {
+                if field.is_empty() { *field = name; }
+            },
+            DecodeError::LimitViolation { .. } | DecodeError::Container { .. } => ()
+        }
+        self
+    }
+}
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let field = if self.field().is_empty() { "<unnamed field>" } else { self.field() };
+        match self {
+            DecodeError::Truncated { bit_offset, .. } => write!(f,"Data ends in {} at bit {}",field,bit_offset),
+            DecodeError::InvalidValue { bit_offset, value: Some(value), .. } => write!(f,"Invalid value {} for {} at bit {}",value,field,bit_offset),
+            DecodeError::InvalidValue { bit_offset, value: None, .. } => write!(f,"Invalid {} at bit {}",field,bit_offset),
+            DecodeError::Unsupported { bit_offset, feature, .. } => write!(f,"{} ({} at bit {}) is not supported",feature,field,bit_offset),
+            DecodeError::LimitViolation { bit_offset, violations } => {
+                let violations: Vec<String> = violations.iter().map(|violation| violation.to_string()).collect();
+                write!(f,"{} (at bit {})",violations.join("; "),bit_offset)
+            },
+            DecodeError::Container { message } => write!(f,"Invalid container: {}",message)
+        }
+    }
+}
+impl std::error::Error for DecodeError {}
+impl From<IoError> for DecodeError {
+    fn from(error: IoError) -> Self {
+        DecodeError::Container { message: error.to_string() }
+    }
+}
+
+pub trait DecodeResultExt {
+    fn field(self, name: &'static str) -> Self;
+}
+impl<T> DecodeResultExt for Result<T,DecodeError> {
+    fn field(self, name: &'static str) -> Self {
+        self.map_err(|error| error.with_field(name))
+    }
+}
+
+#[cfg(test)]
+mod decode_error_tests {
+    use crate::bit_reader::BitStream;
+    use crate::common::ImageSize;
+    use crate::decode_error::{DecodeError, DecodeResultExt};
+
+    #[test]
+    fn errors_name_the_innermost_field() {
+        // div8 is set and the height fits, but the data ends in the middle of the ratio
+        let mut bitstream = BitStream::new(&[0b0000_0001]);
+        let error = ImageSize::read(&mut bitstream).field("ImageMetadata").unwrap_err();
+        assert_eq!(error, DecodeError::Truncated { bit_offset: 6, field: "ImageSize.ratio" });
+        assert_eq!(error.to_string(), "Data ends in ImageSize.ratio at bit 6");
+    }
+}
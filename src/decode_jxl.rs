@@ -1,24 +1,169 @@
+#![allow(dead_code)]
+
 use crate::bit_reader::BitStream;
 use crate::jxl_image::JxlImageMetadata;
 use crate::jxl_file::JxlFile;
 use crate::jxl_frame::JxlFrame;
 use crate::common::ImageSize;
+use crate::jxl_level::{self, JxlLevelViolation};
+use crate::decode_error::{DecodeError, DecodeResultExt};
+use crate::bit_trace::TraceNode;
 
-pub fn decode_jxl(input_file: JxlFile) {
-    let mut jxl_data = BitStream::new(&input_file.get_image_data());
-    assert_eq!(jxl_data.read_u16(16).unwrap(),0x0aff,"Invalid JXL");
-    let image_size = ImageSize::read(&mut jxl_data).expect("Not enough data to read image size!");
-    let image_metadata = JxlImageMetadata::read(&mut jxl_data).expect("Not enough data to read image size!");
+pub fn decode_jxl(input_file: JxlFile) -> Result<(),DecodeError> {
+    let level = input_file.get_level()?;
+    let image_data = input_file.get_image_data()?;
+    let mut jxl_data = BitStream::new(&image_data);
+    let signature = jxl_data.read_u16(16).field("signature")?;
+    if signature != 0x0aff {
+        return Err(DecodeError::InvalidValue { bit_offset: 0, field: "signature", value: Some(signature as u64) });
+    }
+    let image_size = ImageSize::read(&mut jxl_data)?;
+    let image_metadata = JxlImageMetadata::read(&mut jxl_data)?;
     println!("Image dimensions: {:?}",image_size);
     println!("Image metadata: {:?}",image_metadata);
-    let _preview_frame = if image_metadata.preview_size != None {
-        Some(JxlFrame::read(&mut jxl_data,&image_metadata).unwrap())
+    let mut violations = jxl_level::check_image_size(level,&image_size);
+    violations.extend(jxl_level::check_image_metadata(level,&image_metadata));
+    check_level(violations,16)?;
+    let _preview_frame = if image_metadata.preview_size.is_some() {
+        Some(JxlFrame::read(&mut jxl_data,&image_metadata)?)
     } else { None };
     let mut frames: Vec<JxlFrame> = Vec::new();
+    #[allow(clippy::never_loop)]
     loop {
-        let next_frame = JxlFrame::read(&mut jxl_data,&image_metadata).unwrap();
+        let frame_position = jxl_data.bit_position();
+        let next_frame = JxlFrame::read(&mut jxl_data,&image_metadata)?;
+        check_level(jxl_level::check_frame_header(level,&image_size,&next_frame.header),frame_position)?;
         frames.push(next_frame);
         break;
     }
     println!("{:?}",frames);
-}
\ No newline at end of file
+    Ok(())
+}
+
+// Reads the image headers and the first frame header, recording every field. The trace covers everything
+// up to the point of the error, if there is one.
+pub fn trace_headers(input_file: &JxlFile) -> (TraceNode,Result<(),DecodeError>) {
+    let image_data = match input_file.get_image_data() {
+        Ok(image_data) => image_data,
+        Err(error) => return (TraceNode { name: "Codestream", start: 0, end: 0, value: None, children: Vec::new() },Err(error.into()))
+    };
+    let mut jxl_data = BitStream::new(&image_data);
+    jxl_data.enable_trace("Codestream");
+    let result = (|| {
+        let signature = jxl_data.field("signature", |b| b.read_u16(16))?;
+        if signature != 0x0aff {
+            return Err(DecodeError::InvalidValue { bit_offset: 0, field: "signature", value: Some(signature as u64) });
+        }
+        ImageSize::read(&mut jxl_data)?;
+        let image_metadata = JxlImageMetadata::read(&mut jxl_data)?;
+        if image_metadata.preview_size.is_some() {
+            JxlFrame::read(&mut jxl_data,&image_metadata)?;
+        }
+        JxlFrame::read(&mut jxl_data,&image_metadata)?;
+        Ok(())
+    })();
+    (jxl_data.take_trace().unwrap(),result)
+}
+
+// Turns the level violations of the headers starting at bit_offset into one error listing all of them
+fn check_level(violations: Vec<JxlLevelViolation>, bit_offset: u64) -> Result<(),DecodeError> {
+    if violations.is_empty() { return Ok(()); }
+    Err(DecodeError::LimitViolation { bit_offset, violations })
+}
+
+// Uses the frame index to read the keyframe closest to the given time without going through the frames before it.
+// Returns None if the file has no frame index.
+pub fn read_keyframe_at(input_file: &JxlFile, seconds: f64) -> Result<Option<JxlFrame>,DecodeError> {
+    let Some(frame_index) = input_file.get_frame_index() else { return Ok(None); };
+    let Some(entry) = frame_index.nearest_keyframe(seconds) else { return Ok(None); };
+    let image_data = input_file.get_image_data()?;
+    let mut jxl_data = BitStream::new(&image_data);
+    let signature = jxl_data.read_u16(16).field("signature")?;
+    if signature != 0x0aff {
+        return Err(DecodeError::InvalidValue { bit_offset: 0, field: "signature", value: Some(signature as u64) });
+    }
+    let _image_size = ImageSize::read(&mut jxl_data)?;
+    let image_metadata = JxlImageMetadata::read(&mut jxl_data)?;
+    // An offset that doesn't fit in a usize is past the end of the codestream too
+    let frame_data = usize::try_from(entry.codestream_offset).ok().and_then(|offset| image_data.get(offset..)).filter(|data| !data.is_empty())
+        .ok_or(DecodeError::Truncated { bit_offset: image_data.len() as u64 * 8, field: "FrameIndex.offset" })?;
+    JxlFrame::read(&mut BitStream::new(frame_data),&image_metadata).map(Some)
+}
+
+#[cfg(test)]
+mod decode_jxl_tests {
+    use std::io::Cursor;
+    use crate::bit_writer::BitWriter;
+    use crate::box_writer;
+    use crate::decode_error::DecodeError;
+    use crate::decode_jxl::*;
+
+    fn container(boxes: &[(&[u8;4],&[u8])]) -> JxlFile {
+        let mut data: Vec<u8> = Vec::new();
+        data.extend_from_slice(box_writer::JXL_SIGNATURE_BOX);
+        data.extend_from_slice(box_writer::JXL_FILE_TYPE_BOX);
+        for (box_type,payload) in boxes {
+            box_writer::write_box(&mut data,box_type,&[payload]).unwrap();
+        }
+        JxlFile::read(Cursor::new(data)).unwrap()
+    }
+
+    #[test]
+    fn container_errors() {
+        let missing_part = container(&[(b"jxlp",b"\0\0\0\0\xff\x0a"),(b"jxlp",b"\x80\0\0\x02\0")]);
+        let error = DecodeError::Container { message: "missing jxlp index 1".to_string() };
+        assert_eq!(decode_jxl(missing_part).unwrap_err(),error);
+        let missing_part = container(&[(b"jxlp",b"\0\0\0\0\xff\x0a"),(b"jxlp",b"\x80\0\0\x02\0")]);
+        assert_eq!(trace_headers(&missing_part).1,Err(error));
+
+        let bad_level = container(&[(b"jxll",b"\x05\x05"),(b"jxlc",b"\xff\x0a")]);
+        assert_eq!(decode_jxl(bad_level).unwrap_err().to_string(),"Invalid container: jxll box must contain a single byte");
+    }
+
+    // A modular frame header that only sets upsampling, padded to a byte boundary
+    fn write_frame_header(writer: &mut BitWriter, upsampling: u64, is_last: bool) {
+        writer.write_bool(false);
+        // Regular frame, modular, no flags
+        writer.write(2, 0);
+        writer.write(1, 1);
+        writer.write(2, 0);
+        writer.write(2, [1, 2, 4, 8].iter().position(|&value| value == upsampling).unwrap() as u64);
+        // Group size 256, one pass, no crop, replace blending
+        writer.write(2, 1);
+        writer.write(2, 0);
+        writer.write_bool(false);
+        writer.write(2, 0);
+        writer.write_bool(is_last);
+        writer.align_to_byte();
+    }
+
+    #[test]
+    fn keyframe_seek() {
+        let mut writer = BitWriter::new();
+        writer.write(16, 0x0aff);
+        ImageSize { width: 64, height: 64 }.write(&mut writer).unwrap();
+        JxlImageMetadata::default().write(&mut writer).unwrap();
+        writer.align_to_byte();
+        let mut offsets: Vec<u8> = Vec::new();
+        for (upsampling, is_last) in [(1, false), (2, false), (4, true)] {
+            offsets.push((writer.bit_position() / 8) as u8);
+            write_frame_header(&mut writer, upsampling, is_last);
+        }
+        let codestream = writer.finish();
+
+        // Ticks of 1/10 second. The frames start at 0, 0.5 and 1.5 seconds.
+        let mut index = vec![3, 0, 0, 0, 1, 0, 0, 0, 10];
+        index.extend_from_slice(&[offsets[0], 5, 1, offsets[1] - offsets[0], 10, 1, offsets[2] - offsets[1], 5, 1]);
+        let jxl_file = container(&[(b"jxli",&index),(b"jxlc",&codestream)]);
+        for (seconds, upsampling) in [(0.1, 1), (0.6, 2), (1.4, 4), (100.0, 4)] {
+            let frame = read_keyframe_at(&jxl_file, seconds).unwrap().unwrap();
+            assert_eq!(frame.header.upsampling, upsampling);
+        }
+
+        // An offset past the end of the codestream
+        index[15] = 100;
+        let jxl_file = container(&[(b"jxli",&index),(b"jxlc",&codestream)]);
+        assert!(matches!(read_keyframe_at(&jxl_file, 1.4), Err(DecodeError::Truncated { field: "FrameIndex.offset", .. })));
+        assert!(matches!(read_keyframe_at(&container(&[(b"jxlc",&codestream)]), 1.4), Ok(None)));
+    }
+}
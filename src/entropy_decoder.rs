@@ -0,0 +1,592 @@
+#![allow(dead_code)]
+
+use crate::ans::{AnsDecoder, AnsDistribution};
+use crate::bit_reader::BitStream;
+use crate::bit_reader::QuadDistributions::*;
+use crate::bundle::bundle;
+use crate::decode_error::{DecodeError, DecodeResultExt};
+use crate::prefix_code::{PrefixCode, PREFIX_MAX_BITS};
+
+const LZ77_WINDOW_SIZE: usize = 1 << 20;
+// Distances below this pick one of the offsets below, relative to the row width given as the distance multiplier
+const SPECIAL_DISTANCE_COUNT: u32 = 120;
+const SPECIAL_DISTANCES: [(i8, i8); SPECIAL_DISTANCE_COUNT as usize] = [
+    (0, 1), (1, 0), (1, 1), (-1, 1), (0, 2), (2, 0), (1, 2), (-1, 2), (2, 1), (-2, 1), (2, 2), (-2, 2),
+    (0, 3), (3, 0), (1, 3), (-1, 3), (3, 1), (-3, 1), (2, 3), (-2, 3), (3, 2), (-3, 2), (0, 4), (4, 0),
+    (1, 4), (-1, 4), (4, 1), (-4, 1), (3, 3), (-3, 3), (2, 4), (-2, 4), (4, 2), (-4, 2), (0, 5), (3, 4),
+    (-3, 4), (4, 3), (-4, 3), (5, 0), (1, 5), (-1, 5), (5, 1), (-5, 1), (2, 5), (-2, 5), (5, 2), (-5, 2),
+    (4, 4), (-4, 4), (3, 5), (-3, 5), (5, 3), (-5, 3), (0, 6), (6, 0), (1, 6), (-1, 6), (6, 1), (-6, 1),
+    (2, 6), (-2, 6), (6, 2), (-6, 2), (4, 5), (-4, 5), (5, 4), (-5, 4), (3, 6), (-3, 6), (6, 3), (-6, 3),
+    (0, 7), (7, 0), (1, 7), (-1, 7), (5, 5), (-5, 5), (7, 1), (-7, 1), (4, 6), (-4, 6), (6, 4), (-6, 4),
+    (2, 7), (-2, 7), (7, 2), (-7, 2), (3, 7), (-3, 7), (7, 3), (-7, 3), (5, 6), (-5, 6), (6, 5), (-6, 5),
+    (8, 0), (4, 7), (-4, 7), (7, 4), (-7, 4), (8, 1), (8, 2), (6, 6), (-6, 6), (8, 3), (5, 7), (-5, 7),
+    (7, 5), (-7, 5), (8, 4), (6, 7), (-6, 7), (7, 6), (-7, 6), (8, 5), (7, 7), (-7, 7), (8, 6), (8, 7)
+];
+
+// Number of bits needed to hold `value`
+fn bit_width(value: u8) -> u8 {
+    (u8::BITS - value.leading_zeros()) as u8
+}
+
+// Tokens below 1 << split_exponent are the value itself. Above that, the token holds the number of extra bits to read,
+// along with the top msb_in_token bits below the leading one and the bottom lsb_in_token bits of the value.
+bundle! {
+    #[derive(Debug,Clone,Copy,PartialEq)]
+    pub struct HybridUintConfig as "HybridUintConfig" (log_alpha_size: u8) {
+        pub split_exponent: u8 = Bits(bit_width(log_alpha_size)), default log_alpha_size, valid split_exponent <= log_alpha_size;
+        pub msb_in_token: u8 = Bits(bit_width(split_exponent)) if split_exponent != log_alpha_size, default 0,
+            valid msb_in_token <= split_exponent;
+        pub lsb_in_token: u8 = Bits(bit_width(split_exponent - msb_in_token)) if split_exponent != log_alpha_size, default 0,
+            valid msb_in_token + lsb_in_token <= split_exponent;
+    }
+}
+
+impl HybridUintConfig {
+    pub fn read_uint(&self, bitstream: &mut BitStream, token: u32) -> Result<u32,DecodeError> {
+        let split_token = 1u32 << self.split_exponent;
+        if token < split_token {
+            return Ok(token);
+        }
+        let in_token = self.msb_in_token + self.lsb_in_token;
+        let bit_count = (self.split_exponent - in_token) as u32 + ((token - split_token) >> in_token);
+        if bit_count > 32 {
+            return Err(bitstream.invalid_value("HybridUint.token",Some(token as u64)));
+        }
+        let low = token & ((1 << self.lsb_in_token) - 1);
+        let high = ((token >> self.lsb_in_token) & ((1 << self.msb_in_token) - 1)) | (1 << self.msb_in_token);
+        let extra = bitstream.read(bit_count as u8).field("HybridUint.bits")?;
+        let value = ((((high as u64) << bit_count) | extra) << self.lsb_in_token) | low as u64;
+        u32::try_from(value).map_err(|_| bitstream.invalid_value("HybridUint.bits",Some(value)))
+    }
+}
+
+// Tokens from min_symbol up start a copy from earlier in the stream. The length is in the token, the distance is read
+// with its own context after it.
+bundle! {
+    #[derive(Debug,Clone,PartialEq)]
+    pub struct Lz77Params as "LZ77Params" {
+        pub enabled: bool = Bool, default false;
+        pub min_symbol: u32 = U32(RawValue(224), RawValue(512), RawValue(4096), BitCountWithOffset(15, 8)) if enabled, default 224;
+        pub min_length: u32 = U32(RawValue(3), RawValue(4), BitCountWithOffset(2, 5), BitCountWithOffset(8, 9)) if enabled, default 3;
+        pub length_config: HybridUintConfig = Bundle(8) if enabled, default HybridUintConfig::defaults(8);
+    }
+}
+
+// The code for each cluster of contexts. A stream uses either ANS or prefix codes for all of them.
+#[derive(Debug,Clone,PartialEq)]
+pub enum ClusterCodes {
+    Ans(Vec<AnsDistribution>),
+    Prefix(Vec<PrefixCode>)
+}
+
+impl ClusterCodes {
+    pub fn read_ans(bitstream: &mut BitStream, cluster_count: usize, log_alpha_size: u8) -> Result<Self,DecodeError> {
+        let distributions = (0..cluster_count).map(|_| AnsDistribution::read(bitstream, log_alpha_size)).collect::<Result<_,_>>()?;
+        Ok(Self::Ans(distributions))
+    }
+    // All the alphabet sizes come first, then the codes
+    pub fn read_prefix(bitstream: &mut BitStream, cluster_count: usize) -> Result<Self,DecodeError> {
+        let mut alphabet_sizes = Vec::with_capacity(cluster_count);
+        for _ in 0..cluster_count {
+            let alphabet_size = if bitstream.read_bool().field("ClusterCodes.alphabet_size")? {
+                let bits = bitstream.read_u8(4).field("ClusterCodes.alphabet_size")?;
+                1 + (1 << bits) + bitstream.read_u32(bits).field("ClusterCodes.alphabet_size")? as usize
+            } else { 1 };
+            alphabet_sizes.push(alphabet_size);
+        }
+        let codes = alphabet_sizes.into_iter().map(|alphabet_size| PrefixCode::read(bitstream, alphabet_size)).collect::<Result<_,_>>()?;
+        Ok(Self::Prefix(codes))
+    }
+    pub fn cluster_count(&self) -> usize {
+        match self {
+            Self::Ans(distributions) => distributions.len(),
+            Self::Prefix(codes) => codes.len()
+        }
+    }
+}
+
+// Everything needed to decode a stream with `context_count` contexts: the codes and configs for each cluster of
+// contexts, and which cluster each context uses. With LZ77 there is one more context, for the distances.
+#[derive(Debug,Clone,PartialEq)]
+pub struct EntropyCode {
+    pub lz77: Lz77Params,
+    pub context_map: Vec<u8>,
+    pub configs: Vec<HybridUintConfig>,
+    pub codes: ClusterCodes
+}
+
+impl EntropyCode {
+    pub fn read(bitstream: &mut BitStream, context_count: usize) -> Result<Self,DecodeError> {
+        Self::read_with_lz77(bitstream, context_count, true)
+    }
+    // With LZ77 the code of a context map needs a context map of its own, for two contexts. Maps of up to two
+    // contexts can't use LZ77, which keeps that nesting to one level.
+    fn read_with_lz77(bitstream: &mut BitStream, context_count: usize, allow_lz77: bool) -> Result<Self,DecodeError> {
+        bitstream.bundle("EntropyCode", |bitstream| {
+            let lz77 = Lz77Params::read(bitstream)?;
+            if lz77.enabled && !allow_lz77 {
+                return Err(bitstream.invalid_value("LZ77Params.enabled",Some(1)));
+            }
+            let context_count = context_count + lz77.enabled as usize;
+            let context_map = if context_count == 1 { vec![0] } else { read_context_map(bitstream, context_count)? };
+            let cluster_count = context_map.iter().max().map_or(0, |&max| max as usize + 1);
+            let use_prefix_code = bitstream.field("EntropyCode.use_prefix_code", |b| b.read_bool())?;
+            let log_alpha_size = if use_prefix_code { PREFIX_MAX_BITS } else { bitstream.field("EntropyCode.log_alpha_size", |b| Ok(5 + b.read_u8(2)?))? };
+            let configs = (0..cluster_count).map(|_| HybridUintConfig::read(bitstream, log_alpha_size)).collect::<Result<_,_>>()?;
+            let codes = if use_prefix_code {
+                ClusterCodes::read_prefix(bitstream, cluster_count)?
+            } else {
+                ClusterCodes::read_ans(bitstream, cluster_count, log_alpha_size)?
+            };
+            Ok(Self { lz77, context_map, configs, codes })
+        })
+    }
+}
+
+// Maps each context to a cluster. Small maps are stored as fixed width entries, others are entropy coded, optionally
+// as move-to-front indices. Every cluster up to the largest must be used.
+fn read_context_map(bitstream: &mut BitStream, context_count: usize) -> Result<Vec<u8>,DecodeError> {
+    bitstream.bundle("ContextMap", |bitstream| {
+        let context_map = if bitstream.field("ContextMap.simple", |b| b.read_bool())? {
+            let bits = bitstream.field("ContextMap.bits", |b| b.read_u8(2))?;
+            (0..context_count).map(|_| bitstream.read_u8(bits).field("ContextMap.cluster")).collect::<Result<Vec<_>,_>>()?
+        } else {
+            let use_mtf = bitstream.field("ContextMap.use_mtf", |b| b.read_bool())?;
+            let code = EntropyCode::read_with_lz77(bitstream, 1, context_count > 2)?;
+            let mut decoder = EntropyDecoder::new(&code, bitstream, 0)?;
+            let mut context_map = Vec::with_capacity(context_count);
+            for _ in 0..context_count {
+                let cluster = decoder.read_uint(bitstream, 0)?;
+                context_map.push(u8::try_from(cluster).map_err(|_| bitstream.invalid_value("ContextMap.cluster",Some(cluster as u64)))?);
+            }
+            decoder.check_final_state(bitstream)?;
+            if use_mtf {
+                inverse_move_to_front(&mut context_map);
+            }
+            context_map
+        };
+        let cluster_count = context_map.iter().max().map_or(0, |&max| max as usize + 1);
+        let mut used = vec![false; cluster_count];
+        for &cluster in &context_map {
+            used[cluster as usize] = true;
+        }
+        if let Some(unused) = used.iter().position(|&used| !used) {
+            return Err(bitstream.invalid_value("ContextMap.cluster",Some(unused as u64)));
+        }
+        Ok(context_map)
+    })
+}
+
+// Each value is the position of the cluster in a list of the most recently used ones
+fn inverse_move_to_front(values: &mut [u8]) {
+    let mut recent: [u8; 256] = std::array::from_fn(|i| i as u8);
+    for value in values {
+        let index = *value as usize;
+        let cluster = recent[index];
+        recent.copy_within(0..index, 1);
+        recent[0] = cluster;
+        *value = cluster;
+    }
+}
+
+// Reads integers from one entropy coded stream. Values are kept in a window while LZ77 is enabled, for copies to
+// read from. `dist_multiplier` is the row width that special distances are relative to, or 0 if they aren't used.
+#[derive(Debug)]
+pub struct EntropyDecoder<'a> {
+    code: &'a EntropyCode,
+    symbols: SymbolReader<'a>,
+    dist_multiplier: u32,
+    window: Vec<u32>,
+    decoded: usize,
+    copy_position: usize,
+    copy_remaining: u32
+}
+
+impl<'a> EntropyDecoder<'a> {
+    pub fn new(code: &'a EntropyCode, bitstream: &mut BitStream, dist_multiplier: u32) -> Result<Self,DecodeError> {
+        Ok(Self {
+            code,
+            symbols: SymbolReader::new(&code.codes, bitstream)?,
+            dist_multiplier,
+            window: Vec::new(),
+            decoded: 0,
+            copy_position: 0,
+            copy_remaining: 0
+        })
+    }
+}
+
+impl EntropyDecoder<'_> {
+    pub fn read_uint(&mut self, bitstream: &mut BitStream, context: usize) -> Result<u32,DecodeError> {
+        if self.copy_remaining > 0 {
+            return Ok(self.copy_next());
+        }
+        let cluster = self.code.context_map[context] as usize;
+        let token = self.symbols.read_symbol(bitstream, cluster)?;
+        let lz77 = &self.code.lz77;
+        if !lz77.enabled {
+            return self.code.configs[cluster].read_uint(bitstream, token);
+        }
+        if self.window.is_empty() {
+            self.window = vec![0; LZ77_WINDOW_SIZE];
+        }
+        if token < lz77.min_symbol {
+            let value = self.code.configs[cluster].read_uint(bitstream, token)?;
+            self.push(value);
+            return Ok(value);
+        }
+
+        let length = lz77.length_config.read_uint(bitstream, token - lz77.min_symbol)?;
+        self.copy_remaining = length.checked_add(lz77.min_length).ok_or_else(|| bitstream.invalid_value("LZ77.length",Some(length as u64)))?;
+        let distance_cluster = *self.code.context_map.last().unwrap() as usize;
+        let token = self.symbols.read_symbol(bitstream, distance_cluster)?;
+        let distance = self.code.configs[distance_cluster].read_uint(bitstream, token)?;
+        let distance = if self.dist_multiplier == 0 {
+            distance as u64 + 1
+        } else if distance >= SPECIAL_DISTANCE_COUNT {
+            (distance - SPECIAL_DISTANCE_COUNT + 1) as u64
+        } else {
+            let (dx, dy) = SPECIAL_DISTANCES[distance as usize];
+            (dx as i64 + self.dist_multiplier as i64 * dy as i64).max(1) as u64
+        };
+        // Copies from before the start of the stream read zeros
+        let distance = distance.min(self.decoded as u64).min(LZ77_WINDOW_SIZE as u64) as usize;
+        self.copy_position = self.decoded - distance;
+        Ok(self.copy_next())
+    }
+    fn copy_next(&mut self) -> u32 {
+        let value = self.window[self.copy_position % LZ77_WINDOW_SIZE];
+        self.copy_position += 1;
+        self.copy_remaining -= 1;
+        self.push(value);
+        value
+    }
+    fn push(&mut self, value: u32) {
+        self.window[self.decoded % LZ77_WINDOW_SIZE] = value;
+        self.decoded += 1;
+    }
+    // Copies must be finished, and an ANS state must end where the encoder started
+    pub fn check_final_state(&self, bitstream: &BitStream) -> Result<(),DecodeError> {
+        if self.copy_remaining != 0 {
+            return Err(bitstream.invalid_value("LZ77.length",Some(self.copy_remaining as u64)));
+        }
+        self.symbols.check_final_state(bitstream)
+    }
+}
+
+// Reads symbols from one entropy coded stream. For ANS this holds the state, which is read when the stream starts.
+#[derive(Debug)]
+pub struct SymbolReader<'a> {
+    codes: &'a ClusterCodes,
+    ans: Option<AnsDecoder>
+}
+
+impl<'a> SymbolReader<'a> {
+    pub fn new(codes: &'a ClusterCodes, bitstream: &mut BitStream) -> Result<Self,DecodeError> {
+        let ans = match codes {
+            ClusterCodes::Ans(_) => Some(AnsDecoder::new(bitstream)?),
+            ClusterCodes::Prefix(_) => None
+        };
+        Ok(Self { codes, ans })
+    }
+}
+
+impl SymbolReader<'_> {
+    pub fn read_symbol(&mut self, bitstream: &mut BitStream, cluster: usize) -> Result<u32,DecodeError> {
+        match (self.codes, &mut self.ans) {
+            (ClusterCodes::Ans(distributions), Some(ans)) => ans.read_symbol(bitstream, &distributions[cluster]),
+            (ClusterCodes::Prefix(codes), _) => codes[cluster].read_symbol(bitstream).field("PrefixCode.symbol"),
+            (ClusterCodes::Ans(_), None) => unreachable!()
+        }
+    }
+    // Prefix codes have no state to check
+    pub fn check_final_state(&self, bitstream: &BitStream) -> Result<(),DecodeError> {
+        match &self.ans {
+            Some(ans) => ans.check_final_state(bitstream),
+            None => Ok(())
+        }
+    }
+}
+
+#[cfg(test)]
+mod entropy_decoder_tests {
+    use crate::bit_reader::BitStream;
+    use crate::bit_writer::BitWriter;
+    use crate::entropy_decoder::*;
+    use crate::prefix_code::canonical_codes;
+
+    #[test]
+    fn prefix_clusters() {
+        let mut writer = BitWriter::new();
+        // Cluster 0 has only symbol 0. Cluster 1 has an alphabet of 1 + 4 + 1 = 6, with a simple code for 2 and 5.
+        writer.write_bool(false);
+        writer.write_bool(true);
+        writer.write(4, 2);
+        writer.write(2, 1);
+        writer.write(2, 1);
+        writer.write(2, 1);
+        writer.write(3, 5);
+        writer.write(3, 2);
+        // 5, 0, 2: cluster 0 reads no bits, and of the two 1-bit codes 2 gets 0 as the lower symbol
+        writer.write(1, 1);
+        writer.write(1, 0);
+        let data = writer.finish();
+
+        let mut bitstream = BitStream::new(&data);
+        let codes = ClusterCodes::read_prefix(&mut bitstream, 2).unwrap();
+        assert_eq!(codes.cluster_count(), 2);
+        let mut reader = SymbolReader::new(&codes, &mut bitstream).unwrap();
+        assert_eq!(reader.read_symbol(&mut bitstream, 1), Ok(5));
+        assert_eq!(reader.read_symbol(&mut bitstream, 0), Ok(0));
+        assert_eq!(reader.read_symbol(&mut bitstream, 1), Ok(2));
+        assert_eq!(reader.check_final_state(&bitstream), Ok(()));
+        assert_eq!(bitstream.bit_position(), 20);
+    }
+
+    // Cluster 0 has literals 5, 7 and 20 and the LZ77 length tokens 224 and 225, cluster 1 has distance tokens 0 and 1
+    fn lz77_code() -> (EntropyCode, [Vec<u8>; 2]) {
+        let mut literal_lengths = vec![0; 226];
+        for (symbol, length) in [(5, 2), (7, 2), (20, 2), (224, 3), (225, 3)] {
+            literal_lengths[symbol] = length;
+        }
+        let distance_lengths = vec![1, 1];
+        let code = EntropyCode {
+            lz77: Lz77Params { enabled: true, min_symbol: 224, min_length: 3, length_config: HybridUintConfig::defaults(8) },
+            context_map: vec![0, 1],
+            configs: vec![
+                HybridUintConfig { split_exponent: 4, msb_in_token: 1, lsb_in_token: 1 },
+                HybridUintConfig { split_exponent: 4, msb_in_token: 0, lsb_in_token: 0 }
+            ],
+            codes: ClusterCodes::Prefix(vec![PrefixCode::from_lengths(&literal_lengths), PrefixCode::from_lengths(&distance_lengths)])
+        };
+        (code, [literal_lengths, distance_lengths])
+    }
+
+    fn write_tokens(lengths: &[Vec<u8>; 2], tokens: &[(usize, u32)]) -> Vec<u8> {
+        let codes = [canonical_codes(&lengths[0]), canonical_codes(&lengths[1])];
+        let mut writer = BitWriter::new();
+        for &(cluster, token) in tokens {
+            // Cluster 2 stands for the 3 extra bits after literal token 20
+            if cluster == 2 {
+                writer.write(3, token as u64);
+                continue;
+            }
+            writer.write(lengths[cluster][token as usize], codes[cluster][token as usize] as u64);
+        }
+        writer.finish()
+    }
+
+    #[test]
+    fn lz77_copies() {
+        let (code, lengths) = lz77_code();
+        // 20 with extra bits 5 is 42. 225 copies 4 values from distance 2, 224 copies 3 from distance 1.
+        let data = write_tokens(&lengths, &[(0, 5), (0, 7), (0, 20), (2, 5), (0, 225), (1, 1), (0, 5), (0, 224), (1, 0)]);
+        let mut bitstream = BitStream::new(&data);
+        let mut decoder = EntropyDecoder::new(&code, &mut bitstream, 0).unwrap();
+        let values = (0..11).map(|_| decoder.read_uint(&mut bitstream, 0).unwrap()).collect::<Vec<_>>();
+        assert_eq!(values, [5, 7, 42, 7, 42, 7, 42, 5, 5, 5, 5]);
+        assert_eq!(decoder.check_final_state(&bitstream), Ok(()));
+    }
+
+    #[test]
+    fn lz77_special_distances() {
+        let (code, lengths) = lz77_code();
+        // With a multiplier of 3, distance token 0 is (0, 1) for a distance of 3 and token 1 is (1, 0) for 1.
+        // The first copy starts before any values were decoded, so it reads zeros.
+        let data = write_tokens(&lengths, &[(0, 224), (1, 1), (0, 5), (0, 7), (0, 20), (2, 5), (0, 224), (1, 0), (0, 225), (1, 1)]);
+        let mut bitstream = BitStream::new(&data);
+        let mut decoder = EntropyDecoder::new(&code, &mut bitstream, 3).unwrap();
+        let values = (0..13).map(|_| decoder.read_uint(&mut bitstream, 0).unwrap()).collect::<Vec<_>>();
+        assert_eq!(values, [0, 0, 0, 5, 7, 42, 5, 7, 42, 42, 42, 42, 42]);
+        assert_eq!(decoder.check_final_state(&bitstream), Ok(()));
+
+        // Stopping in the middle of a copy is an error
+        let data = write_tokens(&lengths, &[(0, 5), (0, 224), (1, 0)]);
+        let mut bitstream = BitStream::new(&data);
+        let mut decoder = EntropyDecoder::new(&code, &mut bitstream, 3).unwrap();
+        assert_eq!(decoder.read_uint(&mut bitstream, 0), Ok(5));
+        assert_eq!(decoder.read_uint(&mut bitstream, 0), Ok(5));
+        assert!(decoder.check_final_state(&bitstream).is_err());
+    }
+
+    #[test]
+    fn read_prefix_entropy_code() {
+        let mut writer = BitWriter::new();
+        // No LZ77, prefix codes, and a config with every token above 0 followed by extra bits
+        writer.write_bool(false);
+        writer.write_bool(true);
+        let config = HybridUintConfig { split_exponent: 0, msb_in_token: 0, lsb_in_token: 0 };
+        config.write(&mut writer, PREFIX_MAX_BITS).unwrap();
+        // An alphabet of 6 with a simple code for 2 and 5
+        writer.write_bool(true);
+        writer.write(4, 2);
+        writer.write(2, 1);
+        writer.write(2, 1);
+        writer.write(2, 1);
+        writer.write(3, 5);
+        writer.write(3, 2);
+        // 5 with extra bits 3 is 19, 2 with extra bit 1 is 3
+        writer.write(1, 1);
+        writer.write(4, 3);
+        writer.write(1, 0);
+        writer.write(1, 1);
+        let data = writer.finish();
+
+        let mut bitstream = BitStream::new(&data);
+        let code = EntropyCode::read(&mut bitstream, 1).unwrap();
+        assert_eq!(code.context_map, [0]);
+        assert_eq!(code.configs, [config]);
+        let mut decoder = EntropyDecoder::new(&code, &mut bitstream, 0).unwrap();
+        assert_eq!(decoder.read_uint(&mut bitstream, 0), Ok(19));
+        assert_eq!(decoder.read_uint(&mut bitstream, 0), Ok(3));
+        assert_eq!(decoder.check_final_state(&bitstream), Ok(()));
+    }
+
+    #[test]
+    fn read_ans_entropy_code() {
+        let mut writer = BitWriter::new();
+        // No LZ77, ANS with 5 bit alphabets, the default config, and a distribution of only symbol 3
+        writer.write_bool(false);
+        writer.write_bool(false);
+        writer.write(2, 0);
+        HybridUintConfig::defaults(5).write(&mut writer, 5).unwrap();
+        writer.write_bool(true);
+        writer.write(1, 0);
+        writer.write_var_u8(3);
+        // A single symbol never changes the state, so it starts out final
+        writer.write(32, 0x130000);
+        let data = writer.finish();
+
+        let mut bitstream = BitStream::new(&data);
+        let code = EntropyCode::read(&mut bitstream, 1).unwrap();
+        assert_eq!(code.configs, [HybridUintConfig::defaults(5)]);
+        let mut decoder = EntropyDecoder::new(&code, &mut bitstream, 0).unwrap();
+        assert_eq!(decoder.read_uint(&mut bitstream, 0), Ok(3));
+        assert_eq!(decoder.read_uint(&mut bitstream, 0), Ok(3));
+        assert_eq!(decoder.check_final_state(&bitstream), Ok(()));
+
+        // A split exponent above the alphabet size is invalid
+        let mut writer = BitWriter::new();
+        writer.write(3, 6);
+        let data = writer.finish();
+        assert!(HybridUintConfig::read(&mut BitStream::new(&data), 5).is_err());
+    }
+
+    // Headers for two clusters that always decode 0
+    fn write_single_symbol_clusters(writer: &mut BitWriter) {
+        writer.write_bool(true);
+        HybridUintConfig::defaults(PREFIX_MAX_BITS).write(writer, PREFIX_MAX_BITS).unwrap();
+        HybridUintConfig::defaults(PREFIX_MAX_BITS).write(writer, PREFIX_MAX_BITS).unwrap();
+        writer.write_bool(false);
+        writer.write_bool(false);
+    }
+
+    #[test]
+    fn simple_context_map() {
+        let mut writer = BitWriter::new();
+        writer.write_bool(false);
+        writer.write_bool(true);
+        writer.write(2, 2);
+        for cluster in [1, 0, 1] {
+            writer.write(2, cluster);
+        }
+        write_single_symbol_clusters(&mut writer);
+        let data = writer.finish();
+        let code = EntropyCode::read(&mut BitStream::new(&data), 3).unwrap();
+        assert_eq!(code.context_map, [1, 0, 1]);
+        assert_eq!(code.codes.cluster_count(), 2);
+
+        // Cluster 1 is skipped
+        let mut writer = BitWriter::new();
+        writer.write_bool(false);
+        writer.write_bool(true);
+        writer.write(2, 2);
+        for cluster in [2, 0, 2] {
+            writer.write(2, cluster);
+        }
+        let data = writer.finish();
+        assert_eq!(EntropyCode::read(&mut BitStream::new(&data), 3).unwrap_err(),
+            DecodeError::InvalidValue { bit_offset: 10, field: "ContextMap.cluster", value: Some(1) });
+    }
+
+    #[test]
+    fn entropy_coded_context_map() {
+        let mut writer = BitWriter::new();
+        // No LZ77, then a context map with move-to-front
+        writer.write_bool(false);
+        writer.write_bool(false);
+        writer.write_bool(true);
+        // The context map code: no LZ77, one prefix code with an alphabet of 2 and 1-bit codes for 0 and 1
+        writer.write_bool(false);
+        writer.write_bool(true);
+        HybridUintConfig::defaults(PREFIX_MAX_BITS).write(&mut writer, PREFIX_MAX_BITS).unwrap();
+        writer.write_bool(true);
+        writer.write(4, 0);
+        writer.write(2, 1);
+        writer.write(2, 1);
+        writer.write(1, 0);
+        writer.write(1, 1);
+        // Move-to-front indices 1, 0, 1, 1 are clusters 1, 1, 0, 1
+        for index in [1, 0, 1, 1] {
+            writer.write(1, index);
+        }
+        write_single_symbol_clusters(&mut writer);
+        let data = writer.finish();
+        let code = EntropyCode::read(&mut BitStream::new(&data), 4).unwrap();
+        assert_eq!(code.context_map, [1, 1, 0, 1]);
+        assert_eq!(code.configs.len(), 2);
+
+        // The code of a map of two contexts can't use LZ77
+        let mut writer = BitWriter::new();
+        writer.write_bool(false);
+        writer.write_bool(false);
+        writer.write_bool(false);
+        Lz77Params { enabled: true, ..Lz77Params::default() }.write(&mut writer).unwrap();
+        let data = writer.finish();
+        assert!(matches!(EntropyCode::read(&mut BitStream::new(&data), 2),
+            Err(DecodeError::InvalidValue { field: "LZ77Params.enabled", .. })));
+    }
+
+    #[test]
+    fn lz77_context_map() {
+        let mut writer = BitWriter::new();
+        // No LZ77, then an entropy coded context map without move-to-front
+        writer.write_bool(false);
+        writer.write_bool(false);
+        writer.write_bool(false);
+        // The context map code uses LZ77, with one cluster for both the values and the distances
+        Lz77Params { enabled: true, ..Lz77Params::default() }.write(&mut writer).unwrap();
+        writer.write_bool(true);
+        writer.write(2, 0);
+        writer.write_bool(true);
+        HybridUintConfig::defaults(PREFIX_MAX_BITS).write(&mut writer, PREFIX_MAX_BITS).unwrap();
+        // An alphabet of 1 + 128 + 96 = 225, with a simple code where 1 gets 1 bit and 0 and 224 get 2 bits
+        writer.write_bool(true);
+        writer.write(4, 7);
+        writer.write(7, 96);
+        writer.write(2, 1);
+        writer.write(2, 2);
+        for symbol in [1, 0, 224] { writer.write(8, symbol); }
+        // 0 and 1, then 224 copies 3 values from distance token 0, which is a distance of 1
+        let mut lengths = vec![0u8; 225];
+        for (symbol, length) in [(0, 2), (1, 1), (224, 2)] {
+            lengths[symbol] = length;
+        }
+        let codes = canonical_codes(&lengths);
+        for token in [0, 1, 224, 0] {
+            writer.write(lengths[token], codes[token] as u64);
+        }
+        write_single_symbol_clusters(&mut writer);
+        let data = writer.finish();
+        let code = EntropyCode::read(&mut BitStream::new(&data), 5).unwrap();
+        assert_eq!(code.context_map, [0, 1, 1, 1, 1]);
+        assert_eq!(code.codes.cluster_count(), 2);
+    }
+
+    #[test]
+    fn move_to_front() {
+        let mut values = [0, 3, 0, 1, 255, 1];
+        inverse_move_to_front(&mut values);
+        assert_eq!(values, [0, 3, 3, 0, 255, 0]);
+    }
+}
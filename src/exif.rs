@@ -0,0 +1,266 @@
+#![allow(dead_code)]
+
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub enum ExifIfd {
+    Primary,    // IFD0
+    Exif,       // Pointed to by tag 0x8769
+    Gps         // Pointed to by tag 0x8825
+}
+
+#[derive(Debug,Clone,PartialEq)]
+pub enum ExifValue {
+    Byte(Vec<u8>),
+    Ascii(String),
+    Short(Vec<u16>),
+    Long(Vec<u32>),
+    Rational(Vec<(u32,u32)>),
+    SByte(Vec<i8>),
+    Undefined(Vec<u8>),
+    SShort(Vec<i16>),
+    SLong(Vec<i32>),
+    SRational(Vec<(i32,i32)>),
+    Float(Vec<f32>),
+    Double(Vec<f64>)
+}
+impl ExifValue {
+    pub fn as_u32(&self) -> Option<u32> {
+        match self {
+            ExifValue::Byte(v) => v.first().map(|&x| x as u32),
+            ExifValue::Short(v) => v.first().map(|&x| x as u32),
+            ExifValue::Long(v) => v.first().copied(),
+            _ => None
+        }
+    }
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            ExifValue::Ascii(s) => Some(s.as_str()),
+            _ => None
+        }
+    }
+    pub fn as_f64_list(&self) -> Option<Vec<f64>> {
+        match self {
+            ExifValue::Rational(v) => Some(v.iter().map(|&(n,d)| n as f64 / d as f64).collect()),
+            ExifValue::SRational(v) => Some(v.iter().map(|&(n,d)| n as f64 / d as f64).collect()),
+            _ => None
+        }
+    }
+}
+
+#[derive(Debug,Clone,PartialEq)]
+pub struct ExifEntry {
+    pub ifd: ExifIfd,
+    pub tag: u16,
+    pub value: ExifValue
+}
+
+#[derive(Debug,Clone,Copy,PartialEq)]
+pub struct ExifGpsPosition {
+    pub latitude: f64,          // Degrees, negative is south
+    pub longitude: f64,         // Degrees, negative is west
+    pub altitude: Option<f64>   // Metres, negative is below sea level
+}
+
+pub const TAG_ORIENTATION: u16 = 0x0112;
+pub const TAG_MAKE: u16 = 0x010f;
+pub const TAG_MODEL: u16 = 0x0110;
+pub const TAG_DATE_TIME: u16 = 0x0132;
+pub const TAG_EXIF_IFD: u16 = 0x8769;
+pub const TAG_GPS_IFD: u16 = 0x8825;
+pub const TAG_DATE_TIME_ORIGINAL: u16 = 0x9003;
+pub const TAG_GPS_LATITUDE_REF: u16 = 0x0001;
+pub const TAG_GPS_LATITUDE: u16 = 0x0002;
+pub const TAG_GPS_LONGITUDE_REF: u16 = 0x0003;
+pub const TAG_GPS_LONGITUDE: u16 = 0x0004;
+pub const TAG_GPS_ALTITUDE_REF: u16 = 0x0005;
+pub const TAG_GPS_ALTITUDE: u16 = 0x0006;
+
+#[derive(Debug)]
+pub struct ExifData {
+    pub entries: Vec<ExifEntry>
+}
+
+struct TiffReader<'a> {
+    data: &'a [u8],
+    big_endian: bool
+}
+impl TiffReader<'_> {
+    fn bytes(&self, offset: usize, len: usize) -> Option<&[u8]> {
+        self.data.get(offset..offset.checked_add(len)?)
+    }
+    fn u16(&self, offset: usize) -> Option<u16> {
+        let bytes: [u8; 2] = self.bytes(offset, 2)?.try_into().unwrap();
+        Some(if self.big_endian { u16::from_be_bytes(bytes) } else { u16::from_le_bytes(bytes) })
+    }
+    fn u32(&self, offset: usize) -> Option<u32> {
+        let bytes: [u8; 4] = self.bytes(offset, 4)?.try_into().unwrap();
+        Some(if self.big_endian { u32::from_be_bytes(bytes) } else { u32::from_le_bytes(bytes) })
+    }
+    fn u64(&self, offset: usize) -> Option<u64> {
+        let bytes: [u8; 8] = self.bytes(offset, 8)?.try_into().unwrap();
+        Some(if self.big_endian { u64::from_be_bytes(bytes) } else { u64::from_le_bytes(bytes) })
+    }
+
+    fn read_value(&self, value_type: u16, type_size: usize, count: usize, offset: usize) -> Option<ExifValue> {
+        let bytes = self.bytes(offset, type_size.checked_mul(count)?)?;
+        let u16s = || bytes.chunks_exact(2).map(|c| if self.big_endian { u16::from_be_bytes([c[0],c[1]]) } else { u16::from_le_bytes([c[0],c[1]]) });
+        let u32s = || bytes.chunks_exact(4).map(|c| if self.big_endian { u32::from_be_bytes(c.try_into().unwrap()) } else { u32::from_le_bytes(c.try_into().unwrap()) });
+        let pairs = || { let v: Vec<u32> = u32s().collect(); v.chunks_exact(2).map(|p| (p[0],p[1])).collect::<Vec<_>>() };
+        Some(match value_type {
+            1 => ExifValue::Byte(bytes.to_vec()),
+            2 => {
+                let end = bytes.iter().position(|&b| b == 0).unwrap_or(bytes.len());
+                ExifValue::Ascii(String::from_utf8_lossy(&bytes[..end]).trim_end().to_owned())
+            },
+            3 => ExifValue::Short(u16s().collect()),
+            4 => ExifValue::Long(u32s().collect()),
+            5 => ExifValue::Rational(pairs()),
+            6 => ExifValue::SByte(bytes.iter().map(|&b| b as i8).collect()),
+            7 => ExifValue::Undefined(bytes.to_vec()),
+            8 => ExifValue::SShort(u16s().map(|x| x as i16).collect()),
+            9 => ExifValue::SLong(u32s().map(|x| x as i32).collect()),
+            10 => ExifValue::SRational(pairs().into_iter().map(|(n,d)| (n as i32,d as i32)).collect()),
+            11 => ExifValue::Float(u32s().map(f32::from_bits).collect()),
+            12 => ExifValue::Double((0..count).map(|i| self.u64(offset + i * 8).map(f64::from_bits)).collect::<Option<_>>()?),
+            _ => return None
+        })
+    }
+
+    fn read_ifd(&self, ifd: ExifIfd, offset: usize, entries: &mut Vec<ExifEntry>, visited: &mut Vec<usize>) -> Option<()> {
+        if visited.contains(&offset) { return None; }
+        visited.push(offset);
+        let entry_count = self.u16(offset)? as usize;
+        for i in 0..entry_count {
+            let entry_offset = offset + 2 + i * 12;
+            let tag = self.u16(entry_offset)?;
+            let value_type = self.u16(entry_offset + 2)?;
+            let count = self.u32(entry_offset + 4)? as usize;
+            let type_size: usize = match value_type {
+                1 | 2 | 6 | 7 => 1,
+                3 | 8 => 2,
+                4 | 9 | 11 => 4,
+                5 | 10 | 12 => 8,
+                _ => continue // Unknown types are skipped as the spec requires
+            };
+            let value_offset = if type_size.checked_mul(count)? <= 4 { entry_offset + 8 } else { self.u32(entry_offset + 8)? as usize };
+            let value = match self.read_value(value_type, type_size, count, value_offset) {
+                Some(value) => value,
+                None => continue
+            };
+            let sub_ifd = match (ifd, tag) {
+                (ExifIfd::Primary, TAG_EXIF_IFD) => Some(ExifIfd::Exif),
+                (ExifIfd::Primary, TAG_GPS_IFD) => Some(ExifIfd::Gps),
+                _ => None
+            };
+            if let (Some(sub_ifd), Some(sub_offset)) = (sub_ifd, value.as_u32()) {
+                // A broken sub-IFD shouldn't lose the tags that were already read
+                let _ = self.read_ifd(sub_ifd, sub_offset as usize, entries, visited);
+            }
+            entries.push(ExifEntry { ifd, tag, value });
+        }
+        Some(())
+    }
+}
+
+impl ExifData {
+    // Parses the TIFF structure that makes up the Exif payload, starting at the byte order mark
+    pub fn parse(tiff_data: &[u8]) -> Option<Self> {
+        let big_endian = match tiff_data.get(0..4)? {
+            b"II*\0" => false,
+            b"MM\0*" => true,
+            _ => return None
+        };
+        let reader = TiffReader { data: tiff_data, big_endian };
+        let ifd0_offset = reader.u32(4)? as usize;
+        let mut entries: Vec<ExifEntry> = Vec::new();
+        reader.read_ifd(ExifIfd::Primary, ifd0_offset, &mut entries, &mut Vec::new())?;
+        Some(Self { entries })
+    }
+
+    pub fn get(&self, ifd: ExifIfd, tag: u16) -> Option<&ExifValue> {
+        self.entries.iter().find(|entry| entry.ifd == ifd && entry.tag == tag).map(|entry| &entry.value)
+    }
+
+    pub fn orientation(&self) -> Option<u16> {
+        self.get(ExifIfd::Primary, TAG_ORIENTATION)?.as_u32().map(|x| x as u16)
+    }
+
+    pub fn make(&self) -> Option<&str> {
+        self.get(ExifIfd::Primary, TAG_MAKE)?.as_str()
+    }
+
+    pub fn model(&self) -> Option<&str> {
+        self.get(ExifIfd::Primary, TAG_MODEL)?.as_str()
+    }
+
+    // The capture time in Exif's "YYYY:MM:DD HH:MM:SS" format, falling back to the modification time
+    pub fn capture_time(&self) -> Option<&str> {
+        self.get(ExifIfd::Exif, TAG_DATE_TIME_ORIGINAL)
+            .or_else(|| self.get(ExifIfd::Primary, TAG_DATE_TIME))?
+            .as_str()
+    }
+
+    pub fn gps_position(&self) -> Option<ExifGpsPosition> {
+        let to_degrees = |tag: u16| -> Option<f64> {
+            let parts = self.get(ExifIfd::Gps, tag)?.as_f64_list()?;
+            Some(parts.first()? + parts.get(1).unwrap_or(&0.0) / 60.0 + parts.get(2).unwrap_or(&0.0) / 3600.0)
+        };
+        let mut latitude = to_degrees(TAG_GPS_LATITUDE)?;
+        let mut longitude = to_degrees(TAG_GPS_LONGITUDE)?;
+        if self.get(ExifIfd::Gps, TAG_GPS_LATITUDE_REF).and_then(ExifValue::as_str) == Some("S") { latitude = -latitude; }
+        if self.get(ExifIfd::Gps, TAG_GPS_LONGITUDE_REF).and_then(ExifValue::as_str) == Some("W") { longitude = -longitude; }
+        let altitude = self.get(ExifIfd::Gps, TAG_GPS_ALTITUDE)
+            .and_then(ExifValue::as_f64_list)
+            .and_then(|v| v.first().copied())
+            .map(|altitude| {
+                let below_sea_level = self.get(ExifIfd::Gps, TAG_GPS_ALTITUDE_REF).and_then(ExifValue::as_u32) == Some(1);
+                if below_sea_level { -altitude } else { altitude }
+            });
+        Some(ExifGpsPosition { latitude, longitude, altitude })
+    }
+}
+
+#[cfg(test)]
+mod exif_tests {
+    use crate::exif::*;
+
+    fn entry(tag: u16, value_type: u16, count: u32, value: u32) -> Vec<u8> {
+        let mut out = Vec::new();
+        out.extend_from_slice(&tag.to_le_bytes());
+        out.extend_from_slice(&value_type.to_le_bytes());
+        out.extend_from_slice(&count.to_le_bytes());
+        out.extend_from_slice(&value.to_le_bytes());
+        out
+    }
+
+    #[test]
+    fn parse_tiff_tags() {
+        // IFD0 at 8 (4 entries: 8 + 2 + 48 + 4 = 62), GPS IFD at 62 (4 entries: 62 + 2 + 48 + 4 = 116), data after that
+        let mut tiff: Vec<u8> = b"II*\0\x08\0\0\0".to_vec();
+        tiff.extend_from_slice(&4u16.to_le_bytes());
+        tiff.extend(entry(TAG_MAKE, 2, 4, u32::from_le_bytes(*b"ACME")));
+        tiff.extend(entry(TAG_MODEL, 2, 6, 116));
+        tiff.extend(entry(TAG_ORIENTATION, 3, 1, 6));
+        tiff.extend(entry(TAG_GPS_IFD, 4, 1, 62));
+        tiff.extend_from_slice(&0u32.to_le_bytes());
+        tiff.extend_from_slice(&4u16.to_le_bytes());
+        tiff.extend(entry(TAG_GPS_LATITUDE_REF, 2, 2, u32::from_le_bytes(*b"S\0\0\0")));
+        tiff.extend(entry(TAG_GPS_LATITUDE, 5, 3, 122));
+        tiff.extend(entry(TAG_GPS_LONGITUDE_REF, 2, 2, u32::from_le_bytes(*b"E\0\0\0")));
+        tiff.extend(entry(TAG_GPS_LONGITUDE, 5, 3, 146));
+        tiff.extend_from_slice(&0u32.to_le_bytes());
+        tiff.extend_from_slice(b"X-100\0");
+        for (n, d) in [(33u32, 1u32), (30, 1), (0, 1), (151, 1), (12, 1), (36, 1)] {
+            tiff.extend_from_slice(&n.to_le_bytes());
+            tiff.extend_from_slice(&d.to_le_bytes());
+        }
+
+        let exif = ExifData::parse(&tiff).unwrap();
+        assert_eq!(exif.make(), Some("ACME"));
+        assert_eq!(exif.model(), Some("X-100"));
+        assert_eq!(exif.orientation(), Some(6));
+        let gps = exif.gps_position().unwrap();
+        assert_eq!(gps.latitude, -33.5);
+        assert!((gps.longitude - 151.21).abs() < 1e-9);
+        assert_eq!(gps.altitude, None);
+    }
+}
@@ -0,0 +1,557 @@
+#![allow(dead_code)]
+
+use std::io::Error as IoError;
+use std::io::ErrorKind;
+use std::io::{Cursor, Read};
+
+use crate::bit_reader::BitStream;
+use crate::bit_reader::QuadDistributions::*;
+use crate::decode_error::{DecodeError, DecodeResultExt};
+use crate::jxl_file::JxlFile;
+
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub enum JpegAppMarkerType {
+    Unknown,    // Stored in the jbrd box itself
+    Icc,        // Rebuilt from the ICC profile in the codestream
+    Exif,       // Rebuilt from the Exif box
+    Xmp         // Rebuilt from the xml box
+}
+impl JpegAppMarkerType {
+    fn from_u32(value: u32) -> Option<Self> {
+        match value {
+            0 => Some(Self::Unknown),
+            1 => Some(Self::Icc),
+            2 => Some(Self::Exif),
+            3 => Some(Self::Xmp),
+            _ => None
+        }
+    }
+}
+
+#[derive(Debug,PartialEq)]
+pub struct JpegAppMarker {
+    pub marker_type: JpegAppMarkerType,
+    pub data: Vec<u8>   // The whole marker segment starting with the marker byte, filled in for Unknown markers only
+}
+
+#[derive(Debug,PartialEq)]
+pub struct JpegQuantTable {
+    pub precision: u8,
+    pub index: u8,
+    pub is_last: bool
+}
+
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub enum JpegComponentType {
+    Gray,
+    YCbCr,
+    Rgb,
+    Custom
+}
+
+#[derive(Debug,PartialEq)]
+pub struct JpegComponent {
+    pub id: u8,
+    pub quant_index: u8
+}
+
+#[derive(Debug,PartialEq)]
+pub struct JpegHuffmanCode {
+    pub is_ac: bool,
+    pub id: u8,
+    pub is_last: bool,
+    pub counts: [u32; 17],      // Number of codes of each length
+    pub values: Vec<u32>
+}
+
+#[derive(Debug,PartialEq)]
+pub struct JpegScanComponent {
+    pub component_index: u8,
+    pub ac_table: u8,
+    pub dc_table: u8
+}
+
+#[derive(Debug,PartialEq)]
+pub struct JpegScan {
+    pub components: Vec<JpegScanComponent>,
+    pub ss: u8,
+    pub se: u8,
+    pub al: u8,
+    pub ah: u8,
+    pub last_needed_pass: u32,
+    pub reset_points: Vec<u32>,
+    pub extra_zero_runs: Vec<(u32,u32)>    // (block index, number of extra zero runs)
+}
+
+#[derive(Debug,PartialEq)]
+pub struct JpegReconstructionData {
+    pub is_gray: bool,
+    pub marker_order: Vec<u8>,
+    pub app_markers: Vec<JpegAppMarker>,
+    pub com_markers: Vec<Vec<u8>>,
+    pub quant_tables: Vec<JpegQuantTable>,
+    pub component_type: JpegComponentType,
+    pub components: Vec<JpegComponent>,
+    pub huffman_codes: Vec<JpegHuffmanCode>,
+    pub scans: Vec<JpegScan>,
+    pub restart_interval: Option<u16>,
+    pub inter_marker_data: Vec<Vec<u8>>,
+    pub tail_data: Vec<u8>,
+    pub padding_bits: Option<Vec<bool>>
+}
+
+fn read_block_indices(bitstream: &mut BitStream, count: u32, field: &'static str) -> Result<Vec<u32>,DecodeError> {
+    // Block indices are stored as gaps from the previous index
+    let mut indices = Vec::with_capacity(count.min(1024) as usize);
+    let mut next: u32 = 0;
+    for _ in 0..count {
+        let gap = bitstream.read_quad_u32(RawValue(0), BitCountWithOffset(3, 1), BitCountWithOffset(5, 9), BitCountWithOffset(28, 41)).field(field)?;
+        let index = next.checked_add(gap).ok_or_else(|| bitstream.invalid_value(field,Some(gap as u64)))?;
+        indices.push(index);
+        next = index.checked_add(1).ok_or_else(|| bitstream.invalid_value(field,Some(gap as u64)))?;
+    }
+    Ok(indices)
+}
+
+impl JpegReconstructionData {
+    // Parses the payload of a "jbrd" box: a bundle with the JPEG structure, followed by
+    // the Brotli-compressed bytes that can't be derived from the codestream
+    pub fn read(data: &[u8]) -> Result<Self,DecodeError> {
+        let mut bitstream = BitStream::new(data);
+        let is_gray = bitstream.read_bool().field("JpegData.is_gray")?;
+
+        let mut marker_order: Vec<u8> = Vec::new();
+        loop {
+            let marker = bitstream.read_u8(6).field("JpegData.marker_order")? + 0xc0;
+            marker_order.push(marker);
+            if marker == 0xd9 { break; }
+            if marker_order.len() > 16384 { return Err(bitstream.invalid_value("JpegData.marker_order",Some(marker_order.len() as u64))); }
+        }
+        let count = |marker: &dyn Fn(u8) -> bool| marker_order.iter().filter(|&&m| marker(m)).count();
+        let num_app_markers = count(&|m| (0xe0..=0xef).contains(&m));
+        let num_com_markers = count(&|m| m == 0xfe);
+        let num_scans = count(&|m| m == 0xda);
+        let num_inter_markers = count(&|m| m == 0xff);
+        let has_dri = count(&|m| m == 0xdd) > 0;
+
+        let mut app_markers: Vec<JpegAppMarker> = Vec::with_capacity(num_app_markers);
+        for _ in 0..num_app_markers {
+            let marker_type = bitstream.read_quad_u32(RawValue(0), RawValue(1), BitCountWithOffset(1, 2), BitCountWithOffset(2, 4)).field("JpegData.app_marker_type")?;
+            let marker_type = JpegAppMarkerType::from_u32(marker_type).ok_or_else(|| bitstream.invalid_value("JpegData.app_marker_type",Some(marker_type as u64)))?;
+            let len = bitstream.read_u32(16).field("JpegData.app_marker_size")? as usize + 1;
+            if len < 3 { return Err(bitstream.invalid_value("JpegData.app_marker_size",Some(len as u64))); }
+            app_markers.push(JpegAppMarker { marker_type, data: vec![0; len] });
+        }
+        let mut com_markers: Vec<Vec<u8>> = Vec::with_capacity(num_com_markers);
+        for _ in 0..num_com_markers {
+            let len = bitstream.read_u32(16).field("JpegData.com_marker_size")? as usize + 1;
+            if len < 3 { return Err(bitstream.invalid_value("JpegData.com_marker_size",Some(len as u64))); }
+            com_markers.push(vec![0; len]);
+        }
+
+        let num_quant_tables = bitstream.read_quad_u32(RawValue(1), RawValue(2), RawValue(3), RawValue(4)).field("JpegData.num_quant_tables")?;
+        if num_quant_tables == 4 { return Err(bitstream.invalid_value("JpegData.num_quant_tables",Some(4))); }
+        let mut quant_tables: Vec<JpegQuantTable> = Vec::new();
+        for _ in 0..num_quant_tables {
+            quant_tables.push(JpegQuantTable {
+                precision: bitstream.read_u8(1).field("JpegData.quant_precision")?,
+                index: bitstream.read_u8(2).field("JpegData.quant_index")?,
+                is_last: bitstream.read_bool().field("JpegData.quant_is_last")?
+            });
+        }
+
+        let component_type = match bitstream.read_u8(2).field("JpegData.component_type")? {
+            0 => JpegComponentType::Gray,
+            1 => JpegComponentType::YCbCr,
+            2 => JpegComponentType::Rgb,
+            _ => JpegComponentType::Custom
+        };
+        let component_ids: Vec<u8> = match component_type {
+            JpegComponentType::Gray => vec![1],
+            JpegComponentType::YCbCr => vec![1, 2, 3],
+            JpegComponentType::Rgb => b"RGB".to_vec(),
+            JpegComponentType::Custom => {
+                let num_components = bitstream.read_quad_u32(RawValue(1), RawValue(2), RawValue(3), RawValue(4)).field("JpegData.num_components")?;
+                (0..num_components).map(|_| bitstream.read_u8(8).field("JpegData.component_id")).collect::<Result<_,_>>()?
+            }
+        };
+        let mut components: Vec<JpegComponent> = Vec::new();
+        for id in component_ids {
+            let quant_index = bitstream.read_u8(2).field("JpegData.component_quant_index")?;
+            if quant_index as usize >= quant_tables.len() { return Err(bitstream.invalid_value("JpegData.component_quant_index",Some(quant_index as u64))); }
+            components.push(JpegComponent { id, quant_index });
+        }
+
+        let num_huffman_codes = bitstream.read_quad_u32(RawValue(4), BitCountWithOffset(3, 2), BitCountWithOffset(4, 10), BitCountWithOffset(6, 26)).field("JpegData.num_huffman_codes")?;
+        let mut huffman_codes: Vec<JpegHuffmanCode> = Vec::new();
+        for _ in 0..num_huffman_codes {
+            let is_ac = bitstream.read_bool().field("JpegData.huffman_is_ac")?;
+            let id = bitstream.read_u8(2).field("JpegData.huffman_id")?;
+            let is_last = bitstream.read_bool().field("JpegData.huffman_is_last")?;
+            let mut counts = [0u32; 17];
+            for count in counts.iter_mut() {
+                *count = bitstream.read_quad_u32(RawValue(0), RawValue(1), BitCountWithOffset(3, 2), BitCount(8)).field("JpegData.huffman_counts")?;
+            }
+            let num_symbols: u32 = counts.iter().sum();
+            if !(1..=257).contains(&num_symbols) { return Err(bitstream.invalid_value("JpegData.huffman_counts",Some(num_symbols as u64))); }
+            let values = (0..num_symbols)
+                .map(|_| bitstream.read_quad_u32(BitCount(2), BitCountWithOffset(2, 4), BitCountWithOffset(4, 8), BitCountWithOffset(8, 1)).field("JpegData.huffman_values"))
+                .collect::<Result<Vec<u32>,_>>()?;
+            huffman_codes.push(JpegHuffmanCode { is_ac, id, is_last, counts, values });
+        }
+
+        let mut scans: Vec<JpegScan> = Vec::with_capacity(num_scans);
+        for _ in 0..num_scans {
+            let num_components = bitstream.read_quad_u32(RawValue(1), RawValue(2), RawValue(3), RawValue(4)).field("JpegData.scan_num_components")?;
+            let ss = bitstream.read_u8(6).field("JpegData.scan_ss")?;
+            let se = bitstream.read_u8(6).field("JpegData.scan_se")?;
+            let al = bitstream.read_u8(4).field("JpegData.scan_al")?;
+            let ah = bitstream.read_u8(4).field("JpegData.scan_ah")?;
+            let mut scan_components: Vec<JpegScanComponent> = Vec::new();
+            for _ in 0..num_components {
+                scan_components.push(JpegScanComponent {
+                    component_index: bitstream.read_u8(2).field("JpegData.scan_component_index")?,
+                    ac_table: bitstream.read_u8(2).field("JpegData.scan_ac_table")?,
+                    dc_table: bitstream.read_u8(2).field("JpegData.scan_dc_table")?
+                });
+            }
+            let last_needed_pass = bitstream.read_quad_u32(RawValue(0), RawValue(1), RawValue(2), BitCountWithOffset(3, 3)).field("JpegData.scan_last_needed_pass")?;
+            scans.push(JpegScan { components: scan_components, ss, se, al, ah, last_needed_pass, reset_points: Vec::new(), extra_zero_runs: Vec::new() });
+        }
+
+        // Everything from here on is only needed for a bit exact JPEG
+        let restart_interval = if has_dri { Some(bitstream.read_u16(16).field("JpegData.restart_interval")?) } else { None };
+        for scan in scans.iter_mut() {
+            let num_reset_points = bitstream.read_quad_u32(RawValue(0), BitCountWithOffset(2, 1), BitCountWithOffset(4, 4), BitCountWithOffset(16, 20)).field("JpegData.num_reset_points")?;
+            scan.reset_points = read_block_indices(&mut bitstream, num_reset_points, "JpegData.reset_points")?;
+            let num_extra_zero_runs = bitstream.read_quad_u32(RawValue(0), BitCountWithOffset(2, 1), BitCountWithOffset(4, 4), BitCountWithOffset(16, 20)).field("JpegData.num_extra_zero_runs")?;
+            let mut next: u32 = 0;
+            for _ in 0..num_extra_zero_runs {
+                let runs = bitstream.read_quad_u32(RawValue(1), BitCountWithOffset(2, 2), BitCountWithOffset(4, 5), BitCountWithOffset(8, 20)).field("JpegData.extra_zero_runs")?;
+                let gap = bitstream.read_quad_u32(RawValue(0), BitCountWithOffset(3, 1), BitCountWithOffset(5, 9), BitCountWithOffset(28, 41)).field("JpegData.extra_zero_runs")?;
+                let index = next.checked_add(gap).ok_or_else(|| bitstream.invalid_value("JpegData.extra_zero_runs",Some(gap as u64)))?;
+                scan.extra_zero_runs.push((index, runs));
+                next = index.checked_add(1).ok_or_else(|| bitstream.invalid_value("JpegData.extra_zero_runs",Some(gap as u64)))?;
+            }
+        }
+        let inter_marker_sizes = (0..num_inter_markers).map(|_| bitstream.read_u32(16).field("JpegData.inter_marker_size")).collect::<Result<Vec<u32>,_>>()?;
+        let tail_data_len = bitstream.read_quad_u32(RawValue(0), BitCountWithOffset(8, 1), BitCountWithOffset(16, 257), BitCountWithOffset(22, 65793)).field("JpegData.tail_data_length")?;
+        let padding_bits = if bitstream.read_bool().field("JpegData.has_zero_padding_bit")? {
+            let num_bits = bitstream.read_u32(24).field("JpegData.num_padding_bits")?;
+            Some((0..num_bits).map(|_| bitstream.read_bool().field("JpegData.padding_bits")).collect::<Result<Vec<bool>,_>>()?)
+        } else { None };
+
+        let mut reconstruction = Self {
+            is_gray,
+            marker_order,
+            app_markers,
+            com_markers,
+            quant_tables,
+            component_type,
+            components,
+            huffman_codes,
+            scans,
+            restart_interval,
+            inter_marker_data: inter_marker_sizes.iter().map(|&size| vec![0; size as usize]).collect(),
+            tail_data: vec![0; tail_data_len as usize],
+            padding_bits
+        };
+        bitstream.align_to_byte().field("JpegData.padding")?;
+        let compressed_offset = bitstream.bytes_consumed();
+        reconstruction.read_compressed_data(&data[compressed_offset..]).map_err(|error| match error.kind() {
+            ErrorKind::UnexpectedEof => DecodeError::Truncated { bit_offset: data.len() as u64 * 8, field: "JpegData.compressed_data" },
+            _ => DecodeError::InvalidValue { bit_offset: compressed_offset as u64 * 8, field: "JpegData.compressed_data", value: None }
+        })?;
+        Ok(reconstruction)
+    }
+
+    fn read_compressed_data(&mut self, data: &[u8]) -> Result<(),IoError> {
+        if data.is_empty() {
+            // Nothing was compressed, which is only valid if nothing needed to be
+            let unknown_app_markers = self.app_markers.iter().any(|marker| marker.marker_type == JpegAppMarkerType::Unknown);
+            let other_data = self.com_markers.iter().chain(self.inter_marker_data.iter()).any(|buffer| !buffer.is_empty());
+            if unknown_app_markers || other_data || !self.tail_data.is_empty() {
+                return Err(IoError::new(ErrorKind::UnexpectedEof,"jbrd box is missing its compressed data"));
+            }
+            return Ok(());
+        }
+        let mut decompressor = brotli_decompressor::Decompressor::new(Cursor::new(data), 4096);
+        for app_marker in self.app_markers.iter_mut().filter(|marker| marker.marker_type == JpegAppMarkerType::Unknown) {
+            decompressor.read_exact(&mut app_marker.data)?;
+        }
+        for buffer in self.com_markers.iter_mut().chain(self.inter_marker_data.iter_mut()) {
+            decompressor.read_exact(buffer)?;
+        }
+        decompressor.read_exact(&mut self.tail_data)?;
+        if decompressor.read(&mut [0u8])? != 0 {
+            return Err(IoError::new(ErrorKind::InvalidData,"jbrd box has trailing data"));
+        }
+        Ok(())
+    }
+}
+
+// The scan data has to be re-encoded from the quantised DCT coefficients of the main frame, which can't be decoded yet.
+// Only the jbrd box is read, so that errors in it are still reported.
+pub fn reconstruct_jpeg(input_file: &JxlFile) -> Result<Vec<u8>,DecodeError> {
+    let Some(reconstruction) = input_file.get_jpeg_reconstruction() else {
+        return Err(DecodeError::Container { message: "file has no JPEG reconstruction data".to_string() });
+    };
+    reconstruction?;
+    Err(DecodeError::Unsupported { bit_offset: 0, field: "JpegData", feature: "JPEG reconstruction from VarDCT coefficients" })
+}
+
+#[cfg(test)]
+mod jpeg_reconstruction_tests {
+    use std::io::{Cursor, Error as IoError};
+    use crate::bit_writer::{BitWriter, unrepresentable};
+    use crate::box_writer;
+    use crate::decode_error::DecodeError;
+    use crate::jpeg_reconstruction::*;
+    use crate::jxl_file::JxlFile;
+
+    fn write_block_indices(writer: &mut BitWriter, indices: &[u32]) -> Result<(),IoError> {
+        let mut next: u32 = 0;
+        for &index in indices {
+            let gap = index.checked_sub(next).ok_or_else(|| unrepresentable("block indices must be increasing"))?;
+            writer.write_quad_u32(gap, RawValue(0), BitCountWithOffset(3, 1), BitCountWithOffset(5, 9), BitCountWithOffset(28, 41))?;
+            next = index + 1;
+        }
+        Ok(())
+    }
+
+    // Writes the payload of a "jbrd" box. The data that read() decompresses is stored in uncompressed Brotli
+    // meta-blocks, and left out when there is none.
+    fn write_jbrd(jpeg_data: &JpegReconstructionData) -> Result<Vec<u8>,IoError> {
+        if jpeg_data.marker_order.last() != Some(&0xd9) { return Err(unrepresentable("the marker order must end with EOI")); }
+        let count = |marker: &dyn Fn(u8) -> bool| jpeg_data.marker_order.iter().filter(|&&m| marker(m)).count();
+        if count(&|m| (0xe0..=0xef).contains(&m)) != jpeg_data.app_markers.len() || count(&|m| m == 0xfe) != jpeg_data.com_markers.len()
+            || count(&|m| m == 0xda) != jpeg_data.scans.len() || count(&|m| m == 0xff) != jpeg_data.inter_marker_data.len()
+            || (count(&|m| m == 0xdd) > 0) != jpeg_data.restart_interval.is_some() {
+            return Err(unrepresentable("the markers don't match the marker order"));
+        }
+        let size = |data: &Vec<u8>| match data.len() {
+            3..=0x10000 => Ok((data.len() - 1) as u64),
+            _ => Err(unrepresentable("marker segments must be 3 to 65536 bytes"))
+        };
+
+        let mut writer = BitWriter::new();
+        writer.write_bool(jpeg_data.is_gray);
+        for &marker in &jpeg_data.marker_order {
+            if marker < 0xc0 { return Err(unrepresentable("markers must be at least 0xc0")); }
+            writer.write(6, (marker - 0xc0) as u64);
+        }
+        for app_marker in &jpeg_data.app_markers {
+            writer.write_quad_u32(app_marker.marker_type as u32, RawValue(0), RawValue(1), BitCountWithOffset(1, 2), BitCountWithOffset(2, 4))?;
+            writer.write(16, size(&app_marker.data)?);
+        }
+        for com_marker in &jpeg_data.com_markers {
+            writer.write(16, size(com_marker)?);
+        }
+
+        if jpeg_data.quant_tables.len() > 3 { return Err(unrepresentable("there can be at most 3 quantisation tables")); }
+        writer.write_quad_u32(jpeg_data.quant_tables.len() as u32, RawValue(1), RawValue(2), RawValue(3), RawValue(4))?;
+        for quant_table in &jpeg_data.quant_tables {
+            writer.write(1, quant_table.precision as u64);
+            writer.write(2, quant_table.index as u64);
+            writer.write_bool(quant_table.is_last);
+        }
+
+        writer.write(2, jpeg_data.component_type as u64);
+        if jpeg_data.component_type == JpegComponentType::Custom {
+            writer.write_quad_u32(jpeg_data.components.len() as u32, RawValue(1), RawValue(2), RawValue(3), RawValue(4))?;
+            for component in &jpeg_data.components {
+                writer.write(8, component.id as u64);
+            }
+        }
+        for component in &jpeg_data.components {
+            writer.write(2, component.quant_index as u64);
+        }
+
+        writer.write_quad_u32(jpeg_data.huffman_codes.len() as u32, RawValue(4), BitCountWithOffset(3, 2), BitCountWithOffset(4, 10), BitCountWithOffset(6, 26))?;
+        for huffman_code in &jpeg_data.huffman_codes {
+            writer.write_bool(huffman_code.is_ac);
+            writer.write(2, huffman_code.id as u64);
+            writer.write_bool(huffman_code.is_last);
+            for &count in &huffman_code.counts {
+                writer.write_quad_u32(count, RawValue(0), RawValue(1), BitCountWithOffset(3, 2), BitCount(8))?;
+            }
+            for &value in &huffman_code.values {
+                writer.write_quad_u32(value, BitCount(2), BitCountWithOffset(2, 4), BitCountWithOffset(4, 8), BitCountWithOffset(8, 1))?;
+            }
+        }
+
+        for scan in &jpeg_data.scans {
+            writer.write_quad_u32(scan.components.len() as u32, RawValue(1), RawValue(2), RawValue(3), RawValue(4))?;
+            writer.write(6, scan.ss as u64);
+            writer.write(6, scan.se as u64);
+            writer.write(4, scan.al as u64);
+            writer.write(4, scan.ah as u64);
+            for component in &scan.components {
+                writer.write(2, component.component_index as u64);
+                writer.write(2, component.ac_table as u64);
+                writer.write(2, component.dc_table as u64);
+            }
+            writer.write_quad_u32(scan.last_needed_pass, RawValue(0), RawValue(1), RawValue(2), BitCountWithOffset(3, 3))?;
+        }
+
+        if let Some(restart_interval) = jpeg_data.restart_interval {
+            writer.write(16, restart_interval as u64);
+        }
+        for scan in &jpeg_data.scans {
+            writer.write_quad_u32(scan.reset_points.len() as u32, RawValue(0), BitCountWithOffset(2, 1), BitCountWithOffset(4, 4), BitCountWithOffset(16, 20))?;
+            write_block_indices(&mut writer, &scan.reset_points)?;
+            writer.write_quad_u32(scan.extra_zero_runs.len() as u32, RawValue(0), BitCountWithOffset(2, 1), BitCountWithOffset(4, 4), BitCountWithOffset(16, 20))?;
+            let mut next: u32 = 0;
+            for &(index, runs) in &scan.extra_zero_runs {
+                let gap = index.checked_sub(next).ok_or_else(|| unrepresentable("block indices must be increasing"))?;
+                writer.write_quad_u32(runs, RawValue(1), BitCountWithOffset(2, 2), BitCountWithOffset(4, 5), BitCountWithOffset(8, 20))?;
+                writer.write_quad_u32(gap, RawValue(0), BitCountWithOffset(3, 1), BitCountWithOffset(5, 9), BitCountWithOffset(28, 41))?;
+                next = index + 1;
+            }
+        }
+        for data in &jpeg_data.inter_marker_data {
+            if data.len() > 0xffff { return Err(unrepresentable("inter-marker data must be less than 65536 bytes")); }
+            writer.write(16, data.len() as u64);
+        }
+        writer.write_quad_u32(jpeg_data.tail_data.len() as u32, RawValue(0), BitCountWithOffset(8, 1), BitCountWithOffset(16, 257), BitCountWithOffset(22, 65793))?;
+        writer.write_bool(jpeg_data.padding_bits.is_some());
+        if let Some(padding_bits) = &jpeg_data.padding_bits {
+            if padding_bits.len() >= 1 << 24 { return Err(unrepresentable("there can be at most 2^24 - 1 padding bits")); }
+            writer.write(24, padding_bits.len() as u64);
+            for &bit in padding_bits {
+                writer.write_bool(bit);
+            }
+        }
+
+        let mut output = writer.finish();
+        let mut uncompressed: Vec<u8> = Vec::new();
+        for app_marker in jpeg_data.app_markers.iter().filter(|marker| marker.marker_type == JpegAppMarkerType::Unknown) {
+            uncompressed.extend_from_slice(&app_marker.data);
+        }
+        for buffer in jpeg_data.com_markers.iter().chain(jpeg_data.inter_marker_data.iter()) {
+            uncompressed.extend_from_slice(buffer);
+        }
+        uncompressed.extend_from_slice(&jpeg_data.tail_data);
+        if !uncompressed.is_empty() {
+            output.extend(box_writer::brotli_stored(&uncompressed));
+        }
+        Ok(output)
+    }
+
+    fn huffman_code(is_ac: bool, id: u8, is_last: bool, values: Vec<u32>) -> JpegHuffmanCode {
+        let mut counts = [0u32; 17];
+        counts[values.len().ilog2() as usize + 1] = values.len() as u32;
+        JpegHuffmanCode { is_ac, id, is_last, counts, values }
+    }
+
+    fn sample_data() -> JpegReconstructionData {
+        let mut app0 = b"\xe0\0\x10JFIF\0".to_vec();
+        app0.resize(17, 0);
+        JpegReconstructionData {
+            is_gray: false,
+            // SOI, APP0, APP1, COM, DQT, SOF0, DHT, DRI, SOS, inter-marker data, SOS, EOI
+            marker_order: vec![0xd8, 0xe0, 0xe1, 0xfe, 0xdb, 0xc0, 0xc4, 0xdd, 0xda, 0xff, 0xda, 0xd9],
+            app_markers: vec![
+                JpegAppMarker { marker_type: JpegAppMarkerType::Unknown, data: app0 },
+                JpegAppMarker { marker_type: JpegAppMarkerType::Exif, data: vec![0; 300] }
+            ],
+            com_markers: vec![b"\xfe\0\x07hello".to_vec()],
+            quant_tables: vec![
+                JpegQuantTable { precision: 0, index: 0, is_last: false },
+                JpegQuantTable { precision: 1, index: 1, is_last: true }
+            ],
+            component_type: JpegComponentType::YCbCr,
+            components: vec![
+                JpegComponent { id: 1, quant_index: 0 },
+                JpegComponent { id: 2, quant_index: 1 },
+                JpegComponent { id: 3, quant_index: 1 }
+            ],
+            huffman_codes: vec![
+                huffman_code(false, 0, false, vec![0, 1, 2, 3]),
+                huffman_code(true, 0, false, (0..40).collect()),
+                huffman_code(false, 1, false, vec![5, 7]),
+                huffman_code(true, 1, true, vec![0x11, 0xf0, 0x01, 0x00, 0x21, 0x31, 0x41, 0xfa])
+            ],
+            scans: vec![
+                JpegScan {
+                    components: vec![JpegScanComponent { component_index: 0, ac_table: 0, dc_table: 0 }],
+                    ss: 0, se: 63, al: 0, ah: 0, last_needed_pass: 0,
+                    reset_points: vec![3, 4, 100],
+                    extra_zero_runs: vec![(0, 1), (7, 30)]
+                },
+                JpegScan {
+                    components: vec![
+                        JpegScanComponent { component_index: 1, ac_table: 1, dc_table: 1 },
+                        JpegScanComponent { component_index: 2, ac_table: 1, dc_table: 1 }
+                    ],
+                    ss: 1, se: 5, al: 2, ah: 1, last_needed_pass: 4,
+                    reset_points: Vec::new(),
+                    extra_zero_runs: Vec::new()
+                }
+            ],
+            restart_interval: Some(64),
+            inter_marker_data: vec![vec![0xff; 3]],
+            tail_data: b"trailing".to_vec(),
+            padding_bits: Some(vec![true, false, true])
+        }
+    }
+
+    #[test]
+    fn jbrd_round_trip() {
+        let jpeg_data = sample_data();
+        let data = write_jbrd(&jpeg_data).unwrap();
+        assert_eq!(JpegReconstructionData::read(&data), Ok(jpeg_data));
+
+        // Without any data to compress, the payload ends after the bundle
+        let mut jpeg_data = sample_data();
+        jpeg_data.marker_order = vec![0xd8, 0xe1, 0xdb, 0xc0, 0xc4, 0xda, 0xd9];
+        jpeg_data.app_markers.remove(0);
+        jpeg_data.com_markers.clear();
+        jpeg_data.scans.truncate(1);
+        jpeg_data.restart_interval = None;
+        jpeg_data.inter_marker_data.clear();
+        jpeg_data.tail_data.clear();
+        jpeg_data.padding_bits = None;
+        jpeg_data.component_type = JpegComponentType::Custom;
+        jpeg_data.components.truncate(2);
+        let data = write_jbrd(&jpeg_data).unwrap();
+        assert_eq!(JpegReconstructionData::read(&data), Ok(jpeg_data));
+    }
+
+    #[test]
+    fn jbrd_invalid_data() {
+        let data = write_jbrd(&sample_data()).unwrap();
+        // The APP0, COM, inter-marker and tail data are compressed
+        let compressed_size = 17 + 8 + 3 + 8;
+        let bundle_size = data.len() - box_writer::brotli_stored(&vec![0; compressed_size]).len();
+        assert_eq!(JpegReconstructionData::read(&data[..bundle_size]),
+            Err(DecodeError::Truncated { bit_offset: bundle_size as u64 * 8, field: "JpegData.compressed_data" }));
+        let mut extra = data[..bundle_size].to_vec();
+        extra.extend(box_writer::brotli_stored(&vec![0; compressed_size + 1]));
+        assert_eq!(JpegReconstructionData::read(&extra),
+            Err(DecodeError::InvalidValue { bit_offset: bundle_size as u64 * 8, field: "JpegData.compressed_data", value: None }));
+
+        // Four quantisation tables aren't allowed
+        let mut jpeg_data = sample_data();
+        jpeg_data.quant_tables.extend((0..2).map(|index| JpegQuantTable { precision: 0, index, is_last: false }));
+        assert!(write_jbrd(&jpeg_data).is_err());
+    }
+
+    #[test]
+    fn reconstruct_jpeg_is_unsupported() {
+        let file = |boxes: &[(&[u8;4],&[u8])]| {
+            let mut data: Vec<u8> = Vec::new();
+            data.extend_from_slice(box_writer::JXL_SIGNATURE_BOX);
+            data.extend_from_slice(box_writer::JXL_FILE_TYPE_BOX);
+            for (box_type,payload) in boxes {
+                box_writer::write_box(&mut data,box_type,&[payload]).unwrap();
+            }
+            box_writer::write_codestream(&mut data,b"\xff\x0a",box_writer::JxlCodestreamLayout::Single).unwrap();
+            JxlFile::read(Cursor::new(data)).unwrap()
+        };
+        let jbrd = write_jbrd(&sample_data()).unwrap();
+        assert!(matches!(reconstruct_jpeg(&file(&[(b"jbrd",&jbrd)])), Err(DecodeError::Unsupported { field: "JpegData", .. })));
+        assert!(matches!(reconstruct_jpeg(&file(&[(b"jbrd",&jbrd[..10])])), Err(DecodeError::Truncated { .. })));
+        assert!(matches!(reconstruct_jpeg(&file(&[])), Err(DecodeError::Container { .. })));
+    }
+}
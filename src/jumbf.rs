@@ -0,0 +1,213 @@
+#![allow(dead_code)]
+
+// Content types are UUIDs, the ones defined by ISO/IEC 19566-5 are a box type followed by a common suffix
+const UUID_SUFFIX: [u8; 12] = [0x00, 0x11, 0x00, 0x10, 0x80, 0x00, 0x00, 0xaa, 0x00, 0x38, 0x9b, 0x71];
+
+const fn fourcc_uuid(fourcc: &[u8; 4]) -> [u8; 16] {
+    let mut uuid = [0u8; 16];
+    let mut i = 0;
+    while i < 16 {
+        uuid[i] = if i < 4 { fourcc[i] } else { UUID_SUFFIX[i - 4] };
+        i += 1;
+    }
+    uuid
+}
+
+pub const JUMBF_TYPE_JSON: [u8; 16] = fourcc_uuid(b"json");
+pub const JUMBF_TYPE_CBOR: [u8; 16] = fourcc_uuid(b"cbor");
+pub const JUMBF_TYPE_UUID: [u8; 16] = fourcc_uuid(b"uuid");
+pub const JUMBF_TYPE_C2PA: [u8; 16] = fourcc_uuid(b"c2pa");
+pub const JUMBF_TYPE_EMBEDDED_FILE: [u8; 16] = [0x40, 0xcb, 0x0c, 0x32, 0xbb, 0x8a, 0x48, 0x9d, 0xa7, 0x0b, 0x2a, 0xd6, 0xf4, 0x7f, 0x43, 0x69];
+
+// Splits a buffer into its (box type, payload) pairs
+fn split_boxes(mut data: &[u8]) -> Option<Vec<([u8; 4], &[u8])>> {
+    let mut boxes = Vec::new();
+    while !data.is_empty() {
+        let box_type: [u8; 4] = data.get(4..8)?.try_into().unwrap();
+        let (header_size, box_size) = match u32::from_be_bytes(data.get(0..4)?.try_into().unwrap()) {
+            0 => (8, data.len()),
+            1 => (16, usize::try_from(u64::from_be_bytes(data.get(8..16)?.try_into().unwrap())).ok()?),
+            n => (8, n as usize)
+        };
+        if box_size < header_size { return None; }
+        boxes.push((box_type, data.get(header_size..box_size)?));
+        data = &data[box_size..];
+    }
+    Some(boxes)
+}
+
+#[derive(Debug,Clone,PartialEq)]
+pub struct JumbfDescription {
+    pub content_type: [u8; 16],
+    pub requestable: bool,
+    pub label: Option<String>,
+    pub id: Option<u32>,
+    pub signature: Option<[u8; 32]>,            // SHA-256 hash of the superbox contents
+    pub private: Option<([u8; 4], Vec<u8>)>
+}
+impl JumbfDescription {
+    fn parse(data: &[u8]) -> Option<Self> {
+        let content_type: [u8; 16] = data.get(0..16)?.try_into().unwrap();
+        let toggles = *data.get(16)?;
+        let mut rest = &data[17..];
+        let label = if toggles & 0x2 == 0 { None } else {
+            let end = rest.iter().position(|&b| b == 0)?;
+            let label = String::from_utf8(rest[..end].to_vec()).ok()?;
+            rest = &rest[end+1..];
+            Some(label)
+        };
+        let id = if toggles & 0x4 == 0 { None } else {
+            let id = u32::from_be_bytes(rest.get(0..4)?.try_into().unwrap());
+            rest = &rest[4..];
+            Some(id)
+        };
+        let signature = if toggles & 0x8 == 0 { None } else {
+            let signature: [u8; 32] = rest.get(0..32)?.try_into().unwrap();
+            rest = &rest[32..];
+            Some(signature)
+        };
+        let private = if toggles & 0x10 == 0 { None } else {
+            let (box_type, payload) = *split_boxes(rest)?.first()?;
+            Some((box_type, payload.to_vec()))
+        };
+        Some(Self {
+            content_type,
+            requestable: toggles & 0x1 != 0,
+            label,
+            id,
+            signature,
+            private
+        })
+    }
+}
+
+#[derive(Debug,Clone,PartialEq)]
+pub enum JumbfContent {
+    Superbox(JumbfSuperbox),
+    Json(Vec<u8>),                                          // "json"
+    Cbor(Vec<u8>),                                          // "cbor"
+    Uuid { uuid: [u8; 16], data: Vec<u8> },                 // "uuid"
+    EmbeddedFile { media_type: Option<String>, file_name: Option<String>, data: Vec<u8> },  // "bfdb" and "bidb"
+    Other { box_type: [u8; 4], data: Vec<u8> }
+}
+
+#[derive(Debug,Clone,PartialEq)]
+pub struct JumbfSuperbox {
+    pub description: JumbfDescription,
+    pub contents: Vec<JumbfContent>
+}
+
+// Superboxes nested deeper than this are rejected, so that a small crafted file can't overflow the stack
+const MAX_NESTING_DEPTH: usize = 64;
+
+impl JumbfSuperbox {
+    // Parses the payload of a "jumb" box
+    pub fn parse(data: &[u8]) -> Option<Self> {
+        Self::parse_nested(data, 0)
+    }
+    fn parse_nested(data: &[u8], depth: usize) -> Option<Self> {
+        if depth > MAX_NESTING_DEPTH { return None; }
+        let boxes = split_boxes(data)?;
+        let (description_type, description_data) = boxes.first()?;
+        if description_type != b"jumd" { return None; }
+        let description = JumbfDescription::parse(description_data)?;
+
+        let mut contents = Vec::new();
+        let mut file_description: Option<(Option<String>, Option<String>)> = None;
+        for (box_type, payload) in &boxes[1..] {
+            contents.push(match box_type {
+                b"jumb" => JumbfContent::Superbox(JumbfSuperbox::parse_nested(payload, depth + 1)?),
+                b"json" => JumbfContent::Json(payload.to_vec()),
+                b"cbor" => JumbfContent::Cbor(payload.to_vec()),
+                b"uuid" => JumbfContent::Uuid {
+                    uuid: payload.get(0..16)?.try_into().unwrap(),
+                    data: payload[16..].to_vec()
+                },
+                b"bfdb" => {
+                    // Toggles, null-terminated media type, then an optional null-terminated file name
+                    let toggles = *payload.first()?;
+                    let mut strings = payload[1..].split(|&b| b == 0).map(|s| String::from_utf8_lossy(s).into_owned());
+                    let media_type = strings.next();
+                    let file_name = if toggles & 0x1 != 0 { strings.next() } else { None };
+                    file_description = Some((media_type, file_name));
+                    continue;
+                },
+                b"bidb" => {
+                    let (media_type, file_name) = file_description.take().unwrap_or((None, None));
+                    JumbfContent::EmbeddedFile { media_type, file_name, data: payload.to_vec() }
+                },
+                _ => JumbfContent::Other { box_type: *box_type, data: payload.to_vec() }
+            });
+        }
+        Some(Self { description, contents })
+    }
+
+    pub fn label(&self) -> Option<&str> {
+        self.description.label.as_deref()
+    }
+
+    pub fn children(&self) -> impl Iterator<Item = &JumbfSuperbox> {
+        self.contents.iter().filter_map(|content| match content {
+            JumbfContent::Superbox(superbox) => Some(superbox),
+            _ => None
+        })
+    }
+
+    // Depth-first search for a superbox with the given label, including this one
+    pub fn find(&self, label: &str) -> Option<&JumbfSuperbox> {
+        if self.label() == Some(label) { return Some(self); }
+        self.children().find_map(|child| child.find(label))
+    }
+
+    pub fn find_by_type(&self, content_type: &[u8; 16]) -> Option<&JumbfSuperbox> {
+        if &self.description.content_type == content_type { return Some(self); }
+        self.children().find_map(|child| child.find_by_type(content_type))
+    }
+}
+
+#[cfg(test)]
+mod jumbf_tests {
+    use crate::box_writer;
+    use crate::jumbf::*;
+
+    fn make_description(content_type: [u8; 16], label: &str) -> Vec<u8> {
+        let mut payload = content_type.to_vec();
+        payload.push(0x3);
+        payload.extend_from_slice(label.as_bytes());
+        payload.push(0);
+        let mut description = Vec::new();
+        box_writer::write_box(&mut description,b"jumd",&[&payload]).unwrap();
+        description
+    }
+
+    #[test]
+    fn parse_nested_superboxes() {
+        let mut manifest = make_description(JUMBF_TYPE_JSON, "c2pa.claim");
+        box_writer::write_box(&mut manifest,b"json",&[b"{}"]).unwrap();
+        let mut store = make_description(JUMBF_TYPE_C2PA, "c2pa");
+        box_writer::write_box(&mut store,b"jumb",&[&manifest]).unwrap();
+
+        let superbox = JumbfSuperbox::parse(&store).unwrap();
+        assert_eq!(superbox.label(), Some("c2pa"));
+        assert!(superbox.description.requestable);
+        let claim = superbox.find("c2pa.claim").unwrap();
+        assert_eq!(claim.description.content_type, JUMBF_TYPE_JSON);
+        assert_eq!(claim.contents, vec![JumbfContent::Json(b"{}".to_vec())]);
+        assert!(superbox.find("missing").is_none());
+    }
+
+    #[test]
+    fn nesting_depth_limit() {
+        let nested = |depth: usize| {
+            let mut superbox = make_description(JUMBF_TYPE_JSON, "inner");
+            for _ in 0..depth {
+                let mut outer = make_description(JUMBF_TYPE_JSON, "outer");
+                box_writer::write_box(&mut outer,b"jumb",&[&superbox]).unwrap();
+                superbox = outer;
+            }
+            superbox
+        };
+        assert!(JumbfSuperbox::parse(&nested(64)).unwrap().find("inner").is_some());
+        assert!(JumbfSuperbox::parse(&nested(65)).is_none());
+    }
+}
@@ -1,11 +1,22 @@
 #![allow(non_camel_case_types,dead_code,unused_imports)]
 
 use std::io::Error as IoError;
-use std::io::Read;
+use std::io::ErrorKind;
+use std::io::{Read, Seek, Write};
+use std::io::Cursor;
 use std::fmt::Debug;
+use std::borrow::Cow;
 
 use crate::bit_reader::BitStream;
 use crate::bit_reader::QuadDistributions;
+use crate::box_reader::JxlBoxReader;
+use crate::box_writer::{self, JxlCodestreamLayout};
+use crate::exif::ExifData;
+use crate::jumbf::{JumbfSuperbox, JUMBF_TYPE_C2PA};
+use crate::jxl_index::JxlFrameIndex;
+use crate::jxl_level::JxlLevel;
+use crate::jpeg_reconstruction::JpegReconstructionData;
+use crate::decode_error::DecodeError;
 
 #[derive(Debug)]
 pub struct JxlFile {
@@ -13,105 +24,174 @@ pub struct JxlFile {
 }
 
 impl JxlFile {
-    pub fn read<T: Read>(mut src: T) -> Result<Self,IoError> {
-        let mut input_data: Vec<u8> = Vec::new();
-        src.read_to_end(&mut input_data)?;
-
+    pub fn read<T: Read + Seek>(src: T) -> Result<Self,IoError> {
+        let mut box_reader = JxlBoxReader::new(src)?;
         let mut boxes: Vec<JxlBox> = Vec::new();
-        loop {
-            if input_data.len() == 0 { break; }
-            else if input_data.starts_with(&[0xff, 0x0a]) {
-                boxes.push(JxlBox {
-                    box_type: JxlBoxType::JXL_RAW,
-                    length: input_data.len() as u64,
-                    data: input_data.to_owned()
-                });
-                break;
-            } else {
-                let mut box_len: u64 = u32::from_be_bytes(input_data.as_slice()[..4].try_into().unwrap()) as u64;
-                let box_type: [u8; 4] = input_data.as_slice()[4..8].try_into().unwrap();
-                let box_data: Vec<u8>;
-                if box_len == 1 {
-                    box_len = u64::from_be_bytes(input_data.as_slice()[8..16].try_into().unwrap());
-                    box_data = input_data.drain(..(box_len as usize)).skip(16).collect::<Vec<u8>>();
-                } else {
-                    box_data = input_data.drain(..(box_len as usize)).skip(8).collect::<Vec<u8>>();
-                }
-                boxes.push(JxlBox {
-                    box_type: match &box_type {
-                        b"JXL " => JxlBoxType::JXL_SIGNATURE,
-                        b"ftyp" => JxlBoxType::JXL_FILE_TYPE,
-                        b"jxll" => JxlBoxType::JXL_LEVEL,
-                        b"jumb" => JxlBoxType::JXL_JUMBF,
-                        b"Exif" => JxlBoxType::JXL_EXIF,
-                        b"xml " => JxlBoxType::JXL_XML,
-                        b"brob" => JxlBoxType::JXL_BROTLI,
-                        b"jxli" => JxlBoxType::JXL_INDEX,
-                        b"jxlc" => JxlBoxType::JXL_CODESTREAM,
-                        b"jxlp" => JxlBoxType::JXL_PARTIAL,
-                        b"jbrd" => JxlBoxType::JXL_RECONSTRUCTION,
-                        _ => panic!("Invalid box type: {:?}",String::from_utf8(box_type.into()).unwrap())
-                    },
-                    length: box_len,
-                    data: box_data
-                });
-            } 
-        };
+        while let Some(header) = box_reader.next() {
+            boxes.push(box_reader.read_box(&header?)?);
+        }
 
-        Ok(Self { boxes: boxes })
+        Ok(Self { boxes })
     }
 
-    pub fn print_box_list(&self) -> Option<()> {
+    pub fn from_codestream(codestream: Vec<u8>) -> Self {
+        Self { boxes: vec![JxlBox {
+            box_type: JxlBoxType::JXL_RAW,
+            length: codestream.len() as u64,
+            data: codestream
+        }] }
+    }
+
+    // Writes the file as a container, whatever form it was read in. Brotli-compressed boxes are written uncompressed.
+    pub fn write<W: Write>(&self, dst: &mut W, layout: JxlCodestreamLayout) -> Result<(),IoError> {
+        let codestream = self.get_image_data()?;
+        dst.write_all(box_writer::JXL_SIGNATURE_BOX)?;
+        dst.write_all(box_writer::JXL_FILE_TYPE_BOX)?;
+        if let Some(level) = self.boxes.iter().find(|jxl_box| jxl_box.box_type == JxlBoxType::JXL_LEVEL) {
+            box_writer::write_box(dst,b"jxll",&[&level.data])?;
+        }
+        // Metadata goes first so readers can find it without scanning past the codestream, which also keeps jbrd ahead of it
         for jxl_box in &self.boxes {
-            println!("{:?}: {} bytes",jxl_box.box_type,jxl_box.length);
             use JxlBoxType as E;
             match jxl_box.box_type {
-                E::JXL_RAW => println!("Raw codestream"),
-                E::JXL_SIGNATURE => {
-                    if jxl_box.data.as_slice() == b"\x0d\x0a\x87\x0a" {
-                        println!("Valid signature");
-                    } else {
-                        panic!("Error: invalid JXL signature");
-                    }
-                },
-                E::JXL_FILE_TYPE => {
-                    if jxl_box.data.as_slice() == b"jxl \0\0\0\0jxl " {
-                        println!("Valid file type");
-                    } else {
-                        panic!("Error: invalid JXL file type");
-                    }
-                },
-                E::JXL_PARTIAL => {
-                    let box_index = u32::from_be_bytes(jxl_box.data.as_slice()[0..4].try_into().unwrap());
-                    let is_last_box: bool =  box_index & (1<<31) != 0;
-                    println!("Index: {}, is last box: {}",box_index&!(1<<31),is_last_box);
-                }
-                _ => todo!()
+                E::JXL_RAW | E::JXL_SIGNATURE | E::JXL_FILE_TYPE | E::JXL_LEVEL | E::JXL_CODESTREAM | E::JXL_PARTIAL | E::JXL_BROTLI => (),
+                box_type => box_writer::write_box(dst,&box_type.fourcc().unwrap(),&[&jxl_box.data])?
             }
         }
-        Some(())
+        box_writer::write_codestream(dst,&codestream,layout)
+    }
+
+    fn set_box(&mut self, box_type: JxlBoxType, data: Vec<u8>) {
+        self.boxes.retain(|jxl_box| jxl_box.box_type != box_type);
+        self.boxes.push(JxlBox { box_type, length: data.len() as u64 + 8, data });
     }
 
-    pub fn get_image_data(&self) -> Vec<u8> {
-        let mut output_vector = Vec::new();
+    // Takes TIFF data starting at the byte order mark
+    pub fn set_exif(&mut self, tiff_data: &[u8]) {
+        let mut data = vec![0u8; 4];
+        data.extend_from_slice(tiff_data);
+        self.set_box(JxlBoxType::JXL_EXIF,data);
+    }
+
+    pub fn set_xmp(&mut self, packet: &str) {
+        self.set_box(JxlBoxType::JXL_XML,packet.as_bytes().to_vec());
+    }
+
+    pub fn set_level(&mut self, level: JxlLevel) {
+        self.set_box(JxlBoxType::JXL_LEVEL,vec![match level { JxlLevel::Level5 => 5, JxlLevel::Level10 => 10 }]);
+    }
+
+    // Removes Exif, XMP and JUMBF boxes. JPEG reconstruction data is kept, but the original JPEG's Exif and XMP markers can't be rebuilt without them.
+    pub fn strip_metadata(&mut self) {
+        self.boxes.retain(|jxl_box| !matches!(jxl_box.box_type, JxlBoxType::JXL_EXIF | JxlBoxType::JXL_XML | JxlBoxType::JXL_JUMBF));
+    }
+
+    // Returns the TIFF data of the first Exif box, skipping the offset to the TIFF header
+    pub fn get_exif(&self) -> Option<&[u8]> {
+        let jxl_box = self.boxes.iter().find(|jxl_box| jxl_box.box_type == JxlBoxType::JXL_EXIF)?;
+        let tiff_offset = u32::from_be_bytes(jxl_box.data.get(0..4)?.try_into().unwrap()) as usize;
+        jxl_box.data.get(4usize.checked_add(tiff_offset)?..)
+    }
+
+    pub fn get_exif_data(&self) -> Option<ExifData> {
+        ExifData::parse(self.get_exif()?)
+    }
+
+    // Brotli-compressed boxes are already unwrapped when the file is read, so this covers "brob" too
+    pub fn get_xmp(&self) -> Option<&str> {
+        let jxl_box = self.boxes.iter().find(|jxl_box| jxl_box.box_type == JxlBoxType::JXL_XML)?;
+        std::str::from_utf8(&jxl_box.data).ok()
+    }
+
+    pub fn get_jumbf(&self) -> Vec<JumbfSuperbox> {
+        self.boxes.iter()
+            .filter(|jxl_box| jxl_box.box_type == JxlBoxType::JXL_JUMBF)
+            .filter_map(|jxl_box| JumbfSuperbox::parse(&jxl_box.data))
+            .collect()
+    }
+
+    pub fn get_c2pa_manifest_store(&self) -> Option<JumbfSuperbox> {
+        self.get_jumbf().into_iter().find(|superbox| superbox.description.content_type == JUMBF_TYPE_C2PA)
+    }
+
+    pub fn get_frame_index(&self) -> Option<JxlFrameIndex> {
+        let jxl_box = self.boxes.iter().find(|jxl_box| jxl_box.box_type == JxlBoxType::JXL_INDEX)?;
+        JxlFrameIndex::parse(&jxl_box.data)
+    }
+
+    // Files without a jxll box are level 5
+    pub fn get_level(&self) -> Result<JxlLevel,IoError> {
+        match self.boxes.iter().find(|jxl_box| jxl_box.box_type == JxlBoxType::JXL_LEVEL) {
+            None => Ok(JxlLevel::Level5),
+            Some(jxl_box) => match jxl_box.data.as_slice() {
+                [level] => JxlLevel::from_byte(*level).ok_or_else(|| invalid_data(&format!("invalid codestream level {}",level))),
+                _ => Err(invalid_data("jxll box must contain a single byte"))
+            }
+        }
+    }
+
+    pub fn get_jpeg_reconstruction(&self) -> Option<Result<JpegReconstructionData,DecodeError>> {
+        let jxl_box = self.boxes.iter().find(|jxl_box| jxl_box.box_type == JxlBoxType::JXL_RECONSTRUCTION)?;
+        Some(JpegReconstructionData::read(&jxl_box.data))
+    }
+
+    // Borrows the codestream when it is stored in a single box, and only copies it when it is split into parts
+    pub fn get_image_data(&self) -> Result<Cow<'_,[u8]>,IoError> {
+        let mut codestream: Option<&[u8]> = None;
+        let mut partial_boxes: Vec<(u32,bool,&[u8])> = Vec::new();
 
         for jxl_box in &self.boxes {
-            println!("{:?}: {} bytes",jxl_box.box_type,jxl_box.length);
             use JxlBoxType as E;
             match jxl_box.box_type {
-                E::JXL_RAW => {
-                    output_vector.extend(jxl_box.data.iter())
+                E::JXL_RAW => codestream = Some(&jxl_box.data),
+                E::JXL_CODESTREAM => {
+                    if codestream.is_some() {
+                        return Err(invalid_data("more than one jxlc box"));
+                    }
+                    codestream = Some(&jxl_box.data);
+                },
+                E::JXL_PARTIAL => {
+                    if jxl_box.data.len() < 4 {
+                        return Err(invalid_data("jxlp box is too short to hold an index"));
+                    }
+                    let box_index = u32::from_be_bytes(jxl_box.data.as_slice()[0..4].try_into().unwrap());
+                    partial_boxes.push((box_index&!(1<<31),box_index & (1<<31) != 0,&jxl_box.data.as_slice()[4..]));
                 },
-                E::JXL_CODESTREAM | E::JXL_PARTIAL => todo!("not implemented"),
                 _ => ()
             }
         }
-        output_vector
+
+        if partial_boxes.is_empty() { return Ok(Cow::Borrowed(codestream.unwrap_or_default())); }
+        if codestream.is_some() {
+            return Err(invalid_data("jxlc and jxlp boxes cannot be mixed"));
+        }
+        partial_boxes.sort_by_key(|(index,_,_)| *index);
+        for (expected_index,(index,is_last,_)) in partial_boxes.iter().enumerate() {
+            let expected_index = expected_index as u32;
+            if *index < expected_index {
+                return Err(invalid_data(&format!("duplicate jxlp index {}",index)));
+            } else if *index > expected_index {
+                return Err(invalid_data(&format!("missing jxlp index {}",expected_index)));
+            }
+            if *is_last && *index as usize != partial_boxes.len() - 1 {
+                return Err(invalid_data(&format!("jxlp index {} is marked as last but is followed by more boxes",index)));
+            }
+        }
+        if !partial_boxes.last().unwrap().1 {
+            return Err(invalid_data("no jxlp box is marked as last"));
+        }
+        if partial_boxes.len() == 1 {
+            return Ok(Cow::Borrowed(partial_boxes[0].2));
+        }
+        Ok(Cow::Owned(partial_boxes.iter().flat_map(|(_,_,data)| data.iter().copied()).collect()))
     }
 }
 
+fn invalid_data(message: &str) -> IoError {
+    IoError::new(ErrorKind::InvalidData,message)
+}
 
-#[derive(Debug)]
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
 pub enum JxlBoxType {
     JXL_RAW,            // If the file is a raw bitstream
     JXL_SIGNATURE,      // "JXL "
@@ -124,12 +204,189 @@ pub enum JxlBoxType {
     JXL_INDEX,          // "jxli"
     JXL_CODESTREAM,     // "jxlc"
     JXL_PARTIAL,        // "jxlp"
-    JXL_RECONSTRUCTION  // "jbrd"
+    JXL_RECONSTRUCTION, // "jbrd"
+    Unknown([u8;4])     // Any other box, such as "free" or "uuid"
+}
+impl JxlBoxType {
+    pub fn from_fourcc(box_type: &[u8;4]) -> Self {
+        match box_type {
+            b"JXL " => JxlBoxType::JXL_SIGNATURE,
+            b"ftyp" => JxlBoxType::JXL_FILE_TYPE,
+            b"jxll" => JxlBoxType::JXL_LEVEL,
+            b"jumb" => JxlBoxType::JXL_JUMBF,
+            b"Exif" => JxlBoxType::JXL_EXIF,
+            b"xml " => JxlBoxType::JXL_XML,
+            b"brob" => JxlBoxType::JXL_BROTLI,
+            b"jxli" => JxlBoxType::JXL_INDEX,
+            b"jxlc" => JxlBoxType::JXL_CODESTREAM,
+            b"jxlp" => JxlBoxType::JXL_PARTIAL,
+            b"jbrd" => JxlBoxType::JXL_RECONSTRUCTION,
+            _ => JxlBoxType::Unknown(*box_type)
+        }
+    }
+    pub fn fourcc(&self) -> Option<[u8;4]> {
+        match self {
+            JxlBoxType::JXL_RAW => None,
+            JxlBoxType::JXL_SIGNATURE => Some(*b"JXL "),
+            JxlBoxType::JXL_FILE_TYPE => Some(*b"ftyp"),
+            JxlBoxType::JXL_LEVEL => Some(*b"jxll"),
+            JxlBoxType::JXL_JUMBF => Some(*b"jumb"),
+            JxlBoxType::JXL_EXIF => Some(*b"Exif"),
+            JxlBoxType::JXL_XML => Some(*b"xml "),
+            JxlBoxType::JXL_BROTLI => Some(*b"brob"),
+            JxlBoxType::JXL_INDEX => Some(*b"jxli"),
+            JxlBoxType::JXL_CODESTREAM => Some(*b"jxlc"),
+            JxlBoxType::JXL_PARTIAL => Some(*b"jxlp"),
+            JxlBoxType::JXL_RECONSTRUCTION => Some(*b"jbrd"),
+            JxlBoxType::Unknown(box_type) => Some(*box_type)
+        }
+    }
 }
 
 #[derive(Debug)]
 pub struct JxlBox {
-    box_type: JxlBoxType,
-    data: Vec<u8>,
-    length: u64
-}
\ No newline at end of file
+    pub box_type: JxlBoxType,
+    pub data: Vec<u8>,
+    pub length: u64
+}
+impl JxlBox {
+    // A "brob" box holds the type of the wrapped box followed by its Brotli-compressed payload
+    pub fn decompress_brotli(&self) -> Result<JxlBox,IoError> {
+        if self.data.len() < 4 {
+            return Err(invalid_data("brob box is too short to hold a box type"));
+        }
+        let inner_type: [u8; 4] = self.data.as_slice()[0..4].try_into().unwrap();
+        let box_type = JxlBoxType::from_fourcc(&inner_type);
+        use JxlBoxType as E;
+        match box_type {
+            E::JXL_SIGNATURE | E::JXL_FILE_TYPE | E::JXL_LEVEL | E::JXL_BROTLI |
+            E::JXL_INDEX | E::JXL_CODESTREAM | E::JXL_PARTIAL | E::JXL_RECONSTRUCTION => {
+                return Err(invalid_data(&format!("{:?} boxes cannot be Brotli-compressed",box_type)));
+            },
+            _ => ()
+        }
+        let mut data: Vec<u8> = Vec::new();
+        brotli_decompressor::Decompressor::new(Cursor::new(&self.data.as_slice()[4..]),4096).read_to_end(&mut data)?;
+        Ok(JxlBox {
+            box_type,
+            length: data.len() as u64,
+            data
+        })
+    }
+}
+#[cfg(test)]
+mod jxl_file_tests {
+    use std::io::Cursor;
+    use crate::box_writer::{self, JxlCodestreamLayout};
+    use crate::jxl_file::{JxlFile, JxlBoxType};
+
+    #[test]
+    fn write_and_read_back() {
+        let codestream = b"\xff\x0a\x01\x02\x03\x04\x05".to_vec();
+        let mut jxl_file = JxlFile::from_codestream(codestream.clone());
+        jxl_file.set_exif(b"II*\0\x08\0\0\0\0\0");
+        jxl_file.set_xmp("<x:xmpmeta/>");
+        for layout in [JxlCodestreamLayout::Single, JxlCodestreamLayout::Partial { part_size: 3 }] {
+            let mut output: Vec<u8> = Vec::new();
+            jxl_file.write(&mut output,layout).unwrap();
+            let read_back = JxlFile::read(Cursor::new(output)).unwrap();
+            assert_eq!(*read_back.get_image_data().unwrap(),codestream);
+            assert_eq!(read_back.get_exif(),Some(&b"II*\0\x08\0\0\0\0\0"[..]));
+            assert_eq!(read_back.get_xmp(),Some("<x:xmpmeta/>"));
+        }
+
+        jxl_file.strip_metadata();
+        let mut output: Vec<u8> = Vec::new();
+        jxl_file.write(&mut output,JxlCodestreamLayout::Single).unwrap();
+        let read_back = JxlFile::read(Cursor::new(output)).unwrap();
+        assert_eq!(read_back.get_exif(),None);
+        assert_eq!(read_back.get_xmp(),None);
+    }
+
+    const LAST: u32 = 1 << 31;
+
+    // Builds a container from jxlp boxes with the given raw index fields, and returns the reassembly error if any
+    fn reassemble(parts: &[(u32,&[u8])]) -> Result<Vec<u8>,String> {
+        let mut data: Vec<u8> = Vec::new();
+        data.extend_from_slice(box_writer::JXL_SIGNATURE_BOX);
+        data.extend_from_slice(box_writer::JXL_FILE_TYPE_BOX);
+        for (index,part) in parts {
+            box_writer::write_box(&mut data,b"jxlp",&[&index.to_be_bytes(),part]).unwrap();
+        }
+        let jxl_file = JxlFile::read(Cursor::new(data)).unwrap();
+        jxl_file.get_image_data().map(|codestream| codestream.to_vec()).map_err(|error| error.to_string())
+    }
+
+    #[test]
+    fn reassemble_partial_boxes() {
+        assert_eq!(reassemble(&[(1 | LAST,b"\x03\x04"),(0,b"\xff\x0a")]),Ok(b"\xff\x0a\x03\x04".to_vec()));
+        assert_eq!(reassemble(&[(0,b"\xff"),(2 | LAST,b"\x0a")]),Err("missing jxlp index 1".to_string()));
+        assert_eq!(reassemble(&[(0,b"\xff"),(0,b"\xff"),(1 | LAST,b"\x0a")]),Err("duplicate jxlp index 0".to_string()));
+        assert_eq!(reassemble(&[(0,b"\xff"),(1,b"\x0a")]),Err("no jxlp box is marked as last".to_string()));
+        assert_eq!(reassemble(&[(LAST,b"\xff"),(1 | LAST,b"\x0a")]),Err("jxlp index 0 is marked as last but is followed by more boxes".to_string()));
+    }
+
+    #[test]
+    fn reject_invalid_partial_boxes() {
+        let mut data: Vec<u8> = Vec::new();
+        data.extend_from_slice(box_writer::JXL_SIGNATURE_BOX);
+        box_writer::write_box(&mut data,b"jxlp",&[b"\0\0"]).unwrap();
+        let jxl_file = JxlFile::read(Cursor::new(data)).unwrap();
+        assert_eq!(jxl_file.get_image_data().unwrap_err().to_string(),"jxlp box is too short to hold an index");
+
+        let mut data: Vec<u8> = Vec::new();
+        data.extend_from_slice(box_writer::JXL_SIGNATURE_BOX);
+        box_writer::write_box(&mut data,b"jxlc",&[b"\xff\x0a"]).unwrap();
+        box_writer::write_box(&mut data,b"jxlp",&[&LAST.to_be_bytes(),b"\x00"]).unwrap();
+        let jxl_file = JxlFile::read(Cursor::new(data)).unwrap();
+        assert_eq!(jxl_file.get_image_data().unwrap_err().to_string(),"jxlc and jxlp boxes cannot be mixed");
+    }
+
+    #[test]
+    fn read_brotli_boxes() {
+        let mut data: Vec<u8> = Vec::new();
+        data.extend_from_slice(box_writer::JXL_SIGNATURE_BOX);
+        data.extend_from_slice(box_writer::JXL_FILE_TYPE_BOX);
+        box_writer::write_box(&mut data,b"brob",&[b"Exif",&box_writer::brotli_stored(b"\0\0\0\0II*\0\x08\0\0\0\0\0")]).unwrap();
+        box_writer::write_box(&mut data,b"brob",&[b"xml ",&box_writer::brotli_stored(b"<x:xmpmeta/>")]).unwrap();
+        box_writer::write_codestream(&mut data,b"\xff\x0a",JxlCodestreamLayout::Single).unwrap();
+        let jxl_file = JxlFile::read(Cursor::new(data)).unwrap();
+        assert!(jxl_file.boxes.iter().all(|jxl_box| jxl_box.box_type != JxlBoxType::JXL_BROTLI));
+        assert_eq!(jxl_file.get_exif(),Some(&b"II*\0\x08\0\0\0\0\0"[..]));
+        assert_eq!(jxl_file.get_xmp(),Some("<x:xmpmeta/>"));
+
+        // Boxes the decoder needs before reading the codestream can't be compressed
+        let mut data: Vec<u8> = Vec::new();
+        data.extend_from_slice(box_writer::JXL_SIGNATURE_BOX);
+        box_writer::write_box(&mut data,b"brob",&[b"jxlc",&box_writer::brotli_stored(b"\xff\x0a")]).unwrap();
+        assert!(JxlFile::read(Cursor::new(data)).is_err());
+    }
+
+    #[test]
+    fn keep_unknown_boxes() {
+        let mut data: Vec<u8> = Vec::new();
+        data.extend_from_slice(box_writer::JXL_SIGNATURE_BOX);
+        data.extend_from_slice(box_writer::JXL_FILE_TYPE_BOX);
+        box_writer::write_box(&mut data,b"free",&[b"\0\0\0"]).unwrap();
+        box_writer::write_box(&mut data,b"uuid",&[&[0x11; 16],b"payload"]).unwrap();
+        box_writer::write_codestream(&mut data,b"\xff\x0a",JxlCodestreamLayout::Single).unwrap();
+        box_writer::write_box(&mut data,b"abcd",&[b"vendor data"]).unwrap();
+        let jxl_file = JxlFile::read(Cursor::new(data)).unwrap();
+        let unknown_boxes = |jxl_file: &JxlFile| -> Vec<([u8;4],Vec<u8>)> {
+            jxl_file.boxes.iter().filter_map(|jxl_box| match jxl_box.box_type {
+                JxlBoxType::Unknown(box_type) => Some((box_type,jxl_box.data.clone())),
+                _ => None
+            }).collect()
+        };
+        let mut uuid_payload = vec![0x11; 16];
+        uuid_payload.extend_from_slice(b"payload");
+        let expected = vec![(*b"free",b"\0\0\0".to_vec()),(*b"uuid",uuid_payload),(*b"abcd",b"vendor data".to_vec())];
+        assert_eq!(unknown_boxes(&jxl_file),expected);
+        assert_eq!(*jxl_file.get_image_data().unwrap(),*b"\xff\x0a");
+
+        // They are written back out unchanged
+        let mut output: Vec<u8> = Vec::new();
+        jxl_file.write(&mut output,JxlCodestreamLayout::Single).unwrap();
+        assert_eq!(unknown_boxes(&JxlFile::read(Cursor::new(output)).unwrap()),expected);
+    }
+}
@@ -1,47 +1,50 @@
 #![allow(dead_code,unused_imports,unused_variables)]
 
-use crate::common::{ImageSize,unpack_signed};
 use crate::jxl_image::JxlImageMetadata;
 use crate::bit_reader::QuadDistributions::*;
 use crate::bit_reader::BitStream;
+use crate::bundle::{bundle, FieldValue};
+use crate::decode_error::DecodeError;
 
-#[derive(Debug,PartialEq,Eq)]
-enum JxlFrameType {
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub enum JxlFrameType {
     RegularFrame,
     LFFrame,
     ReferenceOnly,
     SkipProgressive
 }
-impl From<u8> for JxlFrameType {
-    fn from(value: u8) -> Self {
+impl FieldValue for JxlFrameType {
+    fn from_raw(raw: u64) -> Option<Self> {
         use JxlFrameType as E;
-        match value {
-            0 => E::RegularFrame,
-            1 => E::LFFrame,
-            2 => E::ReferenceOnly,
-            3 => E::SkipProgressive,
-            _ => unreachable!()
+        match raw {
+            0 => Some(E::RegularFrame),
+            1 => Some(E::LFFrame),
+            2 => Some(E::ReferenceOnly),
+            3 => Some(E::SkipProgressive),
+            _ => None
         }
     }
+    fn to_raw(&self) -> u64 { *self as u64 }
 }
 
-#[derive(Debug,PartialEq,Eq)]
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
 enum JxlFrameEncoding {
     VarDCT, 
     Modular
 }
-impl From<u8> for JxlFrameEncoding {
-    fn from(value: u8) -> Self {
+impl FieldValue for JxlFrameEncoding {
+    fn from_raw(raw: u64) -> Option<Self> {
         use JxlFrameEncoding as E;
-        match value {
-            0 => E::VarDCT,
-            1 => E::Modular,
-            _ => unreachable!()
+        match raw {
+            0 => Some(E::VarDCT),
+            1 => Some(E::Modular),
+            _ => None
         }
     }
+    fn to_raw(&self) -> u64 { *self as u64 }
 }
 
-#[derive(Debug)]
+#[derive(Debug,Clone,PartialEq)]
 struct JxlFrameFlags {
     use_noise: bool,
     use_patches: bool,
@@ -60,52 +63,40 @@ impl From<u64> for JxlFrameFlags {
         }
     }
 }
-
-#[derive(Debug)]
-pub struct JxlFramePasses {
-    pass_count: u8,
-    num_ds: u8,
-    shifts: Vec<u8>,
-    downsample: Vec<u8>,
-    last_pass: Vec<u8>
+impl FieldValue for JxlFrameFlags {
+    fn from_raw(raw: u64) -> Option<Self> { Some(Self::from(raw)) }
+    fn to_raw(&self) -> u64 {
+        (self.use_noise as u64) | (self.use_patches as u64) << 1 | (self.use_splines as u64) << 4
+            | (self.use_lf_frame as u64) << 5 | (!self.use_adaptive_lf_smoothing as u64) << 7
+    }
 }
-impl JxlFramePasses {
-    pub fn read(bitstream: &mut BitStream) -> Option<Self> {
-        let pass_count = bitstream.read_quad_u32(RawValue(1), RawValue(2), RawValue(3), BitCountWithOffset(3, 4))? as u8;
-        let num_ds = if pass_count == 1 { 0 } else { bitstream.read_quad_u32(RawValue(0), RawValue(1), RawValue(2), BitCountWithOffset(1, 3))? }  as u8; 
-        let mut shifts: Vec<u8> = Vec::with_capacity(pass_count as usize);
-        let mut downsample: Vec<u8> = Vec::with_capacity(num_ds as usize);
-        let mut last_pass: Vec<u8> = Vec::with_capacity(num_ds as usize);
-        for _ in 0..(pass_count-1) {
-            shifts.push(bitstream.read_u8(2)?);
-        }
-        shifts.push(0);
-        for _ in 0..num_ds {
-            downsample.push(bitstream.read_quad_u32(RawValue(1), RawValue(2), RawValue(4), RawValue(8))? as u8);
-        }
-        for _ in 0..num_ds {
-            last_pass.push(bitstream.read_quad_u32(RawValue(0), RawValue(1), RawValue(2), BitCount(3))? as u8);
-        }
 
-        Some(JxlFramePasses {
-            pass_count,
-            num_ds,
-            shifts,
-            downsample,
-            last_pass
-        })
+bundle! {
+    #[derive(Debug,Clone,PartialEq)]
+    pub struct JxlFramePasses as "FramePasses" {
+        pass_count: u8 = U32(RawValue(1), RawValue(2), RawValue(3), BitCountWithOffset(3, 4)), default 1;
+        num_ds: u8 = U32(RawValue(0), RawValue(1), RawValue(2), BitCountWithOffset(1, 3)) if pass_count != 1, default 0, valid num_ds < pass_count;
+        // The last pass always has a shift of 0, so it isn't stored
+        shifts: Vec<u8> = [(pass_count as usize).saturating_sub(1)] Bits(2), default Vec::new();
+        downsample: Vec<u8> = [num_ds] U32(RawValue(1), RawValue(2), RawValue(4), RawValue(8)), default Vec::new();
+        last_pass: Vec<u8> = [num_ds] U32(RawValue(0), RawValue(1), RawValue(2), BitCount(3)), default Vec::new();
     }
 }
 
-#[derive(Debug)]
-pub struct JxlFrameCropInfo {
-    pub width: u32,
-    pub height: u32,
-    pub x0: i32,
-    pub y0: i32    
+bundle! {
+    #[derive(Debug,Clone,PartialEq)]
+    pub struct JxlFrameCropInfo as "CropInfo" (frame_type: JxlFrameType) {
+        // Reference-only frames are always stored at the origin
+        pub x0: i32 = U32(BitCount(8), BitCountWithOffset(11, 8), BitCountWithOffset(14, 2304), BitCountWithOffset(30, 18688))
+            if frame_type != JxlFrameType::ReferenceOnly, default 0;
+        pub y0: i32 = U32(BitCount(8), BitCountWithOffset(11, 8), BitCountWithOffset(14, 2304), BitCountWithOffset(30, 18688))
+            if frame_type != JxlFrameType::ReferenceOnly, default 0;
+        pub width: u32 = U32(BitCount(8), BitCountWithOffset(11, 8), BitCountWithOffset(14, 2304), BitCountWithOffset(30, 18688)), default 0;
+        pub height: u32 = U32(BitCount(8), BitCountWithOffset(11, 8), BitCountWithOffset(14, 2304), BitCountWithOffset(30, 18688)), default 0;
+    }
 }
 
-#[derive(Debug,PartialEq)]
+#[derive(Debug,Clone,Copy,PartialEq)]
 enum JxlBlendingMode {
     Replace, 
     Add,
@@ -113,163 +104,202 @@ enum JxlBlendingMode {
     MulAdd,
     Mul
 }
-impl From<u8> for JxlBlendingMode {
-    fn from(value: u8) -> Self {
+impl FieldValue for JxlBlendingMode {
+    fn from_raw(raw: u64) -> Option<Self> {
         use JxlBlendingMode as E;
-        match value {
-            0 => E::Replace,
-            1 => E::Add,
-            2 => E::Blend,
-            3 => E::MulAdd,
-            4 => E::Mul,
-            _ => unreachable!()
+        match raw {
+            0 => Some(E::Replace),
+            1 => Some(E::Add),
+            2 => Some(E::Blend),
+            3 => Some(E::MulAdd),
+            4 => Some(E::Mul),
+            _ => None
         }
     }
+    fn to_raw(&self) -> u64 { *self as u64 }
 }
 
-
-#[derive(Debug)]
-pub struct JxlBlendingInfo {
-    blend_mode: JxlBlendingMode,
-    alpha_channel: u8,
-    clamp: bool,
-    source: u8
+// The same bundle is used for the colour channels and for each extra channel
+bundle! {
+    #[derive(Debug,Clone,PartialEq)]
+    pub struct JxlBlendingInfo as "BlendingInfo" (image_metadata: &JxlImageMetadata) {
+        blend_mode: JxlBlendingMode = U32(RawValue(0), RawValue(1), RawValue(2), BitCountWithOffset(2, 3)), default JxlBlendingMode::Replace;
+        alpha_channel: u8 = U32(RawValue(0), RawValue(1), RawValue(2), BitCountWithOffset(3, 3))
+            if !image_metadata.extra_channels.is_empty() && matches!(blend_mode, JxlBlendingMode::Blend | JxlBlendingMode::MulAdd), default 0;
+        clamp: bool = Bool
+            if (!image_metadata.extra_channels.is_empty() && matches!(blend_mode, JxlBlendingMode::Blend | JxlBlendingMode::MulAdd))
+                || blend_mode == JxlBlendingMode::Mul, default false;
+        // Cropped frames, which would also store it, aren't supported
+        source: u8 = Bits(2) if blend_mode != JxlBlendingMode::Replace, default 0;
+    }
 }
 
-#[derive(Debug)]
-pub struct JxlFrameHeader {
-    frame_type: JxlFrameType,
-    frame_encoding: JxlFrameEncoding,
-    flags: JxlFrameFlags,
-    ycbcr: bool,
-    jpeg_upscaling: [u8;3],
-    upsampling: u8,
-    ec_upscaling: Vec<u8>,
-    modular_group_size: Option<u16>,
-    x_qm_scale: u8,
-    b_qm_scale: u8,
-    passes: JxlFramePasses,
-    lf_level: Option<u8>,
-    crop_info: Option<JxlFrameCropInfo>,
-    blending_info: JxlBlendingInfo,
-    ec_blending_info: Vec<JxlBlendingInfo>,
-    duration: u32,
-    timecode: u32,
-    is_last: bool
+bundle! {
+    #[derive(Debug,Clone,PartialEq)]
+    pub struct JxlFrameHeader as "FrameHeader" (image_metadata: &JxlImageMetadata) with all_default {
+        frame_type: JxlFrameType = Bits(2), default JxlFrameType::RegularFrame,
+            supported frame_type == JxlFrameType::RegularFrame => "Frames other than regular frames";
+        frame_encoding: JxlFrameEncoding = Bits(1), default JxlFrameEncoding::VarDCT,
+            supported frame_encoding == JxlFrameEncoding::Modular => "VarDCT frames";
+        flags: JxlFrameFlags = U64, default JxlFrameFlags::from(0);
+        ycbcr: bool = Bool if !image_metadata.xyb_encoded, default false;
+        jpeg_upscaling: [u8;3] = [3] Bits(2) if ycbcr && !flags.use_lf_frame, default [1,1,1];
+        pub upsampling: u8 = U32(RawValue(1), RawValue(2), RawValue(4), RawValue(8)) if !flags.use_lf_frame, default 1;
+        pub ec_upscaling: Vec<u8> = [image_metadata.extra_channels.len()] U32(RawValue(1), RawValue(2), RawValue(4), RawValue(8))
+            if !flags.use_lf_frame, default vec![1; image_metadata.extra_channels.len()];
+        // Stored as group_size_shift in 2 bits, which is the same as choosing one of the four sizes
+        modular_group_size: u16 = U32(RawValue(128), RawValue(256), RawValue(512), RawValue(1024))
+            if frame_encoding == JxlFrameEncoding::Modular, default 256;
+        x_qm_scale: u8 = Bits(3) if image_metadata.xyb_encoded && frame_encoding == JxlFrameEncoding::VarDCT,
+            default if image_metadata.xyb_encoded && frame_encoding == JxlFrameEncoding::VarDCT { 3 } else { 2 };
+        b_qm_scale: u8 = Bits(3) if image_metadata.xyb_encoded && frame_encoding == JxlFrameEncoding::VarDCT, default 2;
+        passes: JxlFramePasses = Bundle() if frame_type != JxlFrameType::ReferenceOnly, default JxlFramePasses::default();
+        // 1 + u(2), written the same way as choosing one of four values
+        pub lf_level: u8 = U32(RawValue(1), RawValue(2), RawValue(3), RawValue(4)) if frame_type == JxlFrameType::LFFrame, default 0;
+        pub crop_info: Option<JxlFrameCropInfo> = Optional(frame_type) if frame_type != JxlFrameType::LFFrame, default None,
+            supported crop_info.is_none() => "Cropped frames";
+        blending_info: JxlBlendingInfo = Bundle(image_metadata)
+            if matches!(frame_type, JxlFrameType::RegularFrame | JxlFrameType::SkipProgressive), default JxlBlendingInfo::defaults(image_metadata);
+        ec_blending_info: Vec<JxlBlendingInfo> = [image_metadata.extra_channels.len()] Bundle(image_metadata)
+            if matches!(frame_type, JxlFrameType::RegularFrame | JxlFrameType::SkipProgressive),
+            default (0..image_metadata.extra_channels.len()).map(|_| JxlBlendingInfo::defaults(image_metadata)).collect();
+        duration: u32 = U32(RawValue(0), RawValue(1), BitCount(8), BitCount(32))
+            if matches!(frame_type, JxlFrameType::RegularFrame | JxlFrameType::SkipProgressive) && image_metadata.animation_info.is_some(), default 0;
+        timecode: u32 = Bits(32)
+            if matches!(frame_type, JxlFrameType::RegularFrame | JxlFrameType::SkipProgressive) && image_metadata.animation_info.as_ref().is_some_and(|a| a.has_timecodes),
+            default 0;
+        is_last: bool = Bool if matches!(frame_type, JxlFrameType::RegularFrame | JxlFrameType::SkipProgressive),
+            default frame_type == JxlFrameType::RegularFrame;
+    }
 }
 
 #[derive(Debug)]
 pub struct JxlFrame {
     pub header: JxlFrameHeader
 }
-impl JxlFrameHeader {
-    pub fn read(bitstream: &mut BitStream, image_metadata: &JxlImageMetadata) -> Option<Self> {
-        let all_default = bitstream.read_bool()?;
-        let frame_type = if all_default {JxlFrameType::RegularFrame} else {JxlFrameType::from(bitstream.read_u8(2)?)};
-        let frame_encoding = if all_default {JxlFrameEncoding::VarDCT} else {JxlFrameEncoding::from(bitstream.read_u8(1)?)};
-        if frame_type != JxlFrameType::RegularFrame {
-            todo!("Only regular frames are supported")
-        }
-        if frame_encoding != JxlFrameEncoding::Modular {
-            todo!("Only Modular frames are supported")
-        }
-        let flags = JxlFrameFlags::from(if all_default { 0u64 } else {bitstream.read_var_u64()?});
-        let ycbcr = if all_default || image_metadata.xyb_encoded { false } else { bitstream.read_bool()? };
-        let jpeg_upscaling = if !ycbcr || flags.use_lf_frame { [1,1,1] } else {[
-            bitstream.read_u8(2)?,
-            bitstream.read_u8(2)?,
-            bitstream.read_u8(2)?
-        ]};
-        let upsampling = if all_default || flags.use_lf_frame { 1 } else {bitstream.read_quad_u32(RawValue(1), RawValue(2), RawValue(4), RawValue(8))?} as u8;
-        let mut ec_upscaling = Vec::new();
-        for _ in 0..image_metadata.extra_channels.len() {
-            ec_upscaling.push(bitstream.read_quad_u32(RawValue(1), RawValue(2), RawValue(4), RawValue(8))? as u8);
-        }
-        let modular_group_size = if frame_encoding != JxlFrameEncoding::Modular { None } else { Some(128 << bitstream.read_u16(2)?) };
-        let d_xqms = if image_metadata.xyb_encoded && frame_encoding == JxlFrameEncoding::VarDCT {3} else {2};
-        let x_qm_scale = if all_default || !image_metadata.xyb_encoded || frame_encoding != JxlFrameEncoding::VarDCT { d_xqms } else {bitstream.read_u8(3)?};
-        let b_qm_scale = if all_default || !image_metadata.xyb_encoded || frame_encoding != JxlFrameEncoding::VarDCT { 2 } else {bitstream.read_u8(3)?};
-        
-        let passes = JxlFramePasses::read(bitstream)?;
-
-        let lf_level = if frame_type == JxlFrameType::LFFrame {Some(1 + bitstream.read_u8(2)?)} else {None};
-
-        let crop_info = if all_default || flags.use_lf_frame || !(bitstream.read_bool()?) {None} else {
-            let ux0 = bitstream.read_quad_u32(BitCount(8), BitCountWithOffset(11, 8), BitCountWithOffset(14, 2304), BitCountWithOffset(30, 18688))?;
-            let uy0 = bitstream.read_quad_u32(BitCount(8), BitCountWithOffset(11, 8), BitCountWithOffset(14, 2304), BitCountWithOffset(30, 18688))?;
-            let width = bitstream.read_quad_u32(BitCount(8), BitCountWithOffset(11, 8), BitCountWithOffset(14, 2304), BitCountWithOffset(30, 18688))?;
-            let height = bitstream.read_quad_u32(BitCount(8), BitCountWithOffset(11, 8), BitCountWithOffset(14, 2304), BitCountWithOffset(30, 18688))?;
-            Some(JxlFrameCropInfo {
-                x0: unpack_signed(ux0),
-                y0: unpack_signed(uy0),
-                width,
-                height
-            })
-            ;todo!("Cropped images are not supported");
-        };
-
-        let normal_frame = frame_type == JxlFrameType::RegularFrame || frame_type == JxlFrameType::SkipProgressive;
+impl JxlFrame {
+    pub fn read(bitstream: &mut BitStream, image_metadata: &JxlImageMetadata) -> Result<Self,DecodeError> {
+        Ok(Self {
+            header: JxlFrameHeader::read(bitstream, image_metadata)?
+        })
+    }
+}
+#[cfg(test)]
+mod jxl_frame_tests {
+    use crate::bit_reader::BitStream;
+    use crate::bit_writer::BitWriter;
+    use crate::jxl_frame::*;
+    use crate::jxl_image::*;
 
-        let blend_mode = JxlBlendingMode::from(bitstream.read_quad_u32(RawValue(0), RawValue(1), RawValue(2), BitCountWithOffset(2, 3))? as u8);
+    fn metadata(xyb_encoded: bool, animation_info: Option<JxlAnimationInfo>) -> JxlImageMetadata {
+        let mut image_metadata = JxlImageMetadata::default();
+        image_metadata.animation_info = animation_info;
+        image_metadata.xyb_encoded = xyb_encoded;
+        image_metadata
+    }
 
-        let resets_canvas = blend_mode == JxlBlendingMode::Replace;
+    #[test]
+    fn frame_header_round_trip() {
+        for index in 0..64u32 {
+            let animation_info = (index % 3 != 0).then_some(JxlAnimationInfo { tps_numerator: 100, tps_denominator: 1, loop_count: 0, has_timecodes: index % 3 == 2 });
+            let image_metadata = metadata(index % 2 == 0, animation_info);
+            let flags = JxlFrameFlags::from([0, 0x1, 0x12, 0x80, 0x93][index as usize % 5]);
+            let ycbcr = !image_metadata.xyb_encoded && index % 4 == 1;
+            let pass_count = [1, 2, 3, 5][index as usize % 4];
+            let num_ds = if pass_count == 1 { 0 } else { index as u8 % pass_count };
+            let blend_mode = [JxlBlendingMode::Replace, JxlBlendingMode::Add, JxlBlendingMode::Blend, JxlBlendingMode::Mul][index as usize % 4];
+            let header = JxlFrameHeader {
+                frame_type: JxlFrameType::RegularFrame,
+                frame_encoding: JxlFrameEncoding::Modular,
+                flags,
+                ycbcr,
+                jpeg_upscaling: if ycbcr { [0, 1, 2] } else { [1, 1, 1] },
+                upsampling: [1, 2, 4, 8][index as usize % 4],
+                ec_upscaling: Vec::new(),
+                modular_group_size: 128 << (index % 4),
+                x_qm_scale: 2,
+                b_qm_scale: 2,
+                passes: JxlFramePasses {
+                    pass_count,
+                    num_ds,
+                    shifts: (0..pass_count - 1).map(|pass| pass % 4).collect(),
+                    downsample: [1, 2, 4, 8][..num_ds as usize].to_vec(),
+                    last_pass: (0..num_ds).collect()
+                },
+                lf_level: 0,
+                crop_info: None,
+                blending_info: JxlBlendingInfo {
+                    blend_mode,
+                    alpha_channel: 0,
+                    clamp: false,
+                    source: if blend_mode == JxlBlendingMode::Replace { 0 } else { index as u8 % 4 }
+                },
+                ec_blending_info: Vec::new(),
+                duration: if image_metadata.animation_info.is_some() { index * 7 } else { 0 },
+                timecode: if index % 3 == 2 { 0x0102_0304 + index } else { 0 },
+                is_last: index % 2 == 1
+            };
+            let mut writer = BitWriter::new();
+            header.write(&mut writer, &image_metadata).unwrap();
+            let data = writer.finish();
+            assert_eq!(JxlFrameHeader::read(&mut BitStream::new(&data), &image_metadata), Ok(header));
+        }
+    }
 
-        let blend_alpha_channel = if image_metadata.extra_channels.len() == 0 || !(blend_mode==JxlBlendingMode::Blend || blend_mode==JxlBlendingMode::MulAdd) {0} else {bitstream.read_quad_u32(RawValue(0), RawValue(1), RawValue(2), BitCountWithOffset(3, 3))?} as u8;
-        let blend_clamp = if image_metadata.extra_channels.len() == 0 || !(blend_mode==JxlBlendingMode::Blend || blend_mode==JxlBlendingMode::MulAdd || blend_mode==JxlBlendingMode::Mul) {false} else {bitstream.read_bool()?};
-        let blend_source = if resets_canvas { 0 } else {bitstream.read_u8(2)?};
+    #[test]
+    fn extra_channel_blending_source() {
+        let mut image_metadata = metadata(true, None);
+        image_metadata.extra_channels = vec![JxlExtraChannel {}];
+        // Each bundle stores a source when its own mode isn't Replace, whatever the mode of the colour channels is
+        let mut writer = BitWriter::new();
+        writer.write(2, 2);
+        writer.write(2, 0);
+        writer.write_bool(true);
+        writer.write(2, 3);
+        writer.write(2, 0);
+        let data = writer.finish();
+        let mut bitstream = BitStream::new(&data);
+        let blend = JxlBlendingInfo { blend_mode: JxlBlendingMode::Blend, alpha_channel: 0, clamp: true, source: 3 };
+        assert_eq!(JxlBlendingInfo::read(&mut bitstream, &image_metadata), Ok(blend.clone()));
+        assert_eq!(JxlBlendingInfo::read(&mut bitstream, &image_metadata), Ok(JxlBlendingInfo::defaults(&image_metadata)));
+        assert_eq!(bitstream.bit_position(), 9);
 
-        let blending_info = JxlBlendingInfo {
-            blend_mode,
-            alpha_channel: blend_alpha_channel,
-            clamp: blend_clamp,
-            source: blend_source
-        };
+        // Mul stores clamp even without extra channels
+        let mut writer = BitWriter::new();
+        writer.write(2, 3);
+        writer.write(2, 1);
+        writer.write_bool(true);
+        writer.write(2, 2);
+        let data = writer.finish();
+        let mut bitstream = BitStream::new(&data);
+        let mul = JxlBlendingInfo { blend_mode: JxlBlendingMode::Mul, alpha_channel: 0, clamp: true, source: 2 };
+        assert_eq!(JxlBlendingInfo::read(&mut bitstream, &metadata(true, None)), Ok(mul));
+        assert_eq!(bitstream.bit_position(), 7);
 
-        let mut ec_blending_info = Vec::new();
-        for _ in 0..image_metadata.extra_channels.len() {
-            let blend_mode = JxlBlendingMode::from(bitstream.read_quad_u32(RawValue(0), RawValue(1), RawValue(2), BitCountWithOffset(2, 3))? as u8);
-            let alpha_channel = if image_metadata.extra_channels.len() == 0 || !(blend_mode==JxlBlendingMode::Blend || blend_mode==JxlBlendingMode::MulAdd) {0} else {bitstream.read_quad_u32(RawValue(0), RawValue(1), RawValue(2), BitCountWithOffset(3, 3))?} as u8;
-            let clamp = if image_metadata.extra_channels.len() == 0 || !(blend_mode==JxlBlendingMode::Blend || blend_mode==JxlBlendingMode::MulAdd || blend_mode==JxlBlendingMode::Mul) {false} else {bitstream.read_bool()?};
-            let source = if resets_canvas { 0 } else {bitstream.read_u8(2)?};
-            ec_blending_info.push(JxlBlendingInfo {
-                blend_mode,
-                alpha_channel,
-                clamp,
-                source
-            });
+        for (colour_mode, channel_mode) in [(JxlBlendingMode::Replace, JxlBlendingMode::Add), (JxlBlendingMode::Add, JxlBlendingMode::Replace)] {
+            let mut header = JxlFrameHeader::defaults(&image_metadata);
+            header.frame_encoding = JxlFrameEncoding::Modular;
+            header.x_qm_scale = 2;
+            header.blending_info = JxlBlendingInfo { blend_mode: colour_mode, alpha_channel: 0, clamp: false, source: (colour_mode != JxlBlendingMode::Replace) as u8 };
+            header.ec_blending_info = vec![JxlBlendingInfo { blend_mode: channel_mode, alpha_channel: 0, clamp: false, source: 2 * (channel_mode != JxlBlendingMode::Replace) as u8 }];
+            let mut writer = BitWriter::new();
+            header.write(&mut writer, &image_metadata).unwrap();
+            let data = writer.finish();
+            assert_eq!(JxlFrameHeader::read(&mut BitStream::new(&data), &image_metadata), Ok(header));
         }
+    }
 
-        let duration = if all_default || !normal_frame || image_metadata.animation_info.is_none() { 0 } else {bitstream.read_quad_u32(RawValue(0), RawValue(1), BitCount(8), BitCount(32))?};
-        let timecode = if all_default || !normal_frame || !match &image_metadata.animation_info {None=>false,Some(a)=>a.has_timecodes} {0} else {bitstream.read_u32(32)?};
-        let is_last = if all_default || !normal_frame {frame_type==JxlFrameType::RegularFrame} else { bitstream.read_bool()?};
+    #[test]
+    fn reference_only_crop() {
+        // Only the size is stored, in 10 bits each
+        let crop_info = JxlFrameCropInfo { x0: 0, y0: 0, width: 100, height: 200 };
+        let mut writer = BitWriter::new();
+        crop_info.write(&mut writer, JxlFrameType::ReferenceOnly).unwrap();
+        assert_eq!(writer.bit_position(), 20);
+        let data = writer.finish();
+        assert_eq!(JxlFrameCropInfo::read(&mut BitStream::new(&data), JxlFrameType::ReferenceOnly), Ok(crop_info.clone()));
 
-        Some(JxlFrameHeader {
-            frame_type,
-            frame_encoding,
-            flags,
-            ycbcr,
-            jpeg_upscaling,
-            upsampling,
-            ec_upscaling,
-            modular_group_size,
-            x_qm_scale,
-            b_qm_scale,
-            passes,
-            lf_level,
-            crop_info,
-            blending_info,
-            ec_blending_info,
-            duration,
-            timecode,
-            is_last
-        })
+        let offset = JxlFrameCropInfo { x0: -3, ..crop_info };
+        assert!(offset.write(&mut BitWriter::new(), JxlFrameType::ReferenceOnly).is_err());
     }
 }
-
-impl JxlFrame {
-    pub fn read(bitstream: &mut BitStream, image_metadata: &JxlImageMetadata) -> Option<Self> {
-        Some(Self {
-            header: JxlFrameHeader::read(bitstream, image_metadata)?
-        })
-    }
-}
\ No newline at end of file
@@ -1,93 +1,104 @@
 #![allow(dead_code,unused_variables)]
 
+use std::io::Error as IoError;
+
 use crate::bit_reader::BitStream;
 use crate::bit_reader::QuadDistributions::*;
 use crate::common::ImageSize;
+use crate::decode_error::{DecodeError, DecodeResultExt};
+use crate::bit_writer::{BitWriter, unrepresentable};
+use crate::bundle::{bundle, FieldValue};
+
+// The width for each of the aspect ratios that ImageSize can signal instead of storing it
+fn width_from_ratio(height: u32, ratio: u8) -> u32 {
+    let (numerator, denominator): (u64, u64) = match ratio {
+        1 => (1, 1),
+        2 => (6, 5),
+        3 => (4, 3),
+        4 => (3, 2),
+        5 => (16, 9),
+        6 => (5, 4),
+        7 => (2, 1),
+        _ => unreachable!()
+    };
+    (height as u64 * numerator / denominator) as u32
+}
+
+fn ratio_of(size: &ImageSize) -> u8 {
+    (1..=7).find(|&ratio| width_from_ratio(size.height, ratio) == size.width).unwrap_or(0)
+}
+
+// With div8 the dimensions are stored as multiples of 8. The height and width always hold the actual size,
+// whichever way it was stored.
+bundle! {
+    #[derive(Debug,Clone,PartialEq)]
+    pub struct JxlSizeHeader as "ImageSize" {
+        div8: bool = Bool, default false;
+        height_div8: u32 = BitsWithOffset(5, 1) if div8, default 0;
+        pub height: u32 = U32(BitCountWithOffset(9, 1), BitCountWithOffset(13, 1), BitCountWithOffset(18, 1), BitCountWithOffset(30, 1)) if !div8,
+            default height_div8 * 8;
+        ratio: u8 = Bits(3), default 0;
+        width_div8: u32 = BitsWithOffset(5, 1) if div8 && ratio == 0, default 0;
+        pub width: u32 = U32(BitCountWithOffset(9, 1), BitCountWithOffset(13, 1), BitCountWithOffset(18, 1), BitCountWithOffset(30, 1)) if !div8 && ratio == 0,
+            default if ratio == 0 { width_div8 * 8 } else { width_from_ratio(height, ratio) };
+    }
+}
+impl From<&ImageSize> for JxlSizeHeader {
+    fn from(size: &ImageSize) -> Self {
+        let ratio = ratio_of(size);
+        let fits_div8 = |value: u32| value & 7 == 0 && (1..=32).contains(&(value / 8));
+        let div8 = fits_div8(size.height) && (ratio != 0 || fits_div8(size.width));
+        Self {
+            div8,
+            height_div8: if div8 { size.height / 8 } else { 0 },
+            height: size.height,
+            ratio,
+            width_div8: if div8 && ratio == 0 { size.width / 8 } else { 0 },
+            width: size.width
+        }
+    }
+}
+
+bundle! {
+    #[derive(Debug,Clone,PartialEq)]
+    pub struct JxlPreviewHeader as "PreviewSize" {
+        div8: bool = Bool, default false;
+        height_div8: u32 = U32(RawValue(16), RawValue(32), BitCountWithOffset(5, 1), BitCountWithOffset(9, 33)) if div8, default 0;
+        pub height: u32 = U32(BitCountWithOffset(6, 1), BitCountWithOffset(8, 65), BitCountWithOffset(10, 321), BitCountWithOffset(12, 1345)) if !div8,
+            default height_div8 * 8;
+        ratio: u8 = Bits(3), default 0;
+        width_div8: u32 = U32(RawValue(16), RawValue(32), BitCountWithOffset(5, 1), BitCountWithOffset(9, 33)) if div8 && ratio == 0, default 0;
+        pub width: u32 = U32(BitCountWithOffset(6, 1), BitCountWithOffset(8, 65), BitCountWithOffset(10, 321), BitCountWithOffset(12, 1345)) if !div8 && ratio == 0,
+            default if ratio == 0 { width_div8 * 8 } else { width_from_ratio(height, ratio) };
+    }
+}
+impl From<&ImageSize> for JxlPreviewHeader {
+    fn from(size: &ImageSize) -> Self {
+        let ratio = ratio_of(size);
+        let fits_div8 = |value: u32| value & 7 == 0 && (1..=544).contains(&(value / 8));
+        let div8 = fits_div8(size.height) && (ratio != 0 || fits_div8(size.width));
+        Self {
+            div8,
+            height_div8: if div8 { size.height / 8 } else { 0 },
+            height: size.height,
+            ratio,
+            width_div8: if div8 && ratio == 0 { size.width / 8 } else { 0 },
+            width: size.width
+        }
+    }
+}
 
 impl ImageSize {
-    pub fn read(bitstream: &mut BitStream) -> Option<Self> {
-        let div8 = bitstream.read_bool()?;
-        let height = if div8 {
-            (bitstream.read_u8(5)? + 1) as u32 * 8
-        } else {
-            bitstream.read_quad_u32(
-                BitCountWithOffset(9,1), 
-                BitCountWithOffset(13,1),
-                 BitCountWithOffset(18,1), 
-                 BitCountWithOffset(30,1)
-            )?
-        };
-        let ratio = bitstream.read_u8(3)?;
-        let width = match ratio {
-            0 => if div8 {
-                (bitstream.read_u8(5)? + 1) as u32 * 8
-            } else {
-                bitstream.read_quad_u32(
-                    BitCountWithOffset(9,1), 
-                    BitCountWithOffset(13,1),
-                     BitCountWithOffset(18,1), 
-                     BitCountWithOffset(30,1)
-                )?
-            },
-            1 => height,
-            2 => (height * 6) / 5,
-            3 => (height * 4) / 3,
-            4 => (height * 3) / 2,
-            5 => (height * 16) / 9,
-            6 => (height * 5) / 4,
-            7 => height * 2,
-            _ => unreachable!()
-        };
-        Some(ImageSize { width, height })
+    pub fn read(bitstream: &mut BitStream) -> Result<Self,DecodeError> {
+        let header = JxlSizeHeader::read(bitstream)?;
+        Ok(ImageSize { width: header.width, height: header.height })
     }
-    pub fn read_preview(bitstream: &mut BitStream) -> Option<Self> {
-        let div8 = bitstream.read_bool()?;
-        let height = if div8 {
-            bitstream.read_quad_u32(
-                RawValue(16),
-                RawValue(32),
-                BitCountWithOffset(5,1),
-                BitCountWithOffset(9,33)
-            )? * 8
-        } else {
-            bitstream.read_quad_u32(
-                BitCountWithOffset(6,1), 
-                BitCountWithOffset(8,65),
-                 BitCountWithOffset(10,321), 
-                 BitCountWithOffset(12,1345)
-            )?
-        };
-        let ratio = bitstream.read_u8(3)?;
-        let width = match ratio {
-            0 => if div8 {
-                bitstream.read_quad_u32(
-                    RawValue(16),
-                    RawValue(32),
-                    BitCountWithOffset(5,1),
-                    BitCountWithOffset(9,33)
-                )? * 8
-            } else {
-                bitstream.read_quad_u32(
-                    BitCountWithOffset(6,1), 
-                    BitCountWithOffset(8,65),
-                     BitCountWithOffset(10,321), 
-                     BitCountWithOffset(12,1345)
-                )?
-            },
-            1 => height,
-            2 => (height * 6) / 5,
-            3 => (height * 4) / 3,
-            4 => (height * 3) / 2,
-            5 => (height * 16) / 9,
-            6 => (height * 5) / 4,
-            7 => height * 2,
-            _ => unreachable!()
-        };
-        Some(ImageSize { width, height })
+    pub fn write(&self, writer: &mut BitWriter) -> Result<(),IoError> {
+        JxlSizeHeader::from(self).write(writer)
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
 pub enum JxlOrientation {
     Normal,
     Rotate90,
@@ -114,190 +125,250 @@ impl From<u8> for JxlOrientation {
         }
     }
 }
-
-#[derive(Debug)]
-pub struct JxlAnimationInfo {
-    pub tps_numerator: u32,
-    pub tps_denominator: u32,
-    pub loop_count: u32,
-    pub has_timecodes: bool
-}
-impl JxlAnimationInfo {
-    pub fn read(bitstream: &mut BitStream) -> Option<Self> {
-        let tps_numerator = bitstream.read_quad_u32(
-            RawValue(100), 
-            RawValue(1000), 
-            BitCountWithOffset(10, 1),
-            BitCountWithOffset(30, 1)
-        )?;
-        let tps_denominator = bitstream.read_quad_u32(
-            RawValue(1), 
-            RawValue(1001), 
-            BitCountWithOffset(8, 1),
-            BitCountWithOffset(10, 1)
-        )?;
-        let loop_count = bitstream.read_quad_u32(
-            RawValue(0), 
-            BitCount(3),
-            BitCount(16), 
-            BitCount(32)
-        )?;
-        let has_timecodes = bitstream.read_bool()?;
-        Some(JxlAnimationInfo {
-            tps_numerator,
-            tps_denominator,
-            loop_count,
-            has_timecodes
-        })
+impl From<JxlOrientation> for u8 {
+    fn from(value: JxlOrientation) -> Self {
+        use JxlOrientation as E;
+        match value {
+            E::Normal => 0,
+            E::HorizontalFlip => 1,
+            E::Rotate180 => 2,
+            E::VerticalFlip => 3,
+            E::Rotate90HorizontalFlip => 4,
+            E::Rotate90 => 5,
+            E::HorizontalFlipRotate90 => 6,
+            E::Rotate270 => 7
+        }
     }
 }
+impl FieldValue for JxlOrientation {
+    fn from_raw(raw: u64) -> Option<Self> { (raw < 8).then(|| JxlOrientation::from(raw as u8)) }
+    fn to_raw(&self) -> u64 { u8::from(*self) as u64 }
+}
 
-#[derive(Debug)]
-pub enum JxlBitDepth {
-    Integer {
-        bits: u8
-    },
-    Float {
-        bits: u8,
-        exp_bits: u8
+bundle! {
+    #[derive(Debug,Clone,PartialEq)]
+    pub struct JxlAnimationInfo as "AnimationInfo" {
+        pub tps_numerator: u32 = U32(RawValue(100), RawValue(1000), BitCountWithOffset(10, 1), BitCountWithOffset(30, 1)), default 100;
+        pub tps_denominator: u32 = U32(RawValue(1), RawValue(1001), BitCountWithOffset(8, 1), BitCountWithOffset(10, 1)), default 1;
+        pub loop_count: u32 = U32(RawValue(0), BitCount(3), BitCount(16), BitCount(32)), default 0;
+        pub has_timecodes: bool = Bool, default false;
     }
 }
-impl JxlBitDepth {
-    pub fn read(bitstream: &mut BitStream) -> Option<Self> {
-        match bitstream.read_bool()? {
-            false => {
-                Some(Self::Integer { bits: bitstream.read_quad_u32(
-                    RawValue(8),
-                    RawValue(10),
-                    RawValue(12),
-                    BitCountWithOffset(6, 1)
-                )? as u8 })
-            },
-            true => {
-                let bits = bitstream.read_quad_u32(
-                    RawValue(32),
-                    RawValue(16),
-                    RawValue(24),
-                    BitCountWithOffset(6, 1)
-                )? as u8;
-                let exp_bits = bitstream.read_u8(4)? + 1;
-                Some(Self::Float { bits, exp_bits })
-            }
-        }
-    } 
+
+bundle! {
+    #[derive(Debug,Clone,PartialEq)]
+    pub struct JxlBitDepth as "BitDepth" {
+        pub float_sample: bool = Bool, default false;
+        // Integer and float samples each have their own common bit depths
+        pub bits_per_sample: u8 = U32(RawValue(if float_sample { 32 } else { 8 }), RawValue(if float_sample { 16 } else { 10 }),
+            RawValue(if float_sample { 24 } else { 12 }), BitCountWithOffset(6, 1)), default 8;
+        pub exp_bits: u8 = BitsWithOffset(4, 1) if float_sample, default 0;
+    }
 }
 
-#[derive(Debug)]
+#[derive(Debug,Clone,PartialEq)]
 pub struct JxlExtraChannel {}
+impl JxlExtraChannel {
+    // ImageMetadata rejects extra channels by their count, before any of them would be read
+    pub fn read(bitstream: &mut BitStream) -> Result<Self,DecodeError> {
+        Err(bitstream.unsupported("ExtraChannelInfo","Extra channels"))
+    }
+    pub fn write(&self, writer: &mut BitWriter) -> Result<(),IoError> {
+        Err(unrepresentable("extra channels can't be written"))
+    }
+}
 
-#[derive(Debug)]
+#[derive(Debug,Clone,PartialEq)]
 pub struct JxlColourEncoding {}
 impl JxlColourEncoding {
-    pub fn read(bitstream: &mut BitStream) -> Option<Self> {
-        let all_default = bitstream.read_bool()?;
-        if all_default {
-            return Some(Self{});
-        } else {
-            todo!("Not implemented - colour management is hard");
-        }
+    pub fn read(bitstream: &mut BitStream) -> Result<Self,DecodeError> {
+        bitstream.bundle("ColourEncoding", |bitstream| {
+            let all_default = bitstream.field("ColourEncoding.all_default", |b| b.read_bool())?;
+            if all_default {
+                Ok(Self{})
+            } else {
+                Err(bitstream.unsupported("ColourEncoding.all_default","Non-default colour encodings"))
+            }
+        })
+    }
+    pub fn write(&self, writer: &mut BitWriter) -> Result<(),IoError> {
+        writer.write_bool(true);
+        Ok(())
+    }
+}
+
+bundle! {
+    #[derive(Debug,Clone,PartialEq)]
+    pub struct JxlToneMapping as "ToneMapping" with all_default {
+        pub intensity_target: f32 = F16, default 255.0, valid intensity_target > 0.0;
+        pub min_nits: f32 = F16, default 0.0, valid (0.0..=intensity_target).contains(&min_nits);
+        pub relative_to_max_display: bool = Bool, default false;
+        // A fraction of the display's peak when relative_to_max_display is set, otherwise in nits
+        pub linear_below: f32 = F16, default 0.0, valid linear_below >= 0.0 && (!relative_to_max_display || linear_below <= 1.0);
     }
 }
 
-#[derive(Debug)]
-pub struct JxlToneMapping {}
+// The values from the spec, which are more precise than f32
+#[allow(clippy::excessive_precision)]
+const DEFAULT_INVERSE_MATRIX: [f32;9] = [
+    11.031566901960783, -9.866943921568629, -0.16462299647058826,
+    -3.254147380392157, 4.418770392156863, -0.16462299647058826,
+    -3.6588512862745097, 2.7129230470588235, 1.9459282392156863
+];
+#[allow(clippy::excessive_precision)]
+const DEFAULT_OPSIN_BIAS: [f32;3] = [-0.0037930732552754493; 3];
+#[allow(clippy::excessive_precision)]
+const DEFAULT_QUANT_BIAS: [f32;3] = [1.0 - 0.05465007330715401, 1.0 - 0.07005449891748593, 1.0 - 0.049935103337343655];
 
-#[derive(Debug)]
-pub struct JxlExtensions {
-    pub extensions: Vec<u64>
+bundle! {
+    #[derive(Debug,Clone,PartialEq)]
+    pub struct JxlOpsinInverseMatrix as "OpsinInverseMatrix" with all_default {
+        // Row by row
+        pub inverse_matrix: [f32;9] = [9] F16, default DEFAULT_INVERSE_MATRIX;
+        pub opsin_bias: [f32;3] = [3] F16, default DEFAULT_OPSIN_BIAS;
+        pub quant_bias: [f32;3] = [3] F16, default DEFAULT_QUANT_BIAS;
+        pub quant_bias_numerator: f32 = F16, default 0.145;
+    }
 }
-impl JxlExtensions {
-    pub fn read(bitstream: &mut BitStream) -> Option<Self> {
-        let extensions_bitmap = bitstream.read_var_u64()?;
-        if extensions_bitmap == 0 {
-            Some(Self{extensions: Vec::new()})
-        } else {
-            todo!("Not implemented {:#}",extensions_bitmap)
-        }
+
+// The payload of the extensions, which are all unknown. It is skipped when reading and written as zeros.
+#[derive(Debug,Clone,PartialEq)]
+pub struct JxlExtensionPayload;
+impl JxlExtensionPayload {
+    pub fn read(bitstream: &mut BitStream, bits: u64) -> Result<Self,DecodeError> {
+        bitstream.skip_bits(bits).field("Extensions.payload")?;
+        Ok(Self)
+    }
+    pub fn write(&self, writer: &mut BitWriter, bits: u64) -> Result<(),IoError> {
+        for _ in 0..bits / 32 { writer.write(32, 0); }
+        writer.write((bits % 32) as u8, 0);
+        Ok(())
+    }
+}
+
+bundle! {
+    #[derive(Debug,Clone,PartialEq)]
+    pub struct JxlExtensions as "Extensions" {
+        // A bitmap of the extensions that are present
+        pub extensions: u64 = U64, default 0;
+        pub extension_bits: Vec<u64> = [extensions.count_ones()] U64, default Vec::new(),
+            valid extension_bits.iter().try_fold(0u64, |total, &bits| total.checked_add(bits)).is_some();
+        payload: JxlExtensionPayload = Bundle(extension_bits.iter().sum()), default JxlExtensionPayload;
+    }
+}
+
+bundle! {
+    #[derive(Debug,Clone,PartialEq)]
+    pub struct JxlCustomTransformData as "CustomTransformData" (xyb_encoded: bool) with all_default {
+        pub opsin_inverse_matrix: JxlOpsinInverseMatrix = Bundle() if xyb_encoded, default JxlOpsinInverseMatrix::default();
+        custom_weights_mask: u8 = Bits(3), default 0, supported custom_weights_mask == 0 => "Custom upsampling weights";
     }
 }
 
-#[derive(Debug)]
-pub struct JxlImageMetadata {
-    pub orientation: JxlOrientation,
-    pub intrinsic_size: Option<ImageSize>,
-    pub preview_size: Option<ImageSize>,
-    pub animation_info: Option<JxlAnimationInfo>,
-    pub bit_depth: JxlBitDepth,
-    pub modular_16bit: bool,
-    pub extra_channels: Vec<JxlExtraChannel>,
-    pub xyb_encoded: bool,
-    pub colour_encoding: JxlColourEncoding,
-    pub tone_mapping: Option<JxlToneMapping>,
-    pub extensions: Option<JxlExtensions>
+// all_default covers everything up to the transform data, which has an all_default of its own
+bundle! {
+    #[derive(Debug,Clone,PartialEq)]
+    pub struct JxlImageMetadata as "ImageMetadata" {
+        all_default: bool = Bool, default true;
+        extra_fields: bool = Bool if !all_default, default false;
+        pub orientation: JxlOrientation = Bits(3) if extra_fields, default JxlOrientation::Normal;
+        pub intrinsic_size: Option<JxlSizeHeader> = Optional() if extra_fields, default None;
+        pub preview_size: Option<JxlPreviewHeader> = Optional() if extra_fields, default None;
+        pub animation_info: Option<JxlAnimationInfo> = Optional() if extra_fields, default None;
+        pub bit_depth: JxlBitDepth = Bundle() if !all_default, default JxlBitDepth::default();
+        pub modular_16bit_buffers: bool = Bool if !all_default, default true;
+        num_extra: u32 = U32(RawValue(0), RawValue(1), BitCountWithOffset(4, 2), BitCountWithOffset(12, 1)) if !all_default, default 0,
+            supported num_extra == 0 => "Extra channels";
+        pub extra_channels: Vec<JxlExtraChannel> = [num_extra] Bundle() if !all_default, default Vec::new();
+        pub xyb_encoded: bool = Bool if !all_default, default true;
+        pub colour_encoding: JxlColourEncoding = Bundle() if !all_default, default JxlColourEncoding {};
+        pub tone_mapping: JxlToneMapping = Bundle() if extra_fields, default JxlToneMapping::default();
+        pub extensions: JxlExtensions = Bundle() if !all_default, default JxlExtensions::default();
+        pub transform_data: JxlCustomTransformData = Bundle(xyb_encoded), default JxlCustomTransformData::defaults(xyb_encoded);
+    }
 }
-impl JxlImageMetadata {
-    pub fn read(bitstream: &mut BitStream) -> Option<Self> {
-        let all_default = bitstream.read_bool()?;
-        let extra_fields = if all_default { false } else {bitstream.read_bool()?};
-        let orientation = if extra_fields { JxlOrientation::from(bitstream.read_u8(3)?) } else { JxlOrientation::Normal };
-        let intrinsic_size = if !extra_fields { None } else {
-            if bitstream.read_bool()? {
-                Some(ImageSize::read(bitstream).unwrap())
-            } else { None }
-        };
-        let preview_size = if !extra_fields { None } else {
-            if bitstream.read_bool()? {
-                Some(ImageSize::read_preview(bitstream).unwrap())
-            } else { None }
-        };
-        let animation_info = if !extra_fields { None } else {
-            if bitstream.read_bool()? {
-                Some(JxlAnimationInfo::read(bitstream).unwrap())
-            } else { None }
-        };
-        let bit_depth = JxlBitDepth::read(bitstream)?;
-        let modular_16bit = bitstream.read_bool()?;
-        let extra_channel_count = bitstream.read_quad_u32(
-            RawValue(0),
-            RawValue(1),
-            BitCountWithOffset(4, 2),
-            BitCountWithOffset(12, 1)
-        )?;
-        let extra_channels: Vec<JxlExtraChannel> = Vec::with_capacity(extra_channel_count as usize);
-        for _ in 0..extra_channel_count {
-            todo!("Read extra channels");
+#[cfg(test)]
+mod jxl_image_tests {
+    use crate::bit_reader::BitStream;
+    use crate::bit_writer::BitWriter;
+    use crate::common::ImageSize;
+    use crate::jxl_image::*;
+
+    fn round_trip_metadata(metadata: &JxlImageMetadata) -> JxlImageMetadata {
+        let mut writer = BitWriter::new();
+        metadata.write(&mut writer).unwrap();
+        let data = writer.finish();
+        JxlImageMetadata::read(&mut BitStream::new(&data)).unwrap()
+    }
+
+    #[test]
+    fn image_size_round_trip() {
+        let dimensions: [u32; 18] = [1, 7, 8, 9, 64, 255, 256, 257, 511, 512, 513, 4352, 4353, 5440, 5441, 8192, 270000, 1 << 30];
+        for &height in &dimensions {
+            let ratios = [(6, 5), (4, 3), (16, 9), (2, 1)].map(|(numerator, denominator)| (height as u64 * numerator / denominator) as u32);
+            for width in dimensions.iter().copied().chain(ratios) {
+                let size = ImageSize { width, height };
+                let mut writer = BitWriter::new();
+                size.write(&mut writer).unwrap();
+                let previewable = width <= 5440 && height <= 5440;
+                if previewable { JxlPreviewHeader::from(&size).write(&mut writer).unwrap(); }
+                let data = writer.finish();
+                let mut bitstream = BitStream::new(&data);
+                assert_eq!(ImageSize::read(&mut bitstream), Ok(ImageSize { width, height }));
+                if previewable { assert_eq!(JxlPreviewHeader::read(&mut bitstream), Ok(JxlPreviewHeader::from(&size))); }
+            }
         }
-        let xyb_encoded = bitstream.read_bool()?;
-        let colour_encoding = JxlColourEncoding::read(bitstream)?;
-        let tone_mapping = if extra_fields { todo!("Not implemented") } else { None };
-        let extensions = if all_default {
-            None
-        } else {
-            let extensions_ = JxlExtensions::read(bitstream)?;
-            if extensions_.extensions.len() == 0 { None } else { Some(extensions_) }
-        };
-        let default_m = bitstream.read_bool()?;
-        if !default_m {
-            if xyb_encoded {
-                todo!("Custom XYB Matrix")
+        // The dimensions must match the way they are stored
+        let mut header = JxlSizeHeader::from(&ImageSize { width: 64, height: 64 });
+        header.width = 72;
+        assert!(header.write(&mut BitWriter::new()).is_err());
+    }
+
+    #[test]
+    fn image_metadata_round_trip() {
+        let mut writer = BitWriter::new();
+        JxlImageMetadata::default().write(&mut writer).unwrap();
+        assert_eq!(writer.bit_position(), 2);
+        assert_eq!(round_trip_metadata(&JxlImageMetadata::default()), JxlImageMetadata::default());
+
+        for index in 0..48 {
+            let mut metadata = JxlImageMetadata { all_default: false, orientation: JxlOrientation::from((index % 8) as u8), ..Default::default() };
+            if index % 3 == 0 { metadata.intrinsic_size = Some(JxlSizeHeader::from(&ImageSize { width: 1000 + index, height: 600 })); }
+            if index % 5 == 0 { metadata.preview_size = Some(JxlPreviewHeader::from(&ImageSize { width: 256, height: 144 })); }
+            if index % 4 == 1 {
+                metadata.animation_info = Some(JxlAnimationInfo { tps_numerator: 1000, tps_denominator: 1001, loop_count: index, has_timecodes: index % 8 == 1 });
             }
-            let cw_mask = bitstream.read_u8(3)?;
-            if cw_mask != 0 {
-                todo!("CW Mask {:#}",cw_mask);
+            metadata.bit_depth = match index % 4 {
+                0 => JxlBitDepth::default(),
+                1 => JxlBitDepth { float_sample: false, bits_per_sample: 16, exp_bits: 0 },
+                2 => JxlBitDepth { float_sample: true, bits_per_sample: 32, exp_bits: 8 },
+                _ => JxlBitDepth { float_sample: true, bits_per_sample: 16, exp_bits: 5 }
+            };
+            metadata.modular_16bit_buffers = index % 3 != 1;
+            metadata.xyb_encoded = index % 6 != 5;
+            metadata.extra_fields = metadata.orientation != JxlOrientation::Normal || metadata.intrinsic_size.is_some()
+                || metadata.preview_size.is_some() || metadata.animation_info.is_some();
+            if metadata.extra_fields && index % 2 == 1 {
+                metadata.tone_mapping = JxlToneMapping { intensity_target: 1000.0, min_nits: 0.5, relative_to_max_display: true, linear_below: 0.25 };
             }
+            if index % 7 == 3 { metadata.extensions = JxlExtensions { extensions: 0b1001, extension_bits: vec![3, 40], payload: JxlExtensionPayload }; }
+            metadata.transform_data = JxlCustomTransformData::defaults(metadata.xyb_encoded);
+            if metadata.xyb_encoded && index % 5 == 2 {
+                metadata.transform_data.opsin_inverse_matrix = JxlOpsinInverseMatrix {
+                    inverse_matrix: [11.0, -9.875, -0.25, -3.25, 4.5, -0.125, -3.625, 2.75, 2.0],
+                    opsin_bias: [-0.00390625; 3],
+                    quant_bias: [0.9375, 0.9375, 0.9375],
+                    quant_bias_numerator: 0.125
+                };
+            }
+            assert_eq!(round_trip_metadata(&metadata), metadata);
         }
-        Some(JxlImageMetadata {
-            orientation,
-            intrinsic_size,
-            preview_size,
-            animation_info,
-            bit_depth,
-            modular_16bit,
-            extra_channels,
-            xyb_encoded,
-            colour_encoding,
-            tone_mapping,
-            extensions
-        })
+
+        // Fields that all_default and extra_fields leave out can only hold their defaults
+        let mut metadata = JxlImageMetadata { xyb_encoded: false, ..Default::default() };
+        assert!(metadata.write(&mut BitWriter::new()).is_err());
+        metadata.all_default = false;
+        metadata.orientation = JxlOrientation::Rotate90;
+        assert!(metadata.write(&mut BitWriter::new()).is_err());
     }
-}
\ No newline at end of file
+}
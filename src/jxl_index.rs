@@ -0,0 +1,96 @@
+#![allow(dead_code)]
+
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub struct JxlFrameIndexEntry {
+    pub codestream_offset: u64,     // Byte offset of the frame from the start of the codestream
+    pub start_ticks: u64,           // Time at which the frame is shown
+    pub duration_ticks: u64,        // Time until the next indexed frame
+    pub frame_count: u64            // Number of displayed frames until the next indexed frame
+}
+
+#[derive(Debug)]
+pub struct JxlFrameIndex {
+    pub tps_numerator: u32,
+    pub tps_denominator: u32,
+    pub entries: Vec<JxlFrameIndexEntry>
+}
+
+// Little-endian base 128, with at most 63 bits of value
+fn read_varint(data: &mut &[u8]) -> Option<u64> {
+    let mut value: u64 = 0;
+    for shift in (0..63).step_by(7) {
+        let (&byte, rest) = data.split_first()?;
+        *data = rest;
+        value |= ((byte & 0x7f) as u64) << shift;
+        if byte & 0x80 == 0 { return Some(value); }
+    }
+    None
+}
+
+impl JxlFrameIndex {
+    // Parses the payload of a "jxli" box
+    pub fn parse(mut data: &[u8]) -> Option<Self> {
+        let frame_count = read_varint(&mut data)?;
+        let tps_numerator = u32::from_be_bytes(data.get(0..4)?.try_into().unwrap());
+        let tps_denominator = u32::from_be_bytes(data.get(4..8)?.try_into().unwrap());
+        if tps_numerator == 0 || tps_denominator == 0 { return None; }
+        data = &data[8..];
+
+        let mut entries: Vec<JxlFrameIndexEntry> = Vec::new();
+        let mut codestream_offset: u64 = 0;
+        let mut start_ticks: u64 = 0;
+        for _ in 0..frame_count {
+            // Offsets after the first one are relative to the previous indexed frame
+            codestream_offset = codestream_offset.checked_add(read_varint(&mut data)?)?;
+            let duration_ticks = read_varint(&mut data)?;
+            let frame_count = read_varint(&mut data)?;
+            entries.push(JxlFrameIndexEntry { codestream_offset, start_ticks, duration_ticks, frame_count });
+            start_ticks = start_ticks.checked_add(duration_ticks)?;
+        }
+        Some(Self { tps_numerator, tps_denominator, entries })
+    }
+
+    pub fn ticks_to_seconds(&self, ticks: u64) -> f64 {
+        ticks as f64 * self.tps_numerator as f64 / self.tps_denominator as f64
+    }
+
+    pub fn seconds_to_ticks(&self, seconds: f64) -> u64 {
+        (seconds * self.tps_denominator as f64 / self.tps_numerator as f64).round().max(0.0) as u64
+    }
+
+    // The indexed frame whose start time is closest to the given time
+    pub fn nearest_keyframe(&self, seconds: f64) -> Option<&JxlFrameIndexEntry> {
+        let ticks = self.seconds_to_ticks(seconds);
+        self.entries.iter().min_by_key(|entry| entry.start_ticks.abs_diff(ticks))
+    }
+
+    // The last indexed frame that starts at or before the given time
+    pub fn keyframe_before(&self, seconds: f64) -> Option<&JxlFrameIndexEntry> {
+        let ticks = self.seconds_to_ticks(seconds);
+        self.entries.iter().take_while(|entry| entry.start_ticks <= ticks).last()
+    }
+}
+
+#[cfg(test)]
+mod jxl_index_tests {
+    use crate::jxl_index::JxlFrameIndex;
+
+    #[test]
+    fn parse_index() {
+        // 3 frames at 1/10 second per tick, the second offset needs a two byte varint
+        let data = [
+            0x03, 0, 0, 0, 1, 0, 0, 0, 10,
+            0x20, 0x05, 0x01,
+            0x90, 0x03, 0x0a, 0x02,
+            0x40, 0x01, 0x01
+        ];
+        let index = JxlFrameIndex::parse(&data).unwrap();
+        let offsets: Vec<u64> = index.entries.iter().map(|e| e.codestream_offset).collect();
+        let starts: Vec<u64> = index.entries.iter().map(|e| e.start_ticks).collect();
+        assert_eq!(offsets, vec![0x20, 0x20 + 400, 0x20 + 400 + 0x40]);
+        assert_eq!(starts, vec![0, 5, 15]);
+        assert_eq!(index.nearest_keyframe(1.2).unwrap().start_ticks, 15);
+        assert_eq!(index.keyframe_before(1.2).unwrap().start_ticks, 5);
+        assert!(JxlFrameIndex::parse(&data[..15]).is_none());
+    }
+}
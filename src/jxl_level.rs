@@ -0,0 +1,171 @@
+#![allow(dead_code)]
+
+use std::fmt;
+
+use crate::common::ImageSize;
+use crate::jxl_image::JxlImageMetadata;
+use crate::jxl_frame::JxlFrameHeader;
+
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub enum JxlLevel {
+    Level5,
+    Level10
+}
+impl JxlLevel {
+    pub fn from_byte(value: u8) -> Option<Self> {
+        match value {
+            5 => Some(JxlLevel::Level5),
+            10 => Some(JxlLevel::Level10),
+            _ => None
+        }
+    }
+    pub fn limits(&self) -> JxlLevelLimits {
+        match self {
+            JxlLevel::Level5 => JxlLevelLimits {
+                max_dimension: 1 << 18,
+                max_pixels: 1 << 28,
+                max_extra_channels: 4,
+                max_bits_per_sample: 16,
+                max_modular_buffer_bits: 16
+            },
+            JxlLevel::Level10 => JxlLevelLimits {
+                max_dimension: 1 << 30,
+                max_pixels: 1 << 40,
+                max_extra_channels: 256,
+                max_bits_per_sample: 32,
+                max_modular_buffer_bits: 32
+            }
+        }
+    }
+}
+
+#[derive(Debug,Clone,Copy)]
+pub struct JxlLevelLimits {
+    pub max_dimension: u64,
+    pub max_pixels: u64,
+    pub max_extra_channels: u64,
+    pub max_bits_per_sample: u64,
+    pub max_modular_buffer_bits: u64
+}
+
+#[derive(Debug,Clone,PartialEq,Eq)]
+pub struct JxlLevelViolation {
+    pub level: JxlLevel,
+    pub field: &'static str,
+    pub value: u64,
+    pub limit: u64
+}
+impl fmt::Display for JxlLevelViolation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f,"{:?} limit exceeded: {} is {}, the maximum is {}",self.level,self.field,self.value,self.limit)
+    }
+}
+
+fn check(violations: &mut Vec<JxlLevelViolation>, level: JxlLevel, field: &'static str, value: u64, limit: u64) {
+    if value > limit {
+        violations.push(JxlLevelViolation { level, field, value, limit });
+    }
+}
+
+fn check_size(violations: &mut Vec<JxlLevelViolation>, level: JxlLevel, name: &'static [&'static str; 3], width: u64, height: u64) {
+    let limits = level.limits();
+    check(violations, level, name[0], width, limits.max_dimension);
+    check(violations, level, name[1], height, limits.max_dimension);
+    check(violations, level, name[2], width * height, limits.max_pixels);
+}
+
+pub fn check_image_size(level: JxlLevel, image_size: &ImageSize) -> Vec<JxlLevelViolation> {
+    let mut violations = Vec::new();
+    check_size(&mut violations, level, &["ImageSize.width","ImageSize.height","ImageSize pixels"], image_size.width as u64, image_size.height as u64);
+    violations
+}
+
+pub fn check_image_metadata(level: JxlLevel, image_metadata: &JxlImageMetadata) -> Vec<JxlLevelViolation> {
+    let limits = level.limits();
+    let mut violations = Vec::new();
+    if let Some(size) = &image_metadata.intrinsic_size {
+        check_size(&mut violations, level, &["intrinsic_size.width","intrinsic_size.height","intrinsic_size pixels"], size.width as u64, size.height as u64);
+    }
+    if let Some(size) = &image_metadata.preview_size {
+        check_size(&mut violations, level, &["preview_size.width","preview_size.height","preview_size pixels"], size.width as u64, size.height as u64);
+    }
+    check(&mut violations, level, "bit_depth.bits_per_sample", image_metadata.bit_depth.bits_per_sample as u64, limits.max_bits_per_sample);
+    check(&mut violations, level, "num_extra_channels", image_metadata.extra_channels.len() as u64, limits.max_extra_channels);
+    // Without modular_16bit_buffers the decoder needs 32-bit buffers for modular data
+    check(&mut violations, level, "modular buffer bits", if image_metadata.modular_16bit_buffers { 16 } else { 32 }, limits.max_modular_buffer_bits);
+    violations
+}
+
+// Frames can't be cropped yet, so each one covers the image, or an eighth of it in each direction per LF level.
+// The frame is coded at 1/upsampling of that size and held at the upsampled size, rounded up to whole coded pixels,
+// and the same goes for each extra channel with its own upsampling.
+pub fn check_frame_header(level: JxlLevel, image_size: &ImageSize, frame_header: &JxlFrameHeader) -> Vec<JxlLevelViolation> {
+    let mut violations = Vec::new();
+    let scale = 1u64 << (3 * frame_header.lf_level);
+    let width = (image_size.width as u64).div_ceil(scale);
+    let height = (image_size.height as u64).div_ceil(scale);
+    let upsampled = |upsampling: u8| {
+        let upsampling = upsampling as u64;
+        (width.div_ceil(upsampling) * upsampling, height.div_ceil(upsampling) * upsampling)
+    };
+    let (frame_width, frame_height) = upsampled(frame_header.upsampling);
+    check_size(&mut violations, level, &["frame width","frame height","frame pixels"], frame_width, frame_height);
+    for &ec_upsampling in &frame_header.ec_upscaling {
+        let (channel_width, channel_height) = upsampled(ec_upsampling);
+        check_size(&mut violations, level, &["extra channel width","extra channel height","extra channel pixels"], channel_width, channel_height);
+    }
+    violations
+}
+
+#[cfg(test)]
+mod jxl_level_tests {
+    use crate::common::ImageSize;
+    use crate::jxl_image::{JxlImageMetadata, JxlBitDepth, JxlExtraChannel, JxlPreviewHeader};
+    use crate::jxl_frame::JxlFrameHeader;
+    use crate::jxl_level::*;
+
+    fn fields(violations: &[JxlLevelViolation]) -> Vec<&'static str> {
+        violations.iter().map(|violation| violation.field).collect()
+    }
+
+    #[test]
+    fn image_size_limits() {
+        assert!(check_image_size(JxlLevel::Level5, &ImageSize { width: 1 << 18, height: 1 << 10 }).is_empty());
+        assert!(check_image_size(JxlLevel::Level5, &ImageSize { width: 1 << 14, height: 1 << 14 }).is_empty());
+        let violations = check_image_size(JxlLevel::Level5, &ImageSize { width: (1 << 18) + 1, height: 1 << 18 });
+        assert_eq!(violations[0], JxlLevelViolation { level: JxlLevel::Level5, field: "ImageSize.width", value: (1 << 18) + 1, limit: 1 << 18 });
+        assert_eq!(fields(&violations), ["ImageSize.width", "ImageSize pixels"]);
+        assert_eq!(fields(&check_image_size(JxlLevel::Level5, &ImageSize { width: 1 << 14, height: (1 << 14) + 1 })), ["ImageSize pixels"]);
+        assert!(check_image_size(JxlLevel::Level10, &ImageSize { width: (1 << 18) + 1, height: 1 << 18 }).is_empty());
+    }
+
+    #[test]
+    fn image_metadata_limits() {
+        let mut metadata = JxlImageMetadata::default();
+        assert!(check_image_metadata(JxlLevel::Level5, &metadata).is_empty());
+        metadata.preview_size = Some(JxlPreviewHeader::from(&ImageSize { width: 1 << 19, height: 8 }));
+        metadata.bit_depth = JxlBitDepth { bits_per_sample: 24, ..JxlBitDepth::default() };
+        metadata.extra_channels = (0..5).map(|_| JxlExtraChannel {}).collect();
+        metadata.modular_16bit_buffers = false;
+        // Every violation is reported, not just the first
+        assert_eq!(fields(&check_image_metadata(JxlLevel::Level5, &metadata)),
+            ["preview_size.width", "bit_depth.bits_per_sample", "num_extra_channels", "modular buffer bits"]);
+        metadata.preview_size = None;
+        assert!(check_image_metadata(JxlLevel::Level10, &metadata).is_empty());
+    }
+
+    #[test]
+    fn frame_limits() {
+        let metadata = JxlImageMetadata::default();
+        let mut header = JxlFrameHeader::defaults(&metadata);
+        let image_size = ImageSize { width: 16383, height: 16385 };
+        assert!(check_frame_header(JxlLevel::Level5, &image_size, &header).is_empty());
+        // Upsampling rounds the frame up to 16384 x 16386, which is over the pixel limit
+        header.upsampling = 2;
+        assert_eq!(fields(&check_frame_header(JxlLevel::Level5, &image_size, &header)), ["frame pixels"]);
+        header.upsampling = 1;
+        header.ec_upscaling = vec![1, 8];
+        assert_eq!(fields(&check_frame_header(JxlLevel::Level5, &image_size, &header)), ["extra channel pixels"]);
+        assert!(check_frame_header(JxlLevel::Level10, &image_size, &header).is_empty());
+    }
+}
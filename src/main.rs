@@ -1,30 +1,106 @@
 mod jxl_file;
+mod box_reader;
+mod box_writer;
+mod container_validation;
 mod bit_reader;
+mod bit_writer;
 mod jxl_image;
 mod pixel_array;
 mod jxl_frame;
 mod decode_jxl;
 mod decode_frame;
 mod common;
+mod exif;
+mod xmp;
+mod jumbf;
+mod jxl_index;
+mod jxl_level;
+mod jpeg_reconstruction;
+mod decode_error;
+mod bit_trace;
+mod bundle;
+mod ans;
+mod prefix_code;
+mod entropy_decoder;
 
 use std::env;
 
 #[allow(unused_mut,unused_variables)]
 fn main() {
     let args: Vec<String> = env::args().collect();
+    if args.len() > 2 && args[1] == "--validate" {
+        let findings = match std::fs::File::open(&args[2]) {
+            Ok(file) => container_validation::validate_container(file),
+            Err(error) => {
+                println!("Error reading file: {}",error);
+                return;
+            }
+        };
+        if findings.is_empty() {
+            println!("Valid container");
+        }
+        for finding in findings {
+            println!("{}",finding);
+        }
+        return;
+    }
+    if args.len() > 2 && (args[1] == "--trace" || args[1] == "--trace-json") {
+        let jxl_file = match std::fs::File::open(&args[2]).and_then(jxl_file::JxlFile::read) {
+            Ok(jxl_file) => jxl_file,
+            Err(error) => {
+                println!("Error reading file: {}",error);
+                return;
+            }
+        };
+        let (trace, result) = decode_jxl::trace_headers(&jxl_file);
+        if args[1] == "--trace-json" {
+            println!("{}",trace.to_json());
+        } else {
+            print!("{}",trace);
+        }
+        if let Err(error) = result {
+            eprintln!("Error: {}",error);
+        }
+        return;
+    }
+    if args.len() > 1 && args[1] == "--jpeg" {
+        if args.len() < 4 {
+            println!("Usage: {} --jpeg <input.jxl> <output.jpg>",args[0]);
+            return;
+        }
+        let jxl_file = match std::fs::File::open(&args[2]).and_then(jxl_file::JxlFile::read) {
+            Ok(jxl_file) => jxl_file,
+            Err(error) => {
+                println!("Error reading file: {}",error);
+                return;
+            }
+        };
+        let jpeg = match jpeg_reconstruction::reconstruct_jpeg(&jxl_file) {
+            Ok(jpeg) => jpeg,
+            Err(error) => {
+                println!("Error reconstructing JPEG: {}",error);
+                return;
+            }
+        };
+        match std::fs::write(&args[3],jpeg) {
+            Ok(()) => println!("Wrote {}",args[3]),
+            Err(error) => println!("Error writing file: {}",error)
+        }
+        return;
+    }
     if args.len() < 2 {
         println!("Error: no input given");
         return;
     }
     println!("Opening file: {}",args[1]);
-    let mut file = match std::fs::File::open::<&str>(args[1].as_ref()) {
-        Ok(file) => file,
+    let jxl_file = match std::fs::File::open::<&str>(args[1].as_ref()).and_then(jxl_file::JxlFile::read) {
+        Ok(jxl_file) => jxl_file,
         Err(error) => {
             println!("Error reading file: {}",error);
             return;
         }
     };
-    #[allow(unused_variables)]
-    let jxl_file = jxl_file::JxlFile::read(file).unwrap();
-    let output_pixels = decode_jxl::decode_jxl(jxl_file);
+    if let Err(error) = decode_jxl::decode_jxl(jxl_file) {
+        println!("Error: {}",error);
+    }
 }
\ No newline at end of file
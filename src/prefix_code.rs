@@ -0,0 +1,348 @@
+#![allow(dead_code)]
+
+use crate::bit_reader::BitStream;
+use crate::decode_error::{DecodeError, DecodeResultExt};
+
+pub const PREFIX_MAX_BITS: u8 = 15;
+// Codes up to this long are decoded with one lookup, longer ones go through a second table
+const ROOT_BITS: u8 = 8;
+// The order the code length code's lengths are stored in
+const CODE_LENGTH_CODE_ORDER: [usize; 18] = [1, 2, 3, 4, 0, 5, 17, 6, 16, 7, 8, 9, 10, 11, 12, 13, 14, 15];
+// The fixed code the code length code's lengths are read with, indexed by the next 4 bits: (length, value)
+const CODE_LENGTH_CODE_LENGTHS: [(u8, u8); 16] = [
+    (2, 0), (2, 4), (2, 3), (3, 2), (2, 0), (2, 4), (2, 3), (4, 1), (2, 0), (2, 4), (2, 3), (3, 2), (2, 0), (2, 4), (2, 3), (4, 5)
+];
+const REPEAT_PREVIOUS: u8 = 16;
+const REPEAT_ZERO: u8 = 17;
+
+#[derive(Debug,Clone,Copy,PartialEq)]
+struct TableEntry {
+    bits: u8,       // Length of the code, or above PREFIX_MAX_BITS for a link to a second level table
+    value: u16      // The symbol, or the start of the second level table
+}
+
+// A canonical prefix code in the format Brotli uses, read least significant bit first
+#[derive(Debug,Clone,PartialEq)]
+pub struct PrefixCode {
+    table: Vec<TableEntry>
+}
+
+impl PrefixCode {
+    pub fn read(bitstream: &mut BitStream, alphabet_size: usize) -> Result<Self,DecodeError> {
+        if alphabet_size > 1 << PREFIX_MAX_BITS {
+            return Err(bitstream.invalid_value("PrefixCode.alphabet_size",Some(alphabet_size as u64)));
+        }
+        if alphabet_size <= 1 {
+            return Ok(Self::single_symbol(0));
+        }
+        let skip = bitstream.read_u8(2).field("PrefixCode.kind")?;
+        if skip == 1 {
+            return Self::read_simple(bitstream, alphabet_size).field("PrefixCode.symbols");
+        }
+
+        // The lengths of the code length code, which the lengths of the code itself are read with
+        let mut code_length_code_lengths = [0u8; 18];
+        let mut space = 32i32;
+        let mut code_count = 0;
+        for &symbol in &CODE_LENGTH_CODE_ORDER[skip as usize..] {
+            let (bits, length) = CODE_LENGTH_CODE_LENGTHS[bitstream.peek(4) as usize];
+            bitstream.consume(bits).field("PrefixCode.code_length_code")?;
+            code_length_code_lengths[symbol] = length;
+            if length != 0 {
+                space -= 32 >> length;
+                code_count += 1;
+                if space <= 0 { break; }
+            }
+        }
+        if code_count != 1 && space != 0 {
+            return Err(bitstream.invalid_value("PrefixCode.code_length_code",None));
+        }
+        let code_length_code = Self::from_lengths(&code_length_code_lengths);
+
+        let mut lengths = vec![0u8; alphabet_size];
+        let mut symbol = 0;
+        let mut previous_length = 8;
+        let mut repeat = 0;
+        let mut repeat_length = 0;
+        let mut space = 1i32 << PREFIX_MAX_BITS;
+        while symbol < alphabet_size && space > 0 {
+            let length = code_length_code.read_symbol(bitstream).field("PrefixCode.code_lengths")? as u8;
+            if length < REPEAT_PREVIOUS {
+                repeat = 0;
+                lengths[symbol] = length;
+                symbol += 1;
+                if length != 0 {
+                    previous_length = length;
+                    space -= (1 << PREFIX_MAX_BITS) >> length;
+                }
+                continue;
+            }
+            // Consecutive repeat codes of the same kind multiply together rather than adding
+            let extra_bits = length - 14;
+            let new_length = if length == REPEAT_PREVIOUS { previous_length } else { 0 };
+            if repeat_length != new_length {
+                repeat = 0;
+                repeat_length = new_length;
+            }
+            let old_repeat = repeat;
+            if repeat > 0 {
+                repeat = (repeat - 2) << extra_bits;
+            }
+            repeat += bitstream.read_u32(extra_bits).field("PrefixCode.code_lengths")? as usize + 3;
+            let delta = repeat - old_repeat;
+            if symbol + delta > alphabet_size {
+                return Err(bitstream.invalid_value("PrefixCode.code_lengths",Some(delta as u64)));
+            }
+            lengths[symbol..symbol + delta].fill(repeat_length);
+            symbol += delta;
+            if repeat_length != 0 {
+                space -= (delta as i32) << (PREFIX_MAX_BITS - repeat_length);
+            }
+        }
+        if space != 0 {
+            return Err(bitstream.invalid_value("PrefixCode.code_lengths",None));
+        }
+        Ok(Self::from_lengths(&lengths))
+    }
+    // Up to four symbols with lengths fixed by how many there are
+    fn read_simple(bitstream: &mut BitStream, alphabet_size: usize) -> Result<Self,DecodeError> {
+        let symbol_count = bitstream.read_u8(2)? as usize + 1;
+        let symbol_bits = (alphabet_size - 1).ilog2() as u8 + 1;
+        let mut symbols = [0usize; 4];
+        for i in 0..symbol_count {
+            symbols[i] = bitstream.read(symbol_bits)? as usize;
+            if symbols[i] >= alphabet_size || symbols[..i].contains(&symbols[i]) {
+                return Err(bitstream.invalid_value("",Some(symbols[i] as u64)));
+            }
+        }
+        let symbol_lengths: &[u8] = match symbol_count {
+            1 => return Ok(Self::single_symbol(symbols[0] as u16)),
+            2 => &[1, 1],
+            3 => &[1, 2, 2],
+            _ => if bitstream.read_bool()? { &[1, 2, 3, 3] } else { &[2, 2, 2, 2] }
+        };
+        let mut lengths = vec![0u8; alphabet_size];
+        for (&symbol, &length) in symbols.iter().zip(symbol_lengths) {
+            lengths[symbol] = length;
+        }
+        Ok(Self::from_lengths(&lengths))
+    }
+    fn single_symbol(symbol: u16) -> Self {
+        Self { table: vec![TableEntry { bits: 0, value: symbol }; 1 << ROOT_BITS] }
+    }
+
+    // The lengths must make a complete code, or have a single non-zero length
+    pub fn from_lengths(lengths: &[u8]) -> Self {
+        let codes = canonical_codes(lengths);
+        let used: Vec<usize> = (0..lengths.len()).filter(|&symbol| lengths[symbol] != 0).collect();
+        if used.len() == 1 {
+            return Self::single_symbol(used[0] as u16);
+        }
+        let root_size = 1usize << ROOT_BITS;
+        let mut table = vec![TableEntry { bits: 0, value: 0 }; root_size];
+        // Codes longer than the root share a second level table with the other codes that start the same way
+        let mut second_level_bits = vec![0u8; root_size];
+        for &symbol in &used {
+            if lengths[symbol] > ROOT_BITS {
+                let root = codes[symbol] as usize & (root_size - 1);
+                second_level_bits[root] = second_level_bits[root].max(lengths[symbol] - ROOT_BITS);
+            }
+        }
+        for root in 0..root_size {
+            if second_level_bits[root] != 0 {
+                table[root] = TableEntry { bits: PREFIX_MAX_BITS + second_level_bits[root], value: table.len() as u16 };
+                table.resize(table.len() + (1 << second_level_bits[root]), TableEntry { bits: 0, value: 0 });
+            }
+        }
+        for &symbol in &used {
+            let (length, code) = (lengths[symbol], codes[symbol] as usize);
+            let entry = TableEntry { bits: length, value: symbol as u16 };
+            if length <= ROOT_BITS {
+                for index in (code..root_size).step_by(1 << length) {
+                    table[index] = entry;
+                }
+            } else {
+                let link = table[code & (root_size - 1)];
+                let size = 1usize << (link.bits - PREFIX_MAX_BITS);
+                for index in ((code >> ROOT_BITS)..size).step_by(1 << (length - ROOT_BITS)) {
+                    table[link.value as usize + index] = entry;
+                }
+            }
+        }
+        Self { table }
+    }
+
+    pub fn read_symbol(&self, bitstream: &mut BitStream) -> Result<u32,DecodeError> {
+        let bits = bitstream.peek(PREFIX_MAX_BITS) as usize;
+        let mut entry = self.table[bits & ((1 << ROOT_BITS) - 1)];
+        if entry.bits > PREFIX_MAX_BITS {
+            let index = (bits >> ROOT_BITS) & ((1 << (entry.bits - PREFIX_MAX_BITS)) - 1);
+            entry = self.table[entry.value as usize + index];
+        }
+        bitstream.consume(entry.bits)?;
+        Ok(entry.value as u32)
+    }
+}
+
+// Canonical codes for the lengths, bit reversed so they can be matched against bits read least significant first
+pub fn canonical_codes(lengths: &[u8]) -> Vec<u32> {
+    let mut length_counts = [0u32; PREFIX_MAX_BITS as usize + 1];
+    for &length in lengths {
+        length_counts[length as usize] += 1;
+    }
+    length_counts[0] = 0;
+    let mut next_code = [0u32; PREFIX_MAX_BITS as usize + 1];
+    for length in 1..=PREFIX_MAX_BITS as usize {
+        next_code[length] = (next_code[length - 1] + length_counts[length - 1]) << 1;
+    }
+    lengths.iter().map(|&length| {
+        if length == 0 { return 0; }
+        let code = next_code[length as usize];
+        next_code[length as usize] += 1;
+        code.reverse_bits() >> (32 - length)
+    }).collect()
+}
+
+#[cfg(test)]
+mod prefix_code_tests {
+    use crate::bit_reader::BitStream;
+    use crate::bit_writer::BitWriter;
+    use crate::prefix_code::*;
+
+    fn write_symbols(writer: &mut BitWriter, lengths: &[u8], symbols: &[usize]) {
+        let codes = canonical_codes(lengths);
+        for &symbol in symbols {
+            writer.write(lengths[symbol], codes[symbol] as u64);
+        }
+    }
+
+    #[test]
+    fn canonical_codes_are_reversed() {
+        // 0, 10, 110, 111 when written most significant bit first
+        assert_eq!(canonical_codes(&[1, 2, 3, 3]), vec![0b0, 0b01, 0b011, 0b111]);
+        assert_eq!(canonical_codes(&[2, 0, 1, 2]), vec![0b01, 0, 0b0, 0b11]);
+    }
+
+    #[test]
+    fn simple_codes() {
+        let mut writer = BitWriter::new();
+        // One symbol: 9 of an alphabet of 10, which takes 4 bits
+        writer.write(2, 1);
+        writer.write(2, 0);
+        writer.write(4, 9);
+        // Three symbols: 5 gets the 1-bit code, 2 and 7 the 2-bit ones
+        writer.write(2, 1);
+        writer.write(2, 2);
+        for symbol in [5, 7, 2] { writer.write(4, symbol); }
+        write_symbols(&mut writer, &[0, 0, 2, 0, 0, 1, 0, 2], &[5, 2, 7, 5]);
+        // Four symbols with lengths 1, 2, 3, 3
+        writer.write(2, 1);
+        writer.write(2, 3);
+        for symbol in [3, 0, 8, 6] { writer.write(4, symbol); }
+        writer.write_bool(true);
+        write_symbols(&mut writer, &[2, 0, 0, 1, 0, 0, 3, 0, 3], &[8, 3, 6, 0, 3]);
+        // The same symbol twice
+        writer.write(2, 1);
+        writer.write(2, 1);
+        writer.write(4, 4);
+        writer.write(4, 4);
+        let data = writer.finish();
+
+        let mut bitstream = BitStream::new(&data);
+        let code = PrefixCode::read(&mut bitstream, 10).unwrap();
+        assert_eq!(code.read_symbol(&mut bitstream), Ok(9));
+        assert_eq!(bitstream.bit_position(), 8);
+        let code = PrefixCode::read(&mut bitstream, 10).unwrap();
+        for symbol in [5, 2, 7, 5] {
+            assert_eq!(code.read_symbol(&mut bitstream), Ok(symbol));
+        }
+        let code = PrefixCode::read(&mut bitstream, 10).unwrap();
+        for symbol in [8, 3, 6, 0, 3] {
+            assert_eq!(code.read_symbol(&mut bitstream), Ok(symbol));
+        }
+        assert!(PrefixCode::read(&mut bitstream, 10).is_err());
+
+        // A symbol that fits the 4 bits but not the alphabet
+        let mut writer = BitWriter::new();
+        writer.write(2, 1);
+        writer.write(2, 1);
+        writer.write(4, 3);
+        writer.write(4, 12);
+        let data = writer.finish();
+        assert_eq!(PrefixCode::read(&mut BitStream::new(&data), 10),
+            Err(DecodeError::InvalidValue { bit_offset: 12, field: "PrefixCode.symbols", value: Some(12) }));
+    }
+
+    #[test]
+    fn complex_code_with_repeats() {
+        let mut writer = BitWriter::new();
+        writer.write(2, 0);
+        // Code length code: 3 gets 1 bit, 16 and 17 get 2 bits. In the stored order that's 0, 0, 1, 0, 0, 0, 2, 0, 2.
+        let fixed_code = [(2, 0b00), (4, 0b0111), (3, 0b011)];
+        for length in [0, 0, 1, 0, 0, 0, 2, 0, 2] {
+            let (bits, code) = fixed_code[length];
+            writer.write(bits, code);
+        }
+        let mut code_length_code_lengths = [0u8; 18];
+        code_length_code_lengths[3] = 1;
+        code_length_code_lengths[16] = 2;
+        code_length_code_lengths[17] = 2;
+        // 3, then 3 more of it, then 10 zeros, then 3 and 3 more of it. The code is complete after 18 of the 20 symbols.
+        write_symbols(&mut writer, &code_length_code_lengths, &[3, 16]);
+        writer.write(2, 0);
+        write_symbols(&mut writer, &code_length_code_lengths, &[17]);
+        writer.write(3, 7);
+        write_symbols(&mut writer, &code_length_code_lengths, &[3, 16]);
+        writer.write(2, 0);
+        let mut lengths = [0u8; 20];
+        lengths[0..4].fill(3);
+        lengths[14..18].fill(3);
+        let symbols = [0, 17, 3, 14, 1, 16, 2, 15];
+        write_symbols(&mut writer, &lengths, &symbols);
+
+        // Two 17 codes in a row: 3 + 2, then (5 - 2) * 8 + 3 + 4 = 31 zeros
+        writer.write(2, 0);
+        for length in [0, 0, 1, 0, 0, 0, 2, 0, 2] {
+            let (bits, code) = fixed_code[length];
+            writer.write(bits, code);
+        }
+        write_symbols(&mut writer, &code_length_code_lengths, &[3, 16]);
+        writer.write(2, 3);
+        write_symbols(&mut writer, &code_length_code_lengths, &[17]);
+        writer.write(3, 2);
+        write_symbols(&mut writer, &code_length_code_lengths, &[17]);
+        writer.write(3, 4);
+        write_symbols(&mut writer, &code_length_code_lengths, &[3]);
+        let mut long_lengths = [0u8; 40];
+        long_lengths[0..7].fill(3);
+        long_lengths[38] = 3;
+        write_symbols(&mut writer, &long_lengths, &[38, 6, 0]);
+        let data = writer.finish();
+
+        let mut bitstream = BitStream::new(&data);
+        let code = PrefixCode::read(&mut bitstream, 20).unwrap();
+        for symbol in symbols {
+            assert_eq!(code.read_symbol(&mut bitstream), Ok(symbol as u32));
+        }
+        let code = PrefixCode::read(&mut bitstream, 40).unwrap();
+        for symbol in [38, 6, 0] {
+            assert_eq!(code.read_symbol(&mut bitstream), Ok(symbol));
+        }
+    }
+
+    #[test]
+    fn long_codes() {
+        // Lengths 1 to 15, with two codes of 15 bits to make it complete
+        let mut lengths: Vec<u8> = (1..=15).collect();
+        lengths.push(15);
+        let code = PrefixCode::from_lengths(&lengths);
+        let symbols: Vec<usize> = (0..16).chain((0..16).rev()).collect();
+        let mut writer = BitWriter::new();
+        write_symbols(&mut writer, &lengths, &symbols);
+        let data = writer.finish();
+        let mut bitstream = BitStream::new(&data);
+        for symbol in symbols {
+            assert_eq!(code.read_symbol(&mut bitstream), Ok(symbol as u32));
+        }
+    }
+}
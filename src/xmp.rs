@@ -0,0 +1,228 @@
+#![allow(dead_code)]
+
+// Just enough XML to pull simple properties out of an XMP packet, this is not a general purpose parser
+#[derive(Debug,PartialEq)]
+enum XmlToken<'a> {
+    StartTag { name: &'a str, attributes: Vec<(&'a str, String)>, self_closing: bool },
+    EndTag { name: &'a str },
+    Text(String)
+}
+
+fn unescape(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(start) = rest.find('&') {
+        out.push_str(&rest[..start]);
+        rest = &rest[start..];
+        let end = match rest.find(';') {
+            Some(end) => end,
+            None => break
+        };
+        let decoded = match &rest[1..end] {
+            "amp" => Some('&'),
+            "lt" => Some('<'),
+            "gt" => Some('>'),
+            "quot" => Some('"'),
+            "apos" => Some('\''),
+            entity if entity.starts_with("#x") => u32::from_str_radix(&entity[2..], 16).ok().and_then(char::from_u32),
+            entity if entity.starts_with('#') => entity[1..].parse().ok().and_then(char::from_u32),
+            _ => None
+        };
+        match decoded {
+            Some(c) => { out.push(c); rest = &rest[end+1..]; },
+            None => { out.push('&'); rest = &rest[1..]; }
+        }
+    }
+    out.push_str(rest);
+    out
+}
+
+fn parse_attributes(mut tag: &str) -> Vec<(&str, String)> {
+    let mut attributes = Vec::new();
+    loop {
+        tag = tag.trim_start();
+        let equals = match tag.find('=') {
+            Some(equals) => equals,
+            None => break
+        };
+        let name = tag[..equals].trim();
+        let value = tag[equals+1..].trim_start();
+        let quote = match value.chars().next() {
+            Some(q) if q == '"' || q == '\'' => q,
+            _ => break
+        };
+        let end = match value[1..].find(quote) {
+            Some(end) => end + 1,
+            None => break
+        };
+        attributes.push((name, unescape(&value[1..end])));
+        tag = &value[end+1..];
+    }
+    attributes
+}
+
+fn tokenize(xml: &str) -> Vec<XmlToken<'_>> {
+    let mut tokens = Vec::new();
+    let mut rest = xml;
+    while !rest.is_empty() {
+        if !rest.starts_with('<') {
+            let end = rest.find('<').unwrap_or(rest.len());
+            tokens.push(XmlToken::Text(unescape(&rest[..end])));
+            rest = &rest[end..];
+        } else if let Some(cdata) = rest.strip_prefix("<![CDATA[") {
+            let end = cdata.find("]]>").unwrap_or(cdata.len());
+            tokens.push(XmlToken::Text(cdata[..end].to_owned()));
+            rest = cdata.get(end+3..).unwrap_or("");
+        } else if rest.starts_with("<!--") {
+            rest = rest.find("-->").map(|end| &rest[end+3..]).unwrap_or("");
+        } else if rest.starts_with("<?") || rest.starts_with("<!") {
+            rest = rest.find('>').map(|end| &rest[end+1..]).unwrap_or("");
+        } else {
+            // Find the closing '>' while skipping over quoted attribute values
+            let mut quote: Option<char> = None;
+            let mut end = None;
+            for (i, c) in rest.char_indices() {
+                match (quote, c) {
+                    (None, '"') | (None, '\'') => quote = Some(c),
+                    (Some(q), c) if q == c => quote = None,
+                    (None, '>') => { end = Some(i); break; },
+                    _ => ()
+                }
+            }
+            let end = match end {
+                Some(end) => end,
+                None => break
+            };
+            let tag = &rest[1..end];
+            rest = &rest[end+1..];
+            if let Some(name) = tag.strip_prefix('/') {
+                tokens.push(XmlToken::EndTag { name: name.trim() });
+            } else {
+                let self_closing = tag.ends_with('/');
+                let tag = tag.trim_end_matches('/');
+                let name_end = tag.find(char::is_whitespace).unwrap_or(tag.len());
+                tokens.push(XmlToken::StartTag {
+                    name: &tag[..name_end],
+                    attributes: parse_attributes(&tag[name_end..]),
+                    self_closing
+                });
+            }
+        }
+    }
+    tokens
+}
+
+#[derive(Debug)]
+pub struct XmpPacket<'a> {
+    pub packet: &'a str,
+    tokens: Vec<XmlToken<'a>>
+}
+
+impl<'a> XmpPacket<'a> {
+    pub fn new(packet: &'a str) -> Self {
+        Self { packet, tokens: tokenize(packet) }
+    }
+
+    // Returns every value of a property such as "dc:creator", whether it is written as an attribute,
+    // as element text or as the items of an rdf:Seq/rdf:Bag/rdf:Alt. The x-default item of an rdf:Alt comes first.
+    pub fn property_values(&self, name: &str) -> Vec<String> {
+        for token in &self.tokens {
+            if let XmlToken::StartTag { attributes, .. } = token {
+                if let Some((_, value)) = attributes.iter().find(|(attribute, _)| *attribute == name) {
+                    return vec![value.clone()];
+                }
+            }
+        }
+
+        let start = match self.tokens.iter().position(|token| matches!(token, XmlToken::StartTag { name: n, self_closing: false, .. } if *n == name)) {
+            Some(start) => start,
+            None => return Vec::new()
+        };
+        let mut depth = 0;
+        let mut text = String::new();
+        let mut items: Vec<(bool, String)> = Vec::new();
+        let mut in_item = false;
+        for token in &self.tokens[start+1..] {
+            match token {
+                XmlToken::StartTag { name: "rdf:li", attributes, self_closing } => {
+                    let is_default = attributes.iter().any(|(attribute, value)| *attribute == "xml:lang" && value == "x-default");
+                    let resource = attributes.iter().find(|(attribute, _)| *attribute == "rdf:resource").map(|(_, value)| value.clone());
+                    items.push((is_default, resource.unwrap_or_default()));
+                    in_item = !self_closing;
+                },
+                XmlToken::EndTag { name: "rdf:li" } => in_item = false,
+                XmlToken::StartTag { self_closing: false, .. } => depth += 1,
+                // At depth 0 any end tag closes the property, even a mismatched one
+                XmlToken::EndTag { .. } => match depth {
+                    0 => break,
+                    _ => depth -= 1
+                },
+                XmlToken::Text(t) => {
+                    if in_item { items.last_mut().unwrap().1.push_str(t); } else { text.push_str(t); }
+                },
+                _ => ()
+            }
+        }
+        if items.is_empty() {
+            let text = text.trim();
+            if text.is_empty() { Vec::new() } else { vec![text.to_owned()] }
+        } else {
+            items.sort_by_key(|(is_default, _)| !is_default);
+            items.into_iter().map(|(_, item)| item.trim().to_owned()).collect()
+        }
+    }
+
+    pub fn property(&self, name: &str) -> Option<String> {
+        self.property_values(name).into_iter().next()
+    }
+
+    pub fn title(&self) -> Option<String> {
+        self.property("dc:title")
+    }
+
+    pub fn creators(&self) -> Vec<String> {
+        self.property_values("dc:creator")
+    }
+
+    // -1 means rejected, otherwise 0 to 5 stars
+    pub fn rating(&self) -> Option<f32> {
+        self.property("xmp:Rating")?.parse().ok()
+    }
+
+    pub fn orientation(&self) -> Option<u16> {
+        self.property("tiff:Orientation")?.parse().ok()
+    }
+}
+
+#[cfg(test)]
+mod xmp_tests {
+    use crate::xmp::XmpPacket;
+
+    #[test]
+    fn read_properties() {
+        let packet = r#"<?xpacket begin="" id="W5M0MpCehiHzreSzNTczkc9d"?>
+<x:xmpmeta xmlns:x="adobe:ns:meta/">
+ <rdf:RDF xmlns:rdf="http://www.w3.org/1999/02/22-rdf-syntax-ns#">
+  <rdf:Description rdf:about="" xmp:Rating="4" tiff:Orientation="6">
+   <dc:title><rdf:Alt><rdf:li xml:lang="fr">Plage</rdf:li><rdf:li xml:lang="x-default">Beach &amp; sunset</rdf:li></rdf:Alt></dc:title>
+   <dc:creator><rdf:Seq><rdf:li>Alice</rdf:li><rdf:li>Bob</rdf:li></rdf:Seq></dc:creator>
+  </rdf:Description>
+ </rdf:RDF>
+</x:xmpmeta>
+<?xpacket end="w"?>"#;
+        let xmp = XmpPacket::new(packet);
+        assert_eq!(xmp.title().as_deref(), Some("Beach & sunset"));
+        assert_eq!(xmp.creators(), vec!["Alice", "Bob"]);
+        assert_eq!(xmp.rating(), Some(4.0));
+        assert_eq!(xmp.orientation(), Some(6));
+        assert_eq!(xmp.property("dc:subject"), None);
+    }
+
+    #[test]
+    fn mismatched_end_tag() {
+        let packet = r#"<rdf:Description><dc:format>image/jxl</rdf:Description><dc:source>scan</dc:source></dc:format>"#;
+        let xmp = XmpPacket::new(packet);
+        assert_eq!(xmp.property_values("dc:format"), vec!["image/jxl"]);
+        assert_eq!(xmp.property("dc:source").as_deref(), Some("scan"));
+    }
+}